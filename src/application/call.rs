@@ -0,0 +1,278 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::{Context, Poll};
+use std::thread;
+use std::time::{Duration, Instant};
+use futures::channel::oneshot;
+
+use crate::Client;
+use crate::client::DhtType;
+use crate::key::Key;
+use crate::logging;
+
+// Per call settings for the async operations
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CallOptions {
+    pub timeout: Option<Duration>,
+}
+
+impl CallOptions {
+    pub fn timeout(mut self, timeout: Duration) -> CallOptions {
+        self.timeout = Some(timeout);
+        self
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum CallError {
+    TimedOut,
+    Cancelled,
+    Failed(String),
+}
+
+impl fmt::Display for CallError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CallError::TimedOut => write!(f, "Timed out"),
+            CallError::Cancelled => write!(f, "Cancelled"),
+            CallError::Failed(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for CallError {}
+
+type Reply<T> = Arc<Mutex<Option<oneshot::Sender<Result<T, CallError>>>>>;
+
+// Deadline and a sequence number, which keeps equal deadlines apart
+type TimerId = (Instant, u64);
+
+// One thread fires the timeouts of every call, sleeping until the earliest
+struct Timer {
+    state: Mutex<TimerState>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct TimerState {
+    next_id: u64,
+    pending: BTreeMap<TimerId, Box<dyn FnOnce() + Send>>,
+}
+
+fn timer() -> &'static Timer {
+    static TIMER: OnceLock<Timer> = OnceLock::new();
+    TIMER.get_or_init(|| {
+        thread::spawn(|| timer().run());
+        Timer {state: Mutex::new(TimerState::default()), changed: Condvar::new()}
+    })
+}
+
+impl Timer {
+    fn schedule(&self, deadline: Instant, action: Box<dyn FnOnce() + Send>) -> TimerId {
+        let mut state = self.state.lock().unwrap();
+        let id = (deadline, state.next_id);
+        state.next_id += 1;
+        state.pending.insert(id, action);
+        self.changed.notify_one();
+        id
+    }
+
+    // Drop a timeout that is no longer needed
+    fn cancel(&self, id: TimerId) {
+        self.state.lock().unwrap().pending.remove(&id);
+    }
+
+    fn run(&self) {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            let now = Instant::now();
+            state = match state.pending.keys().next().copied() {
+                Some(id) if id.0 <= now => {
+                    let action = state.pending.remove(&id).unwrap();
+                    drop(state);
+                    action();
+                    self.state.lock().unwrap()
+                },
+                Some(id) => self.changed.wait_timeout(state, id.0 - now).unwrap().0,
+                None => self.changed.wait(state).unwrap(),
+            };
+        }
+    }
+}
+
+// A blocking client operation running on its own thread. Dropping the call
+// or running past its timeout cancels the operation at its next network round
+// trip, at the latest once the peer it waits on hits the request timeout.
+pub struct Call<T> {
+    receiver: oneshot::Receiver<Result<T, CallError>>,
+    cancel: Arc<AtomicBool>,
+}
+
+impl<T: Send + 'static> Call<T> {
+    fn spawn<F>(client: &Client, options: CallOptions, operation: F) -> Call<T>
+        where F: FnOnce(&mut Client) -> Result<T, Box<dyn Error>> + Send + 'static {
+        let (sender, receiver) = oneshot::channel();
+        let reply: Reply<T> = Arc::new(Mutex::new(Some(sender)));
+        let cancel = Arc::new(AtomicBool::new(false));
+
+        let request_id = logging::request_id();
+        log::debug!(request_id = request_id.as_str(), timeout_ms = options.timeout.map(|timeout| timeout.as_millis() as u64); "Call started");
+
+        // Whichever of the worker and the timer answers first wins
+        let timeout = options.timeout.map(|timeout| {
+            let timer_reply = reply.clone();
+            let timer_cancel = cancel.clone();
+            timer().schedule(Instant::now() + timeout, Box::new(move || {
+                if finish(&timer_reply, Err(CallError::TimedOut)) {
+                    timer_cancel.store(true, Ordering::SeqCst);
+                }
+            }))
+        });
+
+        let mut worker_client = client.clone();
+        worker_client.cancel = Some(cancel.clone());
+        let worker_cancel = cancel.clone();
+        thread::spawn(move || {
+            let result = operation(&mut worker_client).map_err(|e| match worker_cancel.load(Ordering::SeqCst) {
+                true => CallError::Cancelled,
+                false => CallError::Failed(e.to_string()),
+            });
+            match &result {
+                Ok(_) => log::debug!(request_id = request_id.as_str(); "Call finished"),
+                Err(e) => log::debug!(request_id = request_id.as_str(); "Call failed: {}", e),
+            }
+            finish(&reply, result);
+            if let Some(timeout) = timeout {
+                timer().cancel(timeout);
+            }
+        });
+
+        Call {receiver, cancel}
+    }
+}
+
+fn finish<T>(reply: &Reply<T>, result: Result<T, CallError>) -> bool {
+    match reply.lock().unwrap().take() {
+        Some(sender) => {
+            let _ = sender.send(result);
+            true
+        },
+        None => false,
+    }
+}
+
+impl<T> Future for Call<T> {
+    type Output = Result<T, CallError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match Pin::new(&mut self.receiver).poll(cx) {
+            Poll::Ready(Ok(result)) => Poll::Ready(result),
+            // The worker went away without answering, most likely a panic
+            Poll::Ready(Err(_)) => Poll::Ready(Err(CallError::Failed("Call aborted".to_string()))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<T> Drop for Call<T> {
+    fn drop(&mut self) {
+        self.cancel.store(true, Ordering::SeqCst);
+    }
+}
+
+impl Client {
+    pub fn get_data_async(&self, find_key: Key, options: CallOptions) -> Call<DhtType> {
+        Call::spawn(self, options, move |client| client.get_data(find_key))
+    }
+
+    pub fn put_data_async(&self, name: String, data: DhtType, options: CallOptions) -> Call<Key> {
+        Call::spawn(self, options, move |client| {
+            let key = client.put_data(name, data);
+            // A cancelled store may have reached only some replicas
+            if client.cancelled() {
+                return Err("Cancelled".into());
+            }
+            Ok(key)
+        })
+    }
+
+    // Every provider record known once the nearby nodes answered
+    pub fn get_providers_async(&self, options: CallOptions) -> Call<Vec<(String, Key)>> {
+        Call::spawn(self, options, |client| {
+            client.get_providers();
+            if client.cancelled() {
+                return Err("Cancelled".into());
+            }
+            Ok(client.providers.lock().unwrap().iter().map(|(name, key)| (name.clone(), *key)).collect())
+        })
+    }
+
+    pub fn ping_async(&self, key: Key, options: CallOptions) -> Call<Duration> {
+        Call::spawn(self, options, move |client| client.ping(key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use futures::executor::block_on;
+    use crate::Config;
+
+    #[test]
+    fn timer_fires_in_deadline_order() {
+        let (sender, receiver) = mpsc::channel();
+        let now = Instant::now();
+        for (delay, label) in [(60, "late"), (20, "early"), (40, "cancelled")] {
+            let sender = sender.clone();
+            let id = timer().schedule(now + Duration::from_millis(delay), Box::new(move || sender.send(label).unwrap()));
+            if label == "cancelled" {
+                timer().cancel(id);
+            }
+        }
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(2)), Ok("early"));
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(2)), Ok("late"));
+        assert!(receiver.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+
+    #[test]
+    fn silent_peer_times_out_and_stops_the_lookup() {
+        let mut config = Config::default();
+        config.timeouts.request_secs = 1;
+        let client = Client::with_config("127.0.0.1".to_string(), "0".to_string(), config);
+        client.known_nodes.lock().unwrap().clear();
+
+        // Accepts connections and never answers
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        client.known_nodes.lock().unwrap().insert(Key {key: 0xbeef}, listener.local_addr().unwrap().to_string());
+
+        let started = Instant::now();
+        let call = client.get_data_async(Key {key: 42}, CallOptions::default().timeout(Duration::from_millis(100)));
+        assert_eq!(block_on(call), Err(CallError::TimedOut));
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        // The worker gives up once the read times out
+        while client.transfers.load(Ordering::SeqCst) > 0 {
+            assert!(started.elapsed() < Duration::from_secs(5), "lookup still running");
+            thread::sleep(Duration::from_millis(50));
+        }
+        drop(listener);
+    }
+
+    #[test]
+    fn finished_call_wins_over_its_timeout() {
+        let client = Client::with_config("127.0.0.1".to_string(), "0".to_string(), Config::default());
+        let data = crate::data::Data::new("a.txt", b"local".to_vec());
+        let key = Key::generate_hash_from_data(&data.vec);
+        client.local_hash.lock().unwrap().insert(key, data);
+
+        let call = client.get_data_async(key, CallOptions::default().timeout(Duration::from_secs(30)));
+        assert_eq!(block_on(call).unwrap().vec, b"local");
+    }
+}
@@ -0,0 +1,135 @@
+use std::collections::{HashSet, VecDeque};
+use std::time::{SystemTime, UNIX_EPOCH};
+use crossbeam::channel::{unbounded, Receiver, Sender};
+use rand::Rng;
+use serde::{Serialize, Deserialize};
+
+use crate::key::Key;
+use crate::data::Data;
+
+pub const CHAT_MIME: &str = "application/x-p2p-chat";
+// Messages kept for INBOX, older ones are dropped
+const INBOX_CAPACITY: usize = 1000;
+// (sender, id) pairs remembered to drop retried messages
+const SEEN_CAPACITY: usize = 4096;
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct ChatMessage {
+    pub id: u64,
+    // Node key of the sender
+    pub from: u32,
+    pub text: String,
+    // Seconds since the epoch on the sender's clock
+    pub sent_at: u64,
+}
+
+impl ChatMessage {
+    pub fn new(from: Key, text: &str) -> ChatMessage {
+        let sent_at = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0);
+        ChatMessage {id: rand::thread_rng().gen::<u64>(), from: from.key, text: text.to_string(), sent_at}
+    }
+
+    // Chat messages travel as the JSON body of a Data
+    pub fn to_data(&self) -> Data {
+        let mut data = Data::new("", serde_json::to_vec(self).unwrap());
+        data.file_meta.mime = CHAT_MIME.to_string();
+        data
+    }
+
+    pub fn from_data(data: &Data) -> Option<ChatMessage> {
+        if data.file_meta.mime != CHAT_MIME { return None; }
+        serde_json::from_slice(&data.vec).ok()
+    }
+}
+
+// The latest messages received by this node, also handed to every listener as they arrive
+#[derive(Default)]
+pub struct Inbox {
+    pub messages: VecDeque<ChatMessage>,
+    listeners: Vec<Sender<ChatMessage>>,
+    seen: HashSet<(u32, u64)>,
+    seen_order: VecDeque<(u32, u64)>,
+}
+
+impl Inbox {
+    pub fn new() -> Inbox {
+        Inbox::default()
+    }
+
+    pub fn listen(&mut self) -> Receiver<ChatMessage> {
+        let (sender, receiver) = unbounded();
+        self.listeners.push(sender);
+        receiver
+    }
+
+    // False for a message already delivered, e.g. when the sender retried after a lost ack
+    pub fn deliver(&mut self, message: ChatMessage) -> bool {
+        let seen = (message.from, message.id);
+        if !self.seen.insert(seen) {
+            return false;
+        }
+        self.seen_order.push_back(seen);
+        if self.seen_order.len() > SEEN_CAPACITY {
+            if let Some(oldest) = self.seen_order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+
+        self.listeners.retain(|listener| listener.send(message.clone()).is_ok());
+        self.messages.push_back(message);
+        if self.messages.len() > INBOX_CAPACITY {
+            self.messages.pop_front();
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(from: u32, id: u64) -> ChatMessage {
+        ChatMessage {id, from, text: format!("message {}", id), sent_at: 0}
+    }
+
+    #[test]
+    fn delivered_messages_reach_every_listener() {
+        let mut inbox = Inbox::new();
+        let first = inbox.listen();
+        let second = inbox.listen();
+        drop(inbox.listen());
+
+        assert!(inbox.deliver(message(1, 1)));
+        assert_eq!(first.try_recv().unwrap(), message(1, 1));
+        assert_eq!(second.try_recv().unwrap(), message(1, 1));
+        assert_eq!(inbox.listeners.len(), 2);
+        assert_eq!(inbox.messages, vec![message(1, 1)]);
+    }
+
+    #[test]
+    fn retried_messages_are_delivered_once() {
+        let mut inbox = Inbox::new();
+        let listener = inbox.listen();
+        assert!(inbox.deliver(message(1, 7)));
+        assert!(!inbox.deliver(message(1, 7)));
+        // The same id from another sender is another message
+        assert!(inbox.deliver(message(2, 7)));
+
+        assert_eq!(listener.try_iter().count(), 2);
+        assert_eq!(inbox.messages.len(), 2);
+    }
+
+    #[test]
+    fn inbox_keeps_the_latest_messages() {
+        let mut inbox = Inbox::new();
+        for id in 0..SEEN_CAPACITY as u64 + 1 {
+            assert!(inbox.deliver(message(1, id)));
+        }
+        assert_eq!(inbox.messages.len(), INBOX_CAPACITY);
+        assert_eq!(inbox.messages.back().unwrap().id, SEEN_CAPACITY as u64);
+        assert_eq!(inbox.seen.len(), SEEN_CAPACITY);
+        assert!(!inbox.deliver(message(1, SEEN_CAPACITY as u64)));
+        // Forgotten ids are taken as new
+        assert!(inbox.deliver(message(1, 0)));
+    }
+}
@@ -1,95 +1,137 @@
-use std::net::{TcpListener, TcpStream};
-use std::io::{BufReader};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::io::{BufReader, Write};
 use std::sync::{Mutex, Arc};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
 
 use std::collections::{HashMap};
+use std::cmp::Reverse;
 use priority_queue::PriorityQueue;
 use rand::Rng;
 use std::error::Error;
+use std::time::{Duration, Instant};
+use ed25519_dalek::SigningKey;
 
 use crate::connection::{Connection, ConnectionRef};
-use crate::connection::Message;
+use crate::connection::{Message, MALFORMED};
 use crate::key::Key;
 use crate::data::Data;
+use crate::index::{self, IndexEntry};
+use crate::ledger::{remote_ip, Ledger};
+use crate::record::{self, MutableRecord};
+use crate::pubsub::{self, PubSub, TopicMessage, FANOUT};
+use crate::chat::{ChatMessage, Inbox};
+use crate::latency::{NodeStats, Probe};
+use crate::config::Config;
+use crate::metrics::metrics;
 
-const K : i32 = 20;
-const BOOTNODES: [&'static  str; 1] = [
-    "127.0.0.1:12345"
-];
+const MAX_PENALTY: u32 = 3;
 
 
 pub type DhtType = Data;
 pub type PeerRecord = (Key, String);
 
 
-pub fn parse_peer_record(peer_record: &str) -> PeerRecord {
-    let val = peer_record.trim();
+// (key,address) as it appears in FROM, TO, KEY and KEYS headers
+pub fn parse_peer_record(peer_record: &str) -> Result<PeerRecord, &'static str> {
+    let bracket_vals = peer_record.trim().strip_prefix('(').and_then(|val| val.strip_suffix(')')).ok_or(MALFORMED)?;
 
-    let bracket_vals = &val[1..val.len()-1];
-
-    let mut split = bracket_vals.split(',');
-    let parse_key = split.next().unwrap().trim();
-    let parse_addr = split.next().unwrap().trim();
-    let parse_val = parse_key.parse::<u32>().unwrap();
-    (Key{key:parse_val}, parse_addr.to_string())
+    let (parse_key, parse_addr) = bracket_vals.split_once(',').ok_or(MALFORMED)?;
+    let parse_val = parse_key.trim().parse::<u32>().map_err(|_| MALFORMED)?;
+    Ok((Key{key:parse_val}, parse_addr.trim().to_string()))
 }
 
-pub fn parse_providers(providers: &str) -> (String, Key) {
-    let val = providers.trim();
-
-    let bracket_vals = &val[1..val.len()-1];
+// Provider lists travel as a JSON array of [name, key] pairs so names may hold
+// any character. Entries that are not a pair are dropped.
+pub fn parse_providers(providers: &str) -> Result<Vec<(String, Key)>, serde_json::Error> {
+    let entries: Vec<serde_json::Value> = serde_json::from_str(providers.trim())?;
+    Ok(entries.into_iter()
+        .filter_map(|entry| serde_json::from_value::<(String, u32)>(entry).ok())
+        .map(|(name, key)| (name, Key{key}))
+        .collect())
+}
 
-    let mut split = bracket_vals.split(',');
-    let parse_name = split.next().unwrap().trim();
-    let parse_key = split.next().unwrap().trim();
-    let parse_val = parse_key.parse::<u32>().unwrap();
-    (parse_name.to_string(), Key{key:parse_val})
+pub fn format_providers(providers: &[(String, Key)]) -> String {
+    let entries: Vec<(&str, u32)> = providers.iter().map(|(name, key)| (name.as_str(), key.key)).collect();
+    serde_json::to_string(&entries).unwrap()
 }
 
 pub fn create_empty_peer_record() -> PeerRecord {
     (Key{key:0}, "".to_string())
 }
 
-pub fn empty_data(data: &(Key, Data)) -> bool {
-    if data.0.key == 0 {
-        return true;
-    }
-    return false;
+// Where the replicas of a stored value live: the first replicas nodes
+// closest to near, after skipping offset of them
+#[derive(Clone, Copy, PartialEq)]
+pub struct Placement {
+    pub near: Key,
+    pub offset: usize,
+    pub replicas: usize,
 }
 
 #[derive(Clone)]
 pub struct Client {
     pub host: String,
-    pub peer_id: u16,
     pub key : Key,
 
     pub connections: Arc<Mutex<Vec<ConnectionRef>>>,
 
     pub providers : Arc<Mutex<HashMap<String, Key>>>,
     pub known_nodes : Arc<Mutex<HashMap<Key, String>>>,
+    pub node_stats : Arc<Mutex<HashMap<Key, NodeStats>>>,
     pub local_hash : Arc<Mutex<HashMap<Key, DhtType>>>,
+    pub name_index : Arc<Mutex<HashMap<Key, Vec<IndexEntry>>>>,
+    pub penalties : Arc<Mutex<HashMap<Key, u32>>>,
+    pub ledger : Arc<Mutex<Ledger>>,
+    // Keys this node stored and where their replicas belong. Identical
+    // erasure shards share a key but each has a placement of its own.
+    pub owned : Arc<Mutex<HashMap<Key, Vec<Placement>>>>,
+    // Newest verified mutable record seen for each record key
+    pub records : Arc<Mutex<HashMap<Key, MutableRecord>>>,
+    pub signing_key : SigningKey,
+    pub pubsub : Arc<Mutex<PubSub>>,
+    pub inbox : Arc<Mutex<Inbox>>,
+    // Set when shutdown starts, new connections are refused from then on
+    pub stopping : Arc<AtomicBool>,
+    // Set when shutdown is done, the accept and poll loops return
+    pub stopped : Arc<AtomicBool>,
+    // Uploads and downloads this node is running
+    pub transfers : Arc<AtomicUsize>,
+    // Set on the copy an async call runs on, checked before each network round trip
+    pub cancel : Option<Arc<AtomicBool>>,
+    pub config : Arc<Config>,
+}
+
+// Counts a transfer for as long as it is alive
+struct Transfer(Arc<AtomicUsize>);
+
+impl Transfer {
+    fn start(transfers: &Arc<AtomicUsize>) -> Transfer {
+        transfers.fetch_add(1, Ordering::SeqCst);
+        Transfer(transfers.clone())
+    }
+}
+
+impl Drop for Transfer {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
 }
 
 
 impl Client {
     pub fn new(host: String, port: String) -> Box<Client> {
+        Client::with_config(host, port, Config::default())
+    }
+
+    pub fn with_config(host: String, port: String, config: Config) -> Box<Client> {
         let connections: Vec<ConnectionRef> = vec![];
-        let mut known_nodes =  HashMap::new();
 
-        println!("Hosting on {} {}", host, port);
+        log::info!("Hosting on {} {}", host, port);
 
         let address = host + ":" + &port;
-        let mut is_bootnode = false;
-        
-        // Search for bootnodes
-        for boot in BOOTNODES {
-            if boot != address {
-                let temp_key = Key{key:1};
-                known_nodes.insert(temp_key, boot.to_string());
-            } else {
-                is_bootnode = true;
-            }
-        }
+        // Bootstraps stay in the config, their keys are learnt when joining
+        let is_bootnode = config.network.bootnode || config.network.bootstraps.contains(&address);
         
         // Decide whether node is a bootnode or not
         let new_key = if is_bootnode {
@@ -100,36 +142,85 @@ impl Client {
         };
 
         // Create Client Object
-        let client =  Box::new(Client {host: address, peer_id: 0, 
+        Box::new(Client {host: address, 
                                 connections: Arc::new(Mutex::new(connections)), 
                                 local_hash : Arc::new(Mutex::new(HashMap::new())), 
-                                known_nodes: Arc::new(Mutex::new(known_nodes)), 
+                                known_nodes: Arc::new(Mutex::new(HashMap::new())), 
+                                node_stats: Arc::new(Mutex::new(HashMap::new())),
                                 key: new_key, 
-                                providers: Arc::new(Mutex::new(HashMap::new()))});
+                                providers: Arc::new(Mutex::new(HashMap::new())),
+                                name_index: Arc::new(Mutex::new(HashMap::new())),
+                                penalties: Arc::new(Mutex::new(HashMap::new())),
+                                ledger: Arc::new(Mutex::new(Ledger::new())),
+                                owned: Arc::new(Mutex::new(HashMap::new())),
+                                records: Arc::new(Mutex::new(HashMap::new())),
+                                signing_key: SigningKey::generate(&mut rand::rngs::OsRng),
+                                pubsub: Arc::new(Mutex::new(PubSub::new())),
+                                inbox: Arc::new(Mutex::new(Inbox::new())),
+                                stopping: Arc::new(AtomicBool::new(false)),
+                                stopped: Arc::new(AtomicBool::new(false)),
+                                transfers: Arc::new(AtomicUsize::new(0)),
+                                cancel: None,
+                                config: Arc::new(config)})
+    }
 
-        return client;
+    // Known nodes closest first, with what we measured about each
+    pub fn routing_table(&self) -> Vec<(PeerRecord, NodeStats)> {
+        let node_stats = self.node_stats.lock().unwrap();
+        let mut table: Vec<(PeerRecord, NodeStats)> = self.known_nodes.lock().unwrap().iter()
+            .map(|(key, address)| ((*key, address.clone()), node_stats.get(key).copied().unwrap_or_default()))
+            .collect();
+        table.sort_by_key(|((key, _), _)| self.key.distance(*key));
+        table
     }
 
-    pub fn print_state(self) {
+    pub fn print_state(&self) {
         println!("KNOWN NODES");
+        let node_stats = self.node_stats.lock().unwrap();
         for (key, val) in  self.known_nodes.lock().unwrap().iter() {
-            println!("\t{} {}", key.key, val);
+            let stats = node_stats.get(key).copied().unwrap_or_default();
+            let rtt = stats.rtt.map(|rtt| format!("{:.2}ms", rtt.as_secs_f64() * 1000.0)).unwrap_or("-".to_string());
+            let seen = stats.last_seen.map(|seen| format!("{}s ago", seen.elapsed().as_secs())).unwrap_or("never".to_string());
+            println!("\t{} {} rtt {} seen {} failures {}", key.key, val, rtt, seen, stats.failures);
         }
+        drop(node_stats);
         println!("DATA");
         for (key, val) in  self.local_hash.lock().unwrap().iter() {
-            println!("\t{}", key.key);
+            println!("\t{} {}", key.key, val.file_meta);
         }
         println!("Providers");
         for (key, val) in  self.providers.lock().unwrap().iter() {
             println!("\t{} {}", key, val.key);
         }
+        println!("LEDGER");
+        for (key, entry) in  self.ledger.lock().unwrap().entries.iter() {
+            println!("\t{} sent {} received {} ratio {:.2}", key, entry.sent, entry.received, entry.ratio());
+        }
+        println!("RECORDS");
+        for (key, record) in  self.records.lock().unwrap().iter() {
+            println!("\t{} {}/{} seq {} -> {}", key.key, record.public_key, record.name, record.seq, record.value);
+        }
+        println!("TOPICS");
+        let pubsub = self.pubsub.lock().unwrap();
+        for topic in  pubsub.topics() {
+            println!("\t{} {}", topic, pubsub.peers(&topic).len());
+        }
+        drop(pubsub);
+        println!("NAME INDEX");
+        for (key, entries) in  self.name_index.lock().unwrap().iter() {
+            println!("\t{} {}", key.key, entries.len());
+        }
     }
 
     pub fn run(&mut self, listener : TcpListener) {
-        self.get_peer_record();
-
         for stream in listener.incoming() {
-            let stream = stream.unwrap();
+            if self.stopping.load(Ordering::SeqCst) {
+                break;
+            }
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
             let connection = Connection::new(stream, true, true);
             self.connections.lock().unwrap().push(connection);
         }
@@ -139,6 +230,9 @@ impl Client {
         // TEMP FIX NEED THIS FOR SOME REASON: PERTANING TO BLOCKING PROBABLY
         print!("");
         loop {
+            if self.stopped.load(Ordering::SeqCst) {
+                return;
+            }
             let vec = &*self.connections.lock().unwrap();
             for connection in vec {
                 let msg = match connection.recieve_dht.try_recv() {
@@ -150,54 +244,197 @@ impl Client {
                 };
 
 
-                self.known_nodes.lock().unwrap().insert(msg.sending_node.0.clone(), msg.sending_node.1.clone());
+                if msg.type_of == "leave" {
+                    let client = self.clone();
+                    let peer = msg.sending_node.clone();
+                    thread::spawn(move || {
+                        if client.leave_confirmed(&peer) {
+                            log::info!(peer = peer.0.key, address = peer.1.as_str(), msg_type = "LEAVE"; "Node left");
+                            client.forget_node(peer.0);
+                        }
+                    });
+                    continue;
+                }
+                if !self.is_banned(&msg.sending_node.0) {
+                    self.known_nodes.lock().unwrap().insert(msg.sending_node.0, msg.sending_node.1.clone());
+                    self.node_stats.lock().unwrap().entry(msg.sending_node.0).or_default().seen();
+                }
                 if msg.type_of == "k_peers" {
                     let mut new_msg = msg.clone();
                     new_msg.keys = self.find_k_closest_computers(&new_msg.key.0.clone());
                     //print!("");
 
-                    let key = new_msg.key.0.clone();
+                    let key = new_msg.key.0;
 
                     if key.key > 0 {
-                        if self.local_hash.lock().unwrap().contains_key(&key) {
-                            let val = self.local_hash.lock().unwrap().get(&key).unwrap().clone();
-                            new_msg.data.1 = val;
+                        let found = self.local_hash.lock().unwrap().get(&key).cloned();
+                        match found {
+                            Some(val) => {
+                                let mut ledger = self.ledger.lock().unwrap();
+                                if ledger.should_serve(connection.remote) {
+                                    ledger.record_sent(connection.remote, val.vec.len());
+                                    metrics().served(val.vec.len());
+                                    new_msg.data.1 = val;
+                                } else {
+                                    new_msg.type_of = "choked".to_string();
+                                }
+                            },
+                            None => new_msg.type_of = "not_found".to_string(),
                         }
-                     
                     }
-                    connection.send_dht.send(new_msg.clone());
+                    let _ = connection.send_reply.send(new_msg.clone());
+                } else if msg.type_of == "find_node" {
+                    let mut new_msg = msg.clone();
+                    new_msg.keys = self.find_k_closest_computers(&msg.key.0);
+                    // Lets a node that only knew our address learn our key
+                    new_msg.sending_node = (self.key, self.host.clone());
+                    let _ = connection.send_reply.send(new_msg);
+                } else if msg.type_of == "chat" {
+                    let mut new_msg = msg.clone();
+                    match ChatMessage::from_data(&msg.data.1) {
+                        Some(message) if msg.key.0 == self.key && message.from == msg.sending_node.0.key => {
+                            self.inbox.lock().unwrap().deliver(message);
+                        },
+                        _ => new_msg.type_of = "not_found".to_string(),
+                    }
+                    let _ = connection.send_reply.send(new_msg);
                 } else if msg.type_of == "insert" {
+                    if Key::generate_hash_from_data(&msg.data.1.vec) != msg.data.0 {
+                        log::warn!(peer = msg.sending_node.0.key, address = msg.sending_node.1.as_str(), msg_type = "INSERT", key = msg.data.0.key; "Rejected corrupted insert");
+                        self.penalize(msg.sending_node.0);
+                        continue;
+                    }
+                    self.ledger.lock().unwrap().record_received(connection.remote, msg.data.1.vec.len());
+                    if !self.has_room(msg.data.1.vec.len()) {
+                        log::warn!(peer = msg.sending_node.0.key, msg_type = "INSERT", key = msg.data.0.key; "Storage quota reached, value not kept");
+                        continue;
+                    }
+                    metrics().stored(msg.data.1.vec.len());
                     self.local_hash.lock().unwrap().insert(msg.data.0, msg.data.1.clone());
-                    self.providers.lock().unwrap().insert(msg.key.1, msg.data.0); 
+                    if !msg.name.is_empty() {
+                        self.providers.lock().unwrap().insert(msg.name, msg.data.0); 
+                    }
                 } else if msg.type_of == "providers" {
                     let mut new_msg = msg.clone();
                     let mut provider_vector: Vec<(String, Key)> = Vec::new();
 
                     for (name, key) in self.providers.lock().unwrap().iter() {
-                        provider_vector.push(((*name).clone(), (*key).clone()));
+                        provider_vector.push(((*name).clone(), (*key)));
                     }
                     new_msg.providers = provider_vector;
-                    connection.send_dht.send(new_msg.clone());
+                    let _ = connection.send_reply.send(new_msg.clone());
+                } else if msg.type_of == "has" {
+                    let mut new_msg = msg.clone();
+                    if !self.local_hash.lock().unwrap().contains_key(&msg.key.0) {
+                        new_msg.type_of = "not_found".to_string();
+                    }
+                    let _ = connection.send_reply.send(new_msg);
+                } else if msg.type_of == "index_insert" {
+                    let mut name_index = self.name_index.lock().unwrap();
+                    for entry in msg.providers {
+                        index::add_entry(&mut name_index, msg.key.0, entry);
+                    }
+                } else if msg.type_of == "record_put" {
+                    let stored = match MutableRecord::from_data(&msg.data.1) {
+                        Some(record) if record.key() == msg.data.0 => self.store_record(record),
+                        _ => false,
+                    };
+                    if !stored {
+                        log::info!(peer = msg.sending_node.0.key, address = msg.sending_node.1.as_str(), msg_type = "RECORD_PUT", key = msg.data.0.key; "Ignored record");
+                    }
+                } else if msg.type_of == "record_get" {
+                    let mut new_msg = msg.clone();
+                    match self.records.lock().unwrap().get(&msg.key.0) {
+                        Some(record) => new_msg.data.1 = record.to_data(),
+                        None => new_msg.type_of = "not_found".to_string(),
+                    }
+                    let _ = connection.send_reply.send(new_msg);
+                } else if msg.type_of == "subscribe" {
+                    let mut new_msg = msg.clone();
+                    let topic = &msg.data.1.file_meta.filename;
+                    let mut pubsub = self.pubsub.lock().unwrap();
+                    new_msg.keys = pubsub.peers(topic).into_iter().filter(|peer| peer.0 != msg.sending_node.0).collect();
+                    pubsub.add_peer(topic, msg.sending_node.clone());
+                    let _ = connection.send_reply.send(new_msg);
+                } else if msg.type_of == "unsubscribe" {
+                    self.pubsub.lock().unwrap().remove_peer(&msg.data.1.file_meta.filename, msg.sending_node.0);
+                } else if msg.type_of == "gossip" {
+                    let mut gossip = match TopicMessage::from_data(&msg.data.1) {
+                        Some(gossip) => gossip,
+                        None => continue,
+                    };
+                    if !self.pubsub.lock().unwrap().mark_seen(gossip.id) {
+                        continue;
+                    }
+                    let handlers = self.pubsub.lock().unwrap().handlers(&gossip.topic);
+                    for handler in handlers {
+                        handler(&gossip);
+                    }
+
+                    if gossip.expired() {
+                        continue;
+                    }
+                    gossip.hops += 1;
+                    let exclude = [msg.sending_node.0, Key {key: gossip.origin}, self.key];
+                    let targets = self.pubsub.lock().unwrap().fanout(&gossip.topic, &exclude);
+                    let client = self.clone();
+                    thread::spawn(move || {
+                        for peer in targets {
+                            client.send_gossip(&peer, &gossip);
+                        }
+                    });
+                } else if msg.type_of == "index_get" {
+                    let mut new_msg = msg.clone();
+                    new_msg.providers = self.name_index.lock().unwrap().get(&msg.key.0).cloned().unwrap_or_default();
+                    let _ = connection.send_reply.send(new_msg);
                 }
             }
         }
     }
 
     pub fn get_data(&mut self, find_key: Key) -> Result<DhtType, Box<dyn Error>> {
-        let comps  = self.find_k_closest_computers(&find_key);
+        self.fetch_value(find_key, find_key, true)
+    }
 
-        let mut data : (Key, DhtType) = (Key {key: 0}, Data::create_empty());
-        let mut vec: Vec<u32> = Vec::new();
-        for (key, address) in comps.clone() {
+    // Ask the nodes closest to near for find_key. With cache set the value is
+    // kept locally and pushed back to close nodes that were missing it.
+    pub fn fetch_value(&mut self, find_key: Key, near: Key, cache: bool) -> Result<DhtType, Box<dyn Error>> {
+        if let Some(val) = self.local_hash.lock().unwrap().get(&find_key) {
+            return Ok(val.clone());
+        }
+
+        let started = Instant::now();
+        let found = self.lookup_value(find_key, near, cache);
+        metrics().lookup("value", started.elapsed(), None, found.is_ok());
+        found
+    }
+
+    fn lookup_value(&mut self, find_key: Key, near: Key, cache: bool) -> Result<DhtType, Box<dyn Error>> {
+
+        let _transfer = Transfer::start(&self.transfers);
+        let comps  = self.by_latency(self.find_k_closest_computers(&near));
+
+        let mut data : Option<DhtType> = None;
+        let mut missing: Vec<PeerRecord> = Vec::new();
+        for (key, address) in comps {
             if key == self.key {continue;}
+            if self.cancelled() {
+                return Err("Cancelled")?;
+            }
 
-            let stream = TcpStream::connect(address.clone()).unwrap();
+            let stream = match self.connect(&address) {
+                Ok(stream) => stream,
+                Err(_) => {
+                    self.node_stats.lock().unwrap().entry(key).or_default().record_failure();
+                    continue;
+                },
+            };
             let mut reader = BufReader::new(stream.try_clone().unwrap());
             let peer_record: PeerRecord = (key, address.clone());
             let msg : Message  = Message::new(
                                             "PEERS_I_GET".to_string(), 
-                                            (self.key.clone(), self.host.clone()), 
-                                            (key.clone(), address.clone()), 
+                                            (self.key, self.host.clone()), 
+                                            (key, address.clone()), 
                                             peer_record,
                                             find_key,
                                             Data::create_empty(),
@@ -208,142 +445,996 @@ impl Client {
             // SEND TO PEERS REQUESTING K_CLOSEST
             let connection  = Connection::new(stream.try_clone().unwrap(), false, true);
             {
-                connection.sender.send(msg);
+                let _ = connection.sender.send(msg);
             }
             // Recieve K_Closest
-            let msg = Message::read_message(&mut reader).unwrap();
-            data = (msg.data.0, msg.data.1.clone());
-            if data.1 == Data::create_empty() {vec.push(1);}
-            else { vec.push(0); }
+            let msg = match self.read_reply(&mut reader, key) {
+                Ok(msg) => msg,
+                Err(_) => continue,
+            };
+            let _ = stream.shutdown(std::net::Shutdown::Read);
+
+            if msg.type_of == "NOT_FOUND" {
+                missing.push((key, address));
+                continue;
+            }
+            if msg.type_of == "CHOKED" {
+                log::info!(peer = key.key, address = address.as_str(), msg_type = "CHOKED", key = find_key.key; "Choked");
+                continue;
+            }
+
+            // Content must hash back to the key it was requested under
+            let val = msg.data.1;
+            if Key::generate_hash_from_data(&val.vec) != find_key || val.file_meta.verify(&val.vec).is_err() {
+                log::warn!(peer = key.key, address = address.as_str(), key = find_key.key; "Corrupted value");
+                self.penalize(key);
+                missing.push((key, address));
+                continue;
+            }
+            self.ledger.lock().unwrap().record_received(remote_ip(&stream), val.vec.len());
+            // One verified copy is enough, the remaining nodes are not asked
+            data = Some(val);
+            break;
+        }
+
+        let data = match data {
+            Some(data) => data,
+            None => return Err("Not Found")?,
+        };
+        if !cache {
+            return Ok(data);
+        }
+
+        // Repair peers that should have held the value
+        for peer in missing {
+            let name = data.file_meta.filename.clone();
+            self.send_insert(&peer, &name, find_key, &data);
+        }
+        if self.has_room(data.vec.len()) {
+            self.local_hash.lock().unwrap().insert(find_key, data.clone());    
+        }
+
+        Ok(data)
+    }
+
+    // Copies of each value stored, k unless a caller asks otherwise
+    pub fn replication(&self) -> usize {
+        self.config.dht.k
+    }
+
+    // Whether a value of bytes held for someone else fits in the storage quotas
+    fn has_room(&self, bytes: usize) -> bool {
+        let storage = &self.config.storage;
+        if storage.max_value_bytes > 0 && bytes as u64 > storage.max_value_bytes {
+            return false;
+        }
+        if storage.max_bytes == 0 {
+            return true;
+        }
+        let used: usize = self.local_hash.lock().unwrap().values().map(|data| data.vec.len()).sum();
+        (used + bytes) as u64 <= storage.max_bytes
+    }
+
+    // Count a protocol violation against a peer, forgetting it after MAX_PENALTY
+    pub fn penalize(&self, key: Key) {
+        let mut penalties = self.penalties.lock().unwrap();
+        let count = penalties.entry(key).or_insert(0);
+        *count += 1;
+        if *count >= MAX_PENALTY {
+            log::warn!(peer = key.key; "Dropping misbehaving node");
+            self.known_nodes.lock().unwrap().remove(&key);
+        }
+    }
+
+    pub fn is_banned(&self, key: &Key) -> bool {
+        self.penalties.lock().unwrap().get(key).map(|count| *count >= MAX_PENALTY).unwrap_or(false)
+    }
+
+    pub fn put_data(&mut self, name: String, data : DhtType) -> Key {
+        self.put_data_with_replicas(name, data, self.replication())
+    }
+
+    pub fn put_data_with_replicas(&mut self, name: String, data : DhtType, replicas: usize) -> Key {
+        let calc_key = self.store_value(&name, &data, replicas);
+        self.providers.lock().unwrap().insert(name.clone(), calc_key);    
+
+        let filename = data.file_meta.filename.clone();
+        self.publish_name(&filename, calc_key);
+        self.publish(pubsub::UPLOADS_TOPIC, &pubsub::upload_notice(calc_key, &data.file_meta));
+        calc_key
+    }
+
+    // Store a value here and on the closest replicas nodes, empty names are not recorded as providers
+    pub fn store_value(&mut self, name: &str, data : &DhtType, replicas: usize) -> Key {
+        let calc_key = Key::generate_hash_from_data(&data.vec);
+        self.store_value_at(name, data, Placement {near: calc_key, offset: 0, replicas})
+    }
+
+    // Store a value on the nodes closest to placement.near, skipping the first placement.offset of them
+    pub fn store_value_at(&mut self, name: &str, data : &DhtType, placement: Placement) -> Key {
+        let calc_key = Key::generate_hash_from_data(&data.vec);
+        let replicas = placement.replicas;
+        let _transfer = Transfer::start(&self.transfers);
+
+        self.local_hash.lock().unwrap().insert(calc_key, data.clone());    
+        let mut owned = self.owned.lock().unwrap();
+        let placements = owned.entry(calc_key).or_default();
+        if !placements.contains(&placement) {
+            placements.push(placement);
+        }
+        drop(owned);
+
+        let mut stored = 0;
+        let others: Vec<PeerRecord> = self.closest_nodes(&placement.near, self.config.dht.k).into_iter().filter(|peer| peer.0 != self.key).collect();
+        for peer in others.iter().cycle().skip(placement.offset).take(others.len()) {
+            if stored >= replicas || self.cancelled() { break; }
+            if peer.0 == self.key {continue;}
+
+            if self.send_insert(peer, name, calc_key, data) {
+                stored += 1;
+            }
+        }             
+        calc_key
+    }
+
+    // Push a value to one node, false when it cannot be reached
+    pub fn send_insert(&self, peer: &PeerRecord, name: &str, data_key: Key, data : &DhtType) -> bool {
+        let (key, address) = peer.clone();
+        let stream = match self.connect(&address) {
+            Ok(stream) => stream,
+            Err(_) => return false,
+        };
+        let peer_record: PeerRecord = (Key{key:0}, name.to_string());
+        let msg : Message  = Message::new(
+                                        "INSERT".to_string(), 
+                                        (self.key, self.host.clone()), 
+                                        (key, address),
+                                        peer_record,
+                                        data_key,
+                                        data.clone(),
+                                    );
+
+        let connection  = Connection::new(stream.try_clone().unwrap(), false, true);
+        {
+            let _ = connection.sender.send(msg);
+        }
+        self.ledger.lock().unwrap().record_sent(remote_ip(&stream), data.vec.len());
+        let _ = stream.shutdown(std::net::Shutdown::Read);
+        true
+    }
+
+    // Ask a node whether it holds a key, None when it cannot be reached
+    pub fn has_replica(&self, peer: &PeerRecord, data_key: Key) -> Option<bool> {
+        let (key, address) = peer.clone();
+        let stream = self.connect(&address).ok()?;
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let msg : Message  = Message::new(
+                                        "HAS".to_string(), 
+                                        (self.key, self.host.clone()), 
+                                        (key, address),
+                                        create_empty_peer_record(),
+                                        data_key,
+                                        Data::create_empty(),
+                                    );
+
+        let connection  = Connection::new(stream.try_clone().unwrap(), false, true);
+        {
+            let _ = connection.sender.send(msg);
+        }
+        let reply = self.read_reply(&mut reader, key).ok()?;
+        let _ = stream.shutdown(std::net::Shutdown::Read);
+        Some(reply.type_of == "HAVE")
+    }
+
+    // Check every key this node stored and re-upload it until the closest
+    // reachable nodes hold the requested number of replicas again
+    pub fn audit_replicas(&mut self) -> Vec<(Key, usize, usize)> {
+        let owned: Vec<(Key, Placement)> = self.owned.lock().unwrap().iter()
+            .flat_map(|(key, placements)| placements.iter().map(move |placement| (*key, *placement)))
+            .collect();
+
+        let mut report = Vec::new();
+        for (data_key, placement) in owned {
+            let replicas = placement.replicas;
+            let data = match self.local_hash.lock().unwrap().get(&data_key) {
+                Some(data) => data.clone(),
+                None => continue,
+            };
+
+            let mut live = 0;
+            let mut repaired = 0;
+            let others: Vec<PeerRecord> = self.closest_nodes(&placement.near, usize::MAX).into_iter().filter(|peer| peer.0 != self.key).collect();
+            for peer in others.iter().cycle().skip(placement.offset).take(others.len()) {
+                if live >= replicas { break; }
+                if peer.0 == self.key {continue;}
+
+                match self.has_replica(peer, data_key) {
+                    Some(true) => live += 1,
+                    Some(false) => {
+                        if self.send_insert(peer, &data.file_meta.filename, data_key, &data) {
+                            live += 1;
+                            repaired += 1;
+                        }
+                    },
+                    None => {
+                        log::warn!(peer = peer.0.key, address = peer.1.as_str(); "Node unreachable, dropping it");
+                        self.known_nodes.lock().unwrap().remove(&peer.0);
+                    },
+                }
+            }
+            report.push((data_key, live, repaired));
+        }
+        report
+    }
+
+    // Keep a record only if its signature holds and it is a newer version of the one we have
+    pub fn store_record(&self, record: MutableRecord) -> bool {
+        if !record.verify() {
+            return false;
         }
-        if data.1 ==  Data::create_empty() {
-            return Err("Not Found")?;
+        let mut records = self.records.lock().unwrap();
+        match records.get(&record.key()) {
+            // Someone else's record whose key collides cannot replace it
+            Some(current) if !current.is_owned_by(&record.public_key, &record.name) => {
+                log::warn!(key = record.key().key, name = record.name.as_str(); "Rejected record from a different publisher");
+                false
+            },
+            Some(current) if current.seq >= record.seq => false,
+            _ => {
+                records.insert(record.key(), record);
+                true
+            },
         }
+    }
+
+    // Point this node's record under name at value, superseding any earlier version
+    pub fn publish_record(&mut self, name: &str, value: Key) -> MutableRecord {
+        let public_key = record::public_key_hex(&self.signing_key);
+        let seq = self.fetch_record(&public_key, name).map(|current| current.seq + 1).unwrap_or(1);
 
-        for (i, (key, address)) in comps.clone().iter().enumerate() {
-            if *key == self.key {continue;}
-            if vec[i] == 0 { continue; }
-            let stream = TcpStream::connect(address.clone()).unwrap();
+        let new_record = MutableRecord::signed(&self.signing_key, name, seq, value);
+        self.store_record(new_record.clone());
+        for peer in self.find_k_closest_computers(&new_record.key()) {
+            if peer.0 == self.key {continue;}
+            self.send_record(&peer, &new_record);
+        }
+        new_record
+    }
+
+    // Push a record to one node, false when it cannot be reached
+    pub fn send_record(&self, peer: &PeerRecord, record: &MutableRecord) -> bool {
+        let (key, address) = peer.clone();
+        let stream = match self.connect(&address) {
+            Ok(stream) => stream,
+            Err(_) => return false,
+        };
+        let msg : Message  = Message::new(
+                                        "RECORD_PUT".to_string(), 
+                                        (self.key, self.host.clone()), 
+                                        (key, address),
+                                        create_empty_peer_record(),
+                                        record.key(),
+                                        record.to_data(),
+                                    );
+
+        let connection  = Connection::new(stream.try_clone().unwrap(), false, true);
+        {
+            let _ = connection.sender.send(msg);
+        }
+        let _ = stream.shutdown(std::net::Shutdown::Read);
+        true
+    }
+
+    // Newest valid record public_key keeps under name, refreshing nodes that held an older one
+    pub fn fetch_record(&mut self, public_key: &str, name: &str) -> Option<MutableRecord> {
+        let record_key = record::record_key(public_key, name);
+        let mut newest: Option<MutableRecord> = self.records.lock().unwrap().get(&record_key)
+            .filter(|current| current.is_owned_by(public_key, name))
+            .cloned();
+        let mut seen: Vec<(PeerRecord, u64)> = Vec::new();
+
+        let comps  = self.find_k_closest_computers(&record_key);
+        for (key, address) in comps {
+            if key == self.key {continue;}
+
+            let stream = match self.connect(&address) {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
             let mut reader = BufReader::new(stream.try_clone().unwrap());
-            let peer_record: PeerRecord = (key.clone(), address.clone());
             let msg : Message  = Message::new(
-                                            "INSERT".to_string(), 
-                                            (self.key.clone(), self.host.clone()), 
-                                            (key.clone(), address.clone()), 
-                                            peer_record,
-                                            data.0.clone(),
-                                            data.1.clone(),
+                                            "RECORD_GET".to_string(), 
+                                            (self.key, self.host.clone()), 
+                                            (key, address.clone()), 
+                                            create_empty_peer_record(),
+                                            record_key,
+                                            Data::create_empty(),
                                         );
 
-            
-            
-            // SEND TO PEERS REQUESTING K_CLOSEST
             let connection  = Connection::new(stream.try_clone().unwrap(), false, true);
             {
-                connection.sender.send(msg);
+                let _ = connection.sender.send(msg);
+            }
+            let msg = match self.read_reply(&mut reader, key) {
+                Ok(msg) => msg,
+                Err(_) => continue,
+            };
+            let _ = stream.shutdown(std::net::Shutdown::Read);
+
+            if msg.type_of == "NOT_FOUND" {
+                seen.push(((key, address), 0));
+                continue;
+            }
+            let found = match MutableRecord::from_data(&msg.data.1) {
+                Some(found) if found.is_owned_by(public_key, name) && found.verify() => found,
+                _ => {
+                    log::warn!(peer = key.key, address = address.as_str(), msg_type = "RECORD_GET_REPLY", key = record_key.key; "Invalid record");
+                    self.penalize(key);
+                    continue;
+                },
+            };
+            seen.push(((key, address), found.seq));
+            if newest.as_ref().map(|current| found.seq > current.seq).unwrap_or(true) {
+                newest = Some(found);
             }
-            // Recieve K_Closest
-            let msg = Message::read_message(&mut reader).unwrap();
-            data = (msg.data.0, msg.data.1.clone());
         }
-        self.local_hash.lock().unwrap().insert(data.0, data.1.clone());    
 
-        return Ok(data.1);
+        let newest = newest?;
+        self.store_record(newest.clone());
+        for (peer, seq) in seen {
+            if seq < newest.seq {
+                self.send_record(&peer, &newest);
+            }
+        }
+        Some(newest)
     }
 
-    pub fn put_data(&mut self, name: String, data : DhtType) -> () {
-        let calc_key = Key::generate_hash_from_data(&data.vec);
+    // Content key a publisher currently points name at
+    pub fn resolve_record(&mut self, public_key: &str, name: &str) -> Option<MutableRecord> {
+        self.fetch_record(public_key, name)
+            .filter(|found| found.public_key.eq_ignore_ascii_case(public_key) && found.name == name)
+    }
 
-        self.local_hash.lock().unwrap().insert(calc_key, data.clone());    
-        self.providers.lock().unwrap().insert(name.clone(), calc_key.clone());    
+    // Deliver a text message to the node with key, looking up its address if
+    // needed. Returns once the recipient acknowledged it.
+    pub fn send_message(&mut self, to: Key, text: &str) -> Result<ChatMessage, Box<dyn Error>> {
+        let message = ChatMessage::new(self.key, text);
+
+        let known = self.known_nodes.lock().unwrap().get(&to).cloned();
+        if let Some(address) = known {
+            if self.send_chat(&(to, address), &message) {
+                return Ok(message);
+            }
+        }
+
+        // Unknown or stale address, ask the network where the node lives now
+        let peer = self.find_node(to).ok_or(format!("Node {} not found", to.key))?;
+        if self.send_chat(&peer, &message) {
+            return Ok(message);
+        }
+        Err(format!("Node {} did not acknowledge the message", to.key))?
+    }
+
+    // Hand a chat message to one node, true once it acknowledged delivery
+    fn send_chat(&self, peer: &PeerRecord, message: &ChatMessage) -> bool {
+        let (key, address) = peer.clone();
+        let stream = match self.connect(&address) {
+            Ok(stream) => stream,
+            Err(_) => return false,
+        };
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let msg : Message  = Message::new(
+                                        "CHAT".to_string(), 
+                                        (self.key, self.host.clone()), 
+                                        (key, address),
+                                        create_empty_peer_record(),
+                                        key,
+                                        message.to_data(),
+                                    );
+
+        let connection  = Connection::new(stream.try_clone().unwrap(), false, true);
+        {
+            let _ = connection.sender.send(msg);
+        }
+        let reply = self.read_reply(&mut reader, key);
+        let _ = stream.shutdown(std::net::Shutdown::Read);
+        match reply {
+            Ok(reply) => reply.type_of == "CHAT_ACK" && ChatMessage::from_data(&reply.data.1).map(|ack| ack.id == message.id).unwrap_or(false),
+            Err(_) => false,
+        }
+    }
+
+    // Iterative lookup: query the alpha closest unqueried nodes for their
+    // closest nodes to target until the target turns up or nobody closer is left
+    pub fn find_node(&mut self, target: Key) -> Option<PeerRecord> {
+        let started = Instant::now();
+        let (found, hops) = self.lookup_node(target);
+        metrics().lookup("node", started.elapsed(), Some(hops), found.is_some());
+        found
+    }
 
-        let comps  = self.find_k_closest_computers(&calc_key);
+    // Iterative lookup, also returning the rounds of queries it took
+    fn lookup_node(&mut self, target: Key) -> (Option<PeerRecord>, usize) {
+        let mut queried: Vec<Key> = vec![self.key];
+        let mut hops = 0;
+        loop {
+            let candidates: Vec<PeerRecord> = self.find_k_closest_computers(&target).into_iter()
+                .filter(|peer| !queried.contains(&peer.0))
+                .take(self.config.dht.alpha)
+                .collect();
+            if candidates.is_empty() {
+                return (None, hops);
+            }
+            hops += 1;
+
+            for peer in candidates {
+                queried.push(peer.0);
+                let closest = match self.request_closest(&peer, target) {
+                    Some(closest) => closest,
+                    None => continue,
+                };
+                for record in closest {
+                    if record.0 == self.key || self.is_banned(&record.0) {continue;}
+                    if record.0 == target {
+                        self.known_nodes.lock().unwrap().insert(record.0, record.1.clone());
+                        return (Some(record), hops);
+                    }
+                    self.known_nodes.lock().unwrap().entry(record.0).or_insert(record.1);
+                }
+            }
+        }
+    }
+
+    // Ask one node for the nodes it knows closest to target
+    fn request_closest(&self, peer: &PeerRecord, target: Key) -> Option<Vec<PeerRecord>> {
+        let (key, address) = peer.clone();
+        let stream = self.connect(&address).ok()?;
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let msg : Message  = Message::new(
+                                        "PEERS_I".to_string(), 
+                                        (self.key, self.host.clone()), 
+                                        (key, address),
+                                        create_empty_peer_record(),
+                                        target,
+                                        Data::create_empty(),
+                                    );
+
+        let connection  = Connection::new(stream.try_clone().unwrap(), false, true);
+        {
+            let _ = connection.sender.send(msg);
+        }
+        let reply = self.read_reply(&mut reader, key).ok()?;
+        let _ = stream.shutdown(std::net::Shutdown::Read);
+        Some(reply.keys)
+    }
+
+    pub fn cancelled(&self) -> bool {
+        self.cancel.as_ref().map(|cancel| cancel.load(Ordering::SeqCst)).unwrap_or(false)
+    }
+
+    // Anyone can send a LEAVE naming any key, so it only counts when it comes from
+    // the address the node is known by and that address no longer answers a ping.
+    // A leaving node has closed its listener before it announces itself.
+    pub fn leave_confirmed(&self, peer: &PeerRecord) -> bool {
+        let known = self.known_nodes.lock().unwrap().get(&peer.0).cloned();
+        if known.is_none() {
+            return false;
+        }
+        if known.as_ref() != Some(&peer.1) {
+            log::warn!(peer = peer.0.key, address = peer.1.as_str(), msg_type = "LEAVE"; "Ignored LEAVE from an address the node is not known by");
+            return false;
+        }
+        if self.probe(peer).is_ok() {
+            log::warn!(peer = peer.0.key, address = peer.1.as_str(), msg_type = "LEAVE"; "Ignored LEAVE from a node that still answers");
+            return false;
+        }
+        true
+    }
+
+    pub fn forget_node(&self, key: Key) {
+        self.known_nodes.lock().unwrap().remove(&key);
+        self.node_stats.lock().unwrap().remove(&key);
+        self.pubsub.lock().unwrap().remove_node(key);
+    }
+
+    // Leave the network: refuse new connections, let transfers in flight finish,
+    // hand stored values, records and index entries to the nearest other
+    // nodes, tell every known node we are going and save the ledger
+    pub fn shutdown(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.stopping.swap(true, Ordering::SeqCst) {
+            return Err("Already shutting down".into());
+        }
+        log::info!("Shutting down");
+
+        // Wake the accept loop so it sees the flag
+        let _ = TcpStream::connect(self.host.clone());
+
+        let deadline = Instant::now() + self.config.timeouts.drain();
+        while Instant::now() < deadline && !self.is_idle() {
+            thread::sleep(Duration::from_millis(50));
+        }
+
+        let handed_off = self.hand_off();
+        log::info!("Handed off {} values", handed_off);
+        self.announce_leave();
+
+        let saved = self.ledger.lock().unwrap().save();
+        self.stopped.store(true, Ordering::SeqCst);
+        saved.map_err(|e| format!("Could not save ledger: {}", e).into())
+    }
+
+    fn is_idle(&self) -> bool {
+        self.transfers.load(Ordering::SeqCst) == 0 && self.connections.lock().unwrap().iter().all(|connection| connection.is_idle())
+    }
+
+    // Make sure the nearest reachable other node holds everything stored here,
+    // returns how many values had to be sent
+    fn hand_off(&mut self) -> usize {
+        let values: Vec<(Key, DhtType)> = self.local_hash.lock().unwrap().iter().map(|(key, data)| (*key, data.clone())).collect();
+        let mut sent = 0;
+        for (data_key, data) in values {
+            for peer in self.find_k_closest_computers(&data_key) {
+                if peer.0 == self.key {continue;}
+                match self.has_replica(&peer, data_key) {
+                    Some(true) => break,
+                    Some(false) => {
+                        if self.send_insert(&peer, &data.file_meta.filename, data_key, &data) {
+                            sent += 1;
+                            break;
+                        }
+                    },
+                    None => continue,
+                }
+            }
+        }
+
+        // Stores keep the highest sequence and merge entries, so these are pushed as they are
+        let records: Vec<MutableRecord> = self.records.lock().unwrap().values().cloned().collect();
+        for stored in records {
+            for peer in self.find_k_closest_computers(&stored.key()) {
+                if peer.0 != self.key && self.send_record(&peer, &stored) {
+                    break;
+                }
+            }
+        }
+        let entries: Vec<(Key, Vec<IndexEntry>)> = self.name_index.lock().unwrap().iter().map(|(key, entries)| (*key, entries.clone())).collect();
+        for (index_key, index_entries) in entries {
+            for peer in self.find_k_closest_computers(&index_key) {
+                if peer.0 != self.key && self.send_index_entries(&peer, index_key, index_entries.clone()) {
+                    break;
+                }
+            }
+        }
+        sent
+    }
+
+    fn announce_leave(&self) {
+        let known: Vec<PeerRecord> = self.known_nodes.lock().unwrap().iter().map(|(key, address)| (*key, address.clone())).collect();
+        for (key, address) in known {
+            if key == self.key {continue;}
+            let stream = match self.connect(&address) {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+            let msg : Message  = Message::new(
+                                            "LEAVE".to_string(), 
+                                            (self.key, self.host.clone()), 
+                                            (key, address),
+                                            create_empty_peer_record(),
+                                            self.key,
+                                            Data::create_empty(),
+                                        );
+
+            // Written here rather than by a write thread so it is out before the process exits
+            let mut stream = stream;
+            let _ = stream.write_all(msg.make_message().as_bytes());
+            let _ = stream.shutdown(std::net::Shutdown::Both);
+        }
+    }
+
+    // Join a topic: register the handler, then learn the mesh from the nodes
+    // closest to the topic key and announce ourselves to every member found.
+    // Returns how many members were reached.
+    pub fn subscribe<F>(&mut self, topic: &str, handler: F) -> Result<usize, Box<dyn Error>> where F: Fn(&TopicMessage) + Send + Sync + 'static {
+        if !self.pubsub.lock().unwrap().subscribe(topic, Arc::new(handler)) {
+            return Err(format!("Already subscribed to {}", topic).into());
+        }
+
+        let mut members: Vec<PeerRecord> = Vec::new();
+        for peer in self.find_k_closest_computers(&pubsub::topic_key(topic)) {
+            if peer.0 == self.key {continue;}
+            for member in self.send_subscribe(&peer, topic).unwrap_or_default() {
+                if member.0 != self.key && !members.contains(&member) {
+                    members.push(member);
+                }
+            }
+        }
+
+        let mut joined = 0;
+        for member in members {
+            if self.send_subscribe(&member, topic).is_some() {
+                self.pubsub.lock().unwrap().add_peer(topic, member);
+                joined += 1;
+            }
+        }
+        Ok(joined)
+    }
+
+    pub fn unsubscribe(&mut self, topic: &str) -> Result<(), Box<dyn Error>> {
+        if !self.pubsub.lock().unwrap().unsubscribe(topic) {
+            return Err(format!("Not subscribed to {}", topic).into());
+        }
+
+        let mut peers = self.pubsub.lock().unwrap().peers(topic);
+        peers.extend(self.find_k_closest_computers(&pubsub::topic_key(topic)));
+        for peer in peers {
+            if peer.0 == self.key {continue;}
+            self.send_topic_message(&peer, "UNSUBSCRIBE", topic, Data::new(topic, Vec::new()));
+        }
+        Ok(())
+    }
+
+    // Gossip a message to the topic mesh, returns how many peers it was handed to
+    pub fn publish(&mut self, topic: &str, payload: &str) -> usize {
+        let gossip = TopicMessage::new(topic, self.key, payload);
+        self.pubsub.lock().unwrap().mark_seen(gossip.id);
+
+        let mut targets = self.pubsub.lock().unwrap().fanout(topic, &[self.key]);
+        // Outside the mesh the nodes keeping the member list pass it on
+        if targets.is_empty() {
+            targets = self.find_k_closest_computers(&pubsub::topic_key(topic)).into_iter()
+                .filter(|peer| peer.0 != self.key)
+                .take(FANOUT)
+                .collect();
+        }
+
+        let mut sent = 0;
+        for peer in targets {
+            if self.send_gossip(&peer, &gossip) {
+                sent += 1;
+            }
+        }
+        sent
+    }
+
+    // Hand a gossip message to one peer, dropping it from the mesh when unreachable
+    pub fn send_gossip(&self, peer: &PeerRecord, gossip: &TopicMessage) -> bool {
+        if self.send_topic_message(peer, "GOSSIP", &gossip.topic, gossip.to_data()) {
+            return true;
+        }
+        self.pubsub.lock().unwrap().remove_peer(&gossip.topic, peer.0);
+        false
+    }
+
+    fn send_topic_message(&self, peer: &PeerRecord, type_of: &str, topic: &str, data: Data) -> bool {
+        let (key, address) = peer.clone();
+        let stream = match self.connect(&address) {
+            Ok(stream) => stream,
+            Err(_) => return false,
+        };
+        let msg : Message  = Message::new(
+                                        type_of.to_string(), 
+                                        (self.key, self.host.clone()), 
+                                        (key, address),
+                                        create_empty_peer_record(),
+                                        pubsub::topic_key(topic),
+                                        data,
+                                    );
+
+        let connection  = Connection::new(stream.try_clone().unwrap(), false, true);
+        {
+            let _ = connection.sender.send(msg);
+        }
+        let _ = stream.shutdown(std::net::Shutdown::Read);
+        true
+    }
+
+    // Announce membership of a topic to one node, returning the members it knows
+    fn send_subscribe(&self, peer: &PeerRecord, topic: &str) -> Option<Vec<PeerRecord>> {
+        let (key, address) = peer.clone();
+        let stream = self.connect(&address).ok()?;
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let msg : Message  = Message::new(
+                                        "SUBSCRIBE".to_string(), 
+                                        (self.key, self.host.clone()), 
+                                        (key, address),
+                                        create_empty_peer_record(),
+                                        pubsub::topic_key(topic),
+                                        Data::new(topic, Vec::new()),
+                                    );
+
+        let connection  = Connection::new(stream.try_clone().unwrap(), false, true);
+        {
+            let _ = connection.sender.send(msg);
+        }
+        let reply = self.read_reply(&mut reader, key).ok()?;
+        let _ = stream.shutdown(std::net::Shutdown::Read);
+        Some(reply.keys)
+    }
+
+    // Publish a filename under its name key and each of its keywords
+    pub fn publish_name(&mut self, name: &str, content_key: Key) {
+        if name.is_empty() { return; }
+
+        let entry: IndexEntry = (name.to_string(), content_key);
+        for index_key in index::index_keys(name) {
+            index::add_entry(&mut self.name_index.lock().unwrap(), index_key, entry.clone());
+
+            let comps  = self.find_k_closest_computers(&index_key);
+            for peer in comps {
+                if peer.0 == self.key {continue;}
+                self.send_index_entries(&peer, index_key, vec![entry.clone()]);
+            }
+        }
+    }
+
+    // Push entries stored under an index key to one node, false when it cannot be reached
+    pub fn send_index_entries(&self, peer: &PeerRecord, index_key: Key, entries: Vec<IndexEntry>) -> bool {
+        let (key, address) = peer.clone();
+        let stream = match self.connect(&address) {
+            Ok(stream) => stream,
+            Err(_) => return false,
+        };
+        let mut msg : Message  = Message::new(
+                                        "INDEX_INSERT".to_string(), 
+                                        (self.key, self.host.clone()), 
+                                        (key, address), 
+                                        create_empty_peer_record(),
+                                        index_key,
+                                        Data::create_empty(),
+                                    );
+        msg.providers = entries;
+
+        let connection  = Connection::new(stream.try_clone().unwrap(), false, true);
+        {
+            let _ = connection.sender.send(msg);
+        }
+        let _ = stream.shutdown(std::net::Shutdown::Read);
+        true
+    }
+
+    // Fetch every entry stored under an index key from the k closest nodes
+    pub fn lookup_index(&mut self, index_key: Key) -> Vec<IndexEntry> {
+        let mut entries: Vec<IndexEntry> = self.name_index.lock().unwrap().get(&index_key).cloned().unwrap_or_default();
+
+        let comps  = self.find_k_closest_computers(&index_key);
         for (key, address) in comps {
             if key == self.key {continue;}
 
-            let stream = TcpStream::connect(address.clone()).unwrap();
-            let peer_record: PeerRecord = (Key{key:0}, name.clone());
+            let stream = match self.connect(&address) {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
             let msg : Message  = Message::new(
-                                            "INSERT".to_string(), 
-                                            (self.key.clone(), self.host.clone()), 
-                                            (key.clone(), address.clone()), 
-                                            peer_record,
-                                            calc_key.clone(),
-                                            data.clone(),
+                                            "INDEX_GET".to_string(), 
+                                            (self.key, self.host.clone()), 
+                                            (key, address.clone()), 
+                                            create_empty_peer_record(),
+                                            index_key,
+                                            Data::create_empty(),
                                         );
-            
-            
-            
-            // SEND TO PEERS REQUESTING K_CLOSEST
+
             let connection  = Connection::new(stream.try_clone().unwrap(), false, true);
             {
-                connection.sender.send(msg);
+                let _ = connection.sender.send(msg);
             }
-     
-            stream.shutdown(std::net::Shutdown::Read);
-        }             
+
+            let msg = match self.read_reply(&mut reader, key) {
+                Ok(msg) => msg,
+                Err(_) => continue,
+            };
+            for entry in msg.providers {
+                if !entries.contains(&entry) {
+                    entries.push(entry);
+                }
+            }
+            let _ = stream.shutdown(std::net::Shutdown::Read);
+        }
+        entries
     }
 
-    pub fn get_peer_record(&mut self) {
-        let comps  = self.find_k_closest_computers(&self.key);
-        for (key, address) in comps {
-            let stream = TcpStream::connect(address.clone()).unwrap();
-            let mut reader = BufReader::new(stream.try_clone().unwrap());
+    // Every content key published under exactly this filename
+    pub fn resolve_name(&mut self, name: &str) -> Vec<Key> {
+        let mut keys: Vec<Key> = Vec::new();
+        if let Some(key) = self.providers.lock().unwrap().get(name) {
+            keys.push(*key);
+        }
+
+        for (entry_name, key) in self.lookup_index(index::name_key(name)) {
+            if entry_name == name && !keys.contains(&key) {
+                keys.push(key);
+            }
+        }
+
+        // Fall back to the provider records of nearby nodes
+        if keys.is_empty() {
+            self.get_providers();
+            if let Some(key) = self.providers.lock().unwrap().get(name) {
+                keys.push(*key);
+            }
+        }
+        keys
+    }
+
+    // Find published files with a keyword starting with each word of the query.
+    // Words match from their start only, "holi" finds "holiday.jpg" but "iday" does not.
+    pub fn search(&mut self, query: &str) -> Vec<IndexEntry> {
+        let tokens = index::tokenize(query);
+        if tokens.is_empty() {
+            return self.lookup_index(index::name_key(query.trim()))
+                .into_iter()
+                .filter(|entry| entry.0 == query.trim())
+                .collect();
+        }
+
+        // An entry matching every keyword is stored under each of them
+        let mut results: Vec<IndexEntry> = Vec::new();
+        for token in &tokens {
+            for entry in self.lookup_index(index::lookup_key(token)) {
+                if index::matches(&entry, &tokens) && !results.contains(&entry) {
+                    results.push(entry);
+                }
+            }
+        }
+
+        results.sort();
+        results
+    }
+
+    // Ask the closest known nodes and any bootstrap not known yet for the
+    // nodes closest to us, returns how many answered. Fails only when there
+    // was someone to ask and nobody answered.
+    pub fn get_peer_record(&mut self) -> Result<usize, Box<dyn Error>> {
+        let mut comps  = self.find_k_closest_computers(&self.key);
+        let known: Vec<String> = self.known_nodes.lock().unwrap().values().cloned().collect();
+        for boot in &self.config.network.bootstraps {
+            if *boot != self.host && !known.contains(boot) && !comps.iter().any(|(_, address)| address == boot) {
+                comps.push((Key{key:0}, boot.clone()));
+            }
+        }
+        let mut answered = 0;
+        for (key, address) in comps.iter().cloned() {
+            let stream = match self.connect(&address) {
+                Ok(stream) => stream,
+                Err(e) => {
+                    log::warn!(peer = key.key, address = address.as_str(), msg_type = "PEERS_I"; "Could not reach bootstrap node: {}", e);
+                    continue;
+                },
+            };
+            let mut reader = BufReader::new(stream.try_clone()?);
 
             let peer_record: PeerRecord = (key, address.clone());
             let msg : Message  = Message::new(
                                             "PEERS_I".to_string(), 
-                                            (self.key.clone(), self.host.clone()), 
-                                            (key.clone(), address.clone()), 
+                                            (self.key, self.host.clone()), 
+                                            (key, address.clone()), 
                                             peer_record,
-                                            Key{key:0},
+                                            self.key,
                                             Data::create_empty(),
                                         );
 
             
             
             // SEND TO PEERS REQUESTING K_CLOSEST
-            let connection  = Connection::new(stream.try_clone().unwrap(), false, true);
+            let connection  = Connection::new(stream.try_clone()?, false, true);
             {
-                connection.sender.send(msg);
+                let _ = connection.sender.send(msg);
             }
             // Recieve K_Closest
 
-            let msg = Message::read_message(&mut reader).unwrap();
+            let msg = match self.read_reply(&mut reader, key) {
+                Ok(msg) => msg,
+                Err(_) => continue,
+            };
+            answered += 1;
+
+            // A bootstrap's key is the one it answers from
+            if key.key == 0 && msg.from.0.key != 0 && msg.from.0 != self.key && !self.is_banned(&msg.from.0) {
+                self.known_nodes.lock().unwrap().insert(msg.from.0, address.clone());
+            }
             
             for record in msg.keys {
                 if record.0 == self.key {continue;}
-                if self.known_nodes.lock().unwrap().contains_key(&record.0) == false {
-                    self.known_nodes.lock().unwrap().insert(record.0, record.1);    
+                if let std::collections::hash_map::Entry::Vacant(e) = self.known_nodes.lock().unwrap().entry(record.0) {
+                    e.insert(record.1);    
                 }
             }
             
-            stream.shutdown(std::net::Shutdown::Read);
+            let _ = stream.shutdown(std::net::Shutdown::Read);
         }
+
+        if answered == 0 && !comps.is_empty() {
+            return Err("Could not reach any bootstrap node".into());
+        }
+        Ok(answered)
     }
     
-    pub fn ping(&mut self, key: Key) -> Option<String> {
-        let address = self.known_nodes.lock().unwrap().get(&key)?.clone();
-        let stream = TcpStream::connect(address.clone()).unwrap();
-        let mut reader = BufReader::new(stream.try_clone().unwrap());
+    // Send one probe to a known node and wait for its echo
+    pub fn ping(&mut self, key: Key) -> Result<Duration, Box<dyn Error>> {
+        let address = self.known_nodes.lock().unwrap().get(&key).cloned().ok_or(format!("Node {} is not known", key.key))?;
+        let result = self.probe(&(key, address));
+
+        let mut node_stats = self.node_stats.lock().unwrap();
+        let stats = node_stats.entry(key).or_default();
+        match &result {
+            Ok(rtt) => stats.record_rtt(*rtt),
+            Err(_) => stats.record_failure(),
+        }
+        result
+    }
+
+    fn probe(&self, peer: &PeerRecord) -> Result<Duration, Box<dyn Error>> {
+        let (key, address) = peer.clone();
+        let socket_addr: std::net::SocketAddr = address.parse()?;
+        let stream = TcpStream::connect_timeout(&socket_addr, self.config.timeouts.ping())?;
+        stream.set_read_timeout(Some(self.config.timeouts.ping()))?;
+        let mut reader = BufReader::new(stream.try_clone()?);
 
+        let probe = Probe::new();
+        let sent = Instant::now();
         let msg : Message  = Message::new(
                                         "PING".to_string(), 
-                                        (self.key.clone(), self.host.clone()), 
-                                        (key.clone(), address.clone()), 
+                                        (self.key, self.host.clone()), 
+                                        (key, address),
                                         create_empty_peer_record(),
                                         Key{key:0},
-                                        Data::create_empty(),
+                                        probe.to_data(),
                                         );
 
-        
-        let connection  = Connection::new(stream.try_clone().unwrap(), false, true);
+        let connection  = Connection::new(stream.try_clone()?, false, true);
         {
-            connection.sender.send(msg);
+            let _ = connection.sender.send(msg);
+        }
+        let reply = self.read_reply(&mut reader, key)?;
+        let _ = stream.shutdown(std::net::Shutdown::Read);
+
+        match Probe::from_data(&reply.data.1) {
+            Some(echo) if echo == probe => Ok(sent.elapsed()),
+            _ => Err(format!("Bad echo from {}", key.key))?,
+        }
+    }
+
+    // Any reply shows the peer is alive, whatever it said
+    fn mark_alive(&self, key: Key) {
+        self.node_stats.lock().unwrap().entry(key).or_default().seen();
+    }
+
+    // Read the reply of the node with key. Any reply shows it is alive, a
+    // malformed one counts against it.
+    fn read_reply(&self, reader: &mut BufReader<TcpStream>, key: Key) -> Result<Message, &'static str> {
+        match Message::read_message(reader) {
+            Ok(reply) => {
+                self.mark_alive(key);
+                Ok(reply)
+            },
+            Err(MALFORMED) => {
+                log::warn!(peer = key.key; "Malformed reply");
+                self.penalize(key);
+                Err(MALFORMED)
+            },
+            Err(e) => Err(e),
         }
-        Message::read_message(&mut reader).unwrap();
+    }
+
+    // Connection for one request. Reads and writes give up once the peer has
+    // been silent for the request timeout, so neither a dead peer nor a
+    // cancelled call can hold a lookup forever.
+    fn connect(&self, address: &str) -> std::io::Result<TcpStream> {
+        let timeout = self.config.timeouts.request();
+        let socket_addr = address.to_socket_addrs()?.next()
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("No address for {}", address)))?;
+        let stream = TcpStream::connect_timeout(&socket_addr, timeout)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        Ok(stream)
+    }
 
-        return Some("Success".to_string());
+    // Reorder peers so responsive, low latency nodes are asked first
+    pub fn by_latency(&self, mut peers: Vec<PeerRecord>) -> Vec<PeerRecord> {
+        let node_stats = self.node_stats.lock().unwrap();
+        peers.sort_by_key(|peer| node_stats.get(&peer.0).copied().unwrap_or_default().rank());
+        peers
     }
     
     pub fn get_providers(&mut self) -> Option<DhtType> {
@@ -351,16 +1442,20 @@ impl Client {
         let comps  = self.find_k_closest_computers(&self.key);
         for (key, address) in comps {
             if key == self.key {continue;}
+            if self.cancelled() { break; }
 
-            let stream = TcpStream::connect(address.clone()).unwrap();
+            let stream = match self.connect(&address) {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
             let mut reader = BufReader::new(stream.try_clone().unwrap());
-            let peer_record: PeerRecord = (self.key.clone(), self.host.clone());
+            let peer_record: PeerRecord = (self.key, self.host.clone());
             let msg : Message  = Message::new(
                                             "PROVIDER_GET".to_string(), 
-                                            (self.key.clone(), self.host.clone()), 
-                                            (key.clone(), address.clone()), 
+                                            (self.key, self.host.clone()), 
+                                            (key, address.clone()), 
                                             peer_record,
-                                            self.key.clone(),
+                                            self.key,
                                             Data::create_empty(),
                                         );
 
@@ -369,38 +1464,46 @@ impl Client {
             // SEND TO PEERS REQUESTING K_CLOSEST
             let connection  = Connection::new(stream.try_clone().unwrap(), false, true);
             {
-                connection.sender.send(msg);
+                let _ = connection.sender.send(msg);
             }
             
-            let msg = Message::read_message(&mut reader).unwrap();
+            let msg = match self.read_reply(&mut reader, key) {
+                Ok(msg) => msg,
+                Err(_) => continue,
+            };
             
             for record in msg.providers {
-                if self.providers.lock().unwrap().contains_key(&record.0) == false {
-                    self.providers.lock().unwrap().insert(record.0, record.1);    
+                if let std::collections::hash_map::Entry::Vacant(e) = self.providers.lock().unwrap().entry(record.0) {
+                    e.insert(record.1);    
                 }
             }
 
-            stream.shutdown(std::net::Shutdown::Read);
+            let _ = stream.shutdown(std::net::Shutdown::Read);
         }
                   
-        return None;                               
+        None
     }
 
     pub fn find_k_closest_computers(&self, key : &Key) -> Vec<PeerRecord> {             
+        self.closest_nodes(key, self.config.dht.k)
+    }
+
+    // Known nodes ordered by XOR distance to key, nearest first
+    pub fn closest_nodes(&self, key : &Key, count: usize) -> Vec<PeerRecord> {
         let mut k_closest : Vec<PeerRecord> = Vec::new();
 
-        let mut pqueue_distance: PriorityQueue<PeerRecord, u32> = PriorityQueue::new();
+        let mut pqueue_distance: PriorityQueue<PeerRecord, Reverse<u32>> = PriorityQueue::new();
         for (curr_key, item) in self.known_nodes.lock().unwrap().iter() {
-            pqueue_distance.push((*curr_key, item.to_string()), key.distance(*curr_key));
+            pqueue_distance.push((*curr_key, item.to_string()), Reverse(key.distance(*curr_key)));
         }
 
-        for _ in 1..K {
+        for _ in 0..count {
             if pqueue_distance.is_empty() { break };
 
             let (peer_record, _) = pqueue_distance.pop().unwrap(); 
             k_closest.push(peer_record);
         }
-        return k_closest;
+        k_closest
     }
 }
 
@@ -410,3 +1513,230 @@ impl Client {
 
   
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn providers_keep_spaces_commas_and_brackets() {
+        let providers = vec![("my notes.txt".to_string(), Key {key: 1}), ("a,b (1).txt".to_string(), Key {key: u32::MAX})];
+        assert_eq!(parse_providers(&format_providers(&providers)).unwrap(), providers);
+        assert_eq!(parse_providers(&format_providers(&[])).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn parse_providers_drops_bad_entries() {
+        let parsed = parse_providers(r#" [["ok.txt", 7], ["no key"], [1, 2], ["negative", -1], "text", ["big", 4294967296]] "#).unwrap();
+        assert_eq!(parsed, vec![("ok.txt".to_string(), Key {key: 7})]);
+    }
+
+    #[test]
+    fn parse_providers_rejects_garbage() {
+        assert!(parse_providers("(my notes.txt,1)").is_err());
+        assert!(parse_providers("").is_err());
+    }
+
+    fn client() -> Box<Client> {
+        Client::with_config("127.0.0.1".to_string(), "0".to_string(), Config::default())
+    }
+
+    #[test]
+    fn leave_needs_known_address() {
+        let client = client();
+        let address = closed_address();
+        client.known_nodes.lock().unwrap().insert(Key {key: 5}, address.clone());
+        assert!(!client.leave_confirmed(&(Key {key: 5}, "127.0.0.1:1".to_string())));
+        assert!(!client.leave_confirmed(&(Key {key: 6}, address)));
+    }
+
+    #[test]
+    fn leave_accepted_once_node_is_gone() {
+        let client = client();
+        let address = closed_address();
+        client.known_nodes.lock().unwrap().insert(Key {key: 5}, address.clone());
+        assert!(client.leave_confirmed(&(Key {key: 5}, address)));
+    }
+
+    // An address nothing listens on any more
+    fn closed_address() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    }
+
+    #[test]
+    fn store_record_rejects_stale_seq() {
+        let client = client();
+        let signing_key = SigningKey::from_bytes(&[1; 32]);
+        assert!(client.store_record(MutableRecord::signed(&signing_key, "site", 2, Key {key: 10})));
+        assert!(!client.store_record(MutableRecord::signed(&signing_key, "site", 2, Key {key: 11})));
+        assert!(!client.store_record(MutableRecord::signed(&signing_key, "site", 1, Key {key: 12})));
+        assert!(client.store_record(MutableRecord::signed(&signing_key, "site", 3, Key {key: 13})));
+
+        let key = record::record_key(&record::public_key_hex(&signing_key), "site");
+        assert_eq!(client.records.lock().unwrap()[&key].value, 13);
+    }
+
+    #[test]
+    fn store_record_rejects_colliding_publisher() {
+        let client = client();
+        let owner = MutableRecord::signed(&SigningKey::from_bytes(&[1; 32]), "site", 1, Key {key: 10});
+        let attacker = MutableRecord::signed(&SigningKey::from_bytes(&[2; 32]), "site", 99, Key {key: 66});
+        assert!(attacker.verify());
+
+        // Pretend the attacker found a key colliding with the owner's record
+        client.records.lock().unwrap().insert(attacker.key(), owner.clone());
+        assert!(!client.store_record(attacker.clone()));
+        assert_eq!(client.records.lock().unwrap()[&attacker.key()], owner);
+    }
+
+    #[test]
+    fn fetch_record_ignores_colliding_local_record() {
+        let mut client = client();
+        let owner = MutableRecord::signed(&SigningKey::from_bytes(&[1; 32]), "site", 1, Key {key: 10});
+        let attacker = MutableRecord::signed(&SigningKey::from_bytes(&[2; 32]), "site", 99, Key {key: 66});
+        client.known_nodes.lock().unwrap().clear();
+        client.records.lock().unwrap().insert(owner.key(), attacker);
+
+        assert_eq!(client.resolve_record(&owner.public_key, "site"), None);
+    }
+
+    // A node holding one value, counting the requests it answers
+    fn fake_holder(key: Key, value: Data, requests: Arc<AtomicUsize>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let holder = (key, address.clone());
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let request = Message::read_message(&mut BufReader::new(stream.try_clone().unwrap())).unwrap();
+                requests.fetch_add(1, Ordering::SeqCst);
+                let reply = Message::new("PEERS_R_GET".to_string(), holder.clone(), request.from, create_empty_peer_record(), request.data.0, value.clone());
+                stream.write_all(reply.make_message().as_bytes()).unwrap();
+            }
+        });
+        address
+    }
+
+    #[test]
+    fn fetch_value_stops_at_first_verified_copy() {
+        let mut client = client();
+        let value = Data::new("notes.txt", b"the same bytes everywhere".to_vec());
+        let value_key = Key::generate_hash_from_data(&value.vec);
+        let requests = Arc::new(AtomicUsize::new(0));
+        client.known_nodes.lock().unwrap().clear();
+        for key in [Key {key: 100}, Key {key: 200}, Key {key: 300}] {
+            let address = fake_holder(key, value.clone(), requests.clone());
+            client.known_nodes.lock().unwrap().insert(key, address);
+            client.node_stats.lock().unwrap().entry(key).or_default().failures = 2;
+        }
+
+        let found = client.fetch_value(value_key, value_key, false).unwrap();
+        assert_eq!(found.vec, value.vec);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        // The node that answered is no longer counted as failing
+        let node_stats = client.node_stats.lock().unwrap();
+        assert_eq!(node_stats.values().filter(|stats| stats.failures == 0).count(), 1);
+    }
+
+    // A bootstrap answering PEERS_I from its own record with one other node it knows
+    fn fake_bootstrap(key: Key, neighbour: PeerRecord) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let bootstrap = (key, address.clone());
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let request = Message::read_message(&mut BufReader::new(stream.try_clone().unwrap())).unwrap();
+                let mut reply = Message::new("PEERS_R".to_string(), bootstrap.clone(), request.from, create_empty_peer_record(), Key {key: 0}, Data::create_empty());
+                reply.keys = vec![neighbour.clone()];
+                stream.write_all(reply.make_message().as_bytes()).unwrap();
+            }
+        });
+        address
+    }
+
+    #[test]
+    fn every_bootstrap_is_asked_and_learnt() {
+        let first = fake_bootstrap(Key {key: 100}, (Key {key: 101}, "127.0.0.1:101".to_string()));
+        let second = fake_bootstrap(Key {key: 200}, (Key {key: 201}, "127.0.0.1:201".to_string()));
+        let mut config = Config::default();
+        config.network.bootstraps = vec![first.clone(), second.clone()];
+        let mut client = Client::with_config("127.0.0.1".to_string(), "0".to_string(), config);
+        assert!(client.known_nodes.lock().unwrap().is_empty());
+
+        assert_eq!(client.get_peer_record().unwrap(), 2);
+        let known_nodes = client.known_nodes.lock().unwrap().clone();
+        assert_eq!(known_nodes.len(), 4);
+        assert_eq!(known_nodes[&Key {key: 100}], first);
+        assert_eq!(known_nodes[&Key {key: 200}], second);
+        assert_eq!(known_nodes[&Key {key: 101}], "127.0.0.1:101");
+        assert_eq!(known_nodes[&Key {key: 201}], "127.0.0.1:201");
+    }
+
+    #[test]
+    fn search_finds_long_prefixes() {
+        let mut client = client();
+        client.known_nodes.lock().unwrap().clear();
+        client.publish_name("internationalization_notes.txt", Key {key: 9});
+        client.publish_name("international_trade.txt", Key {key: 10});
+
+        let found = client.search("internationalizati");
+        assert_eq!(found, vec![("internationalization_notes.txt".to_string(), Key {key: 9})]);
+        assert_eq!(client.search("internationalization notes").len(), 1);
+        assert_eq!(client.search("international").len(), 2);
+        assert!(client.search("internationalisation").is_empty());
+    }
+
+    #[test]
+    fn chat_is_acknowledged_and_delivered_once() {
+        let recipient = client();
+        recipient.known_nodes.lock().unwrap().clear();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let (mut run_client, mut poll_client) = (recipient.clone(), recipient.clone());
+        thread::spawn(move || run_client.run(listener));
+        thread::spawn(move || poll_client.poll());
+
+        let mut sender = client();
+        sender.known_nodes.lock().unwrap().clear();
+        sender.known_nodes.lock().unwrap().insert(recipient.key, address.clone());
+        let message = sender.send_message(recipient.key, "hello").unwrap();
+        // A retry after a lost ack is acknowledged again but not delivered twice
+        assert!(sender.send_chat(&(recipient.key, address.clone()), &message));
+        assert_eq!(recipient.inbox.lock().unwrap().messages, vec![message]);
+
+        // A message for another key is refused
+        assert!(!sender.send_chat(&(Key {key: recipient.key.key ^ 1}, address), &ChatMessage::new(sender.key, "lost")));
+        assert_eq!(recipient.inbox.lock().unwrap().messages.len(), 1);
+
+        recipient.stopping.store(true, Ordering::SeqCst);
+        recipient.stopped.store(true, Ordering::SeqCst);
+    }
+
+    // A node answering every request with a message whose body is not JSON
+    fn garbling_peer() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let _ = Message::read_message(&mut BufReader::new(stream.try_clone().unwrap()));
+                let _ = stream.write_all(b"P2P/1.0 PEERS_R_GET\r\nFROM- (5,127.0.0.1:5)\r\nDATA_KEY- 7\r\n\r\n{not json\r\n");
+            }
+        });
+        address
+    }
+
+    #[test]
+    fn corrupted_replies_penalize_the_peer() {
+        let mut client = client();
+        let peer = Key {key: 0xbeef};
+        client.known_nodes.lock().unwrap().clear();
+        client.known_nodes.lock().unwrap().insert(peer, garbling_peer());
+
+        assert!(client.ping(peer).is_err());
+        assert_eq!(client.penalties.lock().unwrap().get(&peer), Some(&1));
+        assert!(client.fetch_value(Key {key: 7}, Key {key: 7}, false).is_err());
+        assert_eq!(client.penalties.lock().unwrap().get(&peer), Some(&2));
+    }
+}
@@ -0,0 +1,284 @@
+use std::error::Error;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use serde::Deserialize;
+
+use crate::logging::{self, LogFormat};
+use crate::throttle::Limits;
+
+/// Largest k accepted, lookups and stores contact up to k nodes each.
+pub const MAX_K: usize = 256;
+
+/// Node settings, read from a TOML file. Every section and key is optional:
+///
+/// ```toml
+/// [network]
+/// listen = "127.0.0.1:0"
+/// advertise = "127.0.0.1:4000"
+/// bootstraps = ["127.0.0.1:12345"]
+/// bootnode = false
+///
+/// [dht]
+/// k = 20
+/// alpha = 3
+///
+/// [timeouts]
+/// ping_ms = 2000
+/// request_secs = 10
+/// drain_secs = 10
+/// audit_secs = 300
+///
+/// [storage]
+/// data_dir = "/var/lib/peer_stream"
+/// max_bytes = 1073741824
+/// max_value_bytes = 0
+///
+/// [limits]
+/// upload = 0
+/// download = 0
+/// peer_upload = 0
+/// peer_download = 0
+///
+/// [logging]
+/// level = "info,peer_stream::client=debug"
+/// file = "/var/log/peer_stream.log"
+/// format = "json"
+/// ```
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub network: NetworkConfig,
+    pub dht: DhtConfig,
+    pub timeouts: TimeoutConfig,
+    pub storage: StorageConfig,
+    pub limits: Limits,
+    pub logging: LoggingConfig,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkConfig {
+    /// Address to bind, port 0 picks a free one.
+    pub listen: String,
+    /// Address other nodes are told to reach this one on, the bound address when unset.
+    pub advertise: Option<String>,
+    /// Nodes contacted to join the network.
+    pub bootstraps: Vec<String>,
+    /// Run as the well known node with key 1.
+    pub bootnode: bool,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> NetworkConfig {
+        NetworkConfig {
+            listen: "127.0.0.1:0".to_string(),
+            advertise: None,
+            bootstraps: vec!["127.0.0.1:12345".to_string()],
+            bootnode: false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct DhtConfig {
+    /// Bucket size and default replication.
+    pub k: usize,
+    /// Parallel queries per round of a node lookup.
+    pub alpha: usize,
+}
+
+impl Default for DhtConfig {
+    fn default() -> DhtConfig {
+        DhtConfig {k: 20, alpha: 3}
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeoutConfig {
+    /// Longest to wait for a ping echo.
+    pub ping_ms: u64,
+    /// Longest a peer may stay silent while answering a request.
+    pub request_secs: u64,
+    /// Longest a shutdown waits for replies still being written.
+    pub drain_secs: u64,
+    /// Seconds between replica audits, 0 to never audit.
+    pub audit_secs: u64,
+}
+
+impl Default for TimeoutConfig {
+    fn default() -> TimeoutConfig {
+        TimeoutConfig {ping_ms: 2000, request_secs: 10, drain_secs: 10, audit_secs: 300}
+    }
+}
+
+impl TimeoutConfig {
+    pub fn ping(&self) -> Duration {
+        Duration::from_millis(self.ping_ms)
+    }
+
+    pub fn request(&self) -> Duration {
+        Duration::from_secs(self.request_secs)
+    }
+
+    pub fn drain(&self) -> Duration {
+        Duration::from_secs(self.drain_secs)
+    }
+
+    pub fn audit(&self) -> Option<Duration> {
+        if self.audit_secs == 0 { None } else { Some(Duration::from_secs(self.audit_secs)) }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    /// Keeps the node key, signing key and ledgers between runs.
+    pub data_dir: Option<PathBuf>,
+    /// Bytes of values held for other nodes, 0 for unlimited.
+    pub max_bytes: u64,
+    /// Largest single value accepted from other nodes, 0 for unlimited.
+    pub max_value_bytes: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// `RUST_LOG` style filter, a level optionally followed by per module
+    /// levels such as `info,peer_stream::client=debug`.
+    pub level: String,
+    /// Append to this file instead of writing to stderr.
+    pub file: Option<PathBuf>,
+    pub format: LogFormat,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> LoggingConfig {
+        LoggingConfig {level: "info".to_string(), file: None, format: LogFormat::Text}
+    }
+}
+
+impl Config {
+    /// Read and validate a config file.
+    pub fn load(path: &Path) -> Result<Config, Box<dyn Error>> {
+        let text = fs::read_to_string(path).map_err(|e| format!("Could not read config {}: {}", path.display(), e))?;
+        let config: Config = toml::from_str(&text).map_err(|e| format!("Invalid config {}: {}", path.display(), e))?;
+        config.validate().map_err(|e| format!("Invalid config {}: {}", path.display(), e))?;
+        Ok(config)
+    }
+
+    /// Check the values make sense together, naming the first one that does not.
+    pub fn validate(&self) -> Result<(), String> {
+        self.network.listen.parse::<SocketAddr>()
+            .map_err(|_| format!("network.listen {:?} is not an ip:port address", self.network.listen))?;
+        if let Some(advertise) = &self.network.advertise {
+            let address = advertise.parse::<SocketAddr>()
+                .map_err(|_| format!("network.advertise {:?} is not an ip:port address", advertise))?;
+            if address.port() == 0 {
+                return Err("network.advertise needs an explicit port".to_string());
+            }
+        }
+        for bootstrap in &self.network.bootstraps {
+            bootstrap.parse::<SocketAddr>()
+                .map_err(|_| format!("network.bootstraps entry {:?} is not an ip:port address", bootstrap))?;
+        }
+
+        if self.dht.k == 0 || self.dht.k > MAX_K {
+            return Err(format!("dht.k must be between 1 and {}, got {}", MAX_K, self.dht.k));
+        }
+        if self.dht.alpha == 0 || self.dht.alpha > self.dht.k {
+            return Err(format!("dht.alpha must be between 1 and k ({}), got {}", self.dht.k, self.dht.alpha));
+        }
+
+        if self.timeouts.ping_ms == 0 {
+            return Err("timeouts.ping_ms must be above 0".to_string());
+        }
+        if self.timeouts.request_secs == 0 {
+            return Err("timeouts.request_secs must be above 0".to_string());
+        }
+        if self.storage.max_bytes > 0 && self.storage.max_value_bytes > self.storage.max_bytes {
+            return Err(format!("storage.max_value_bytes ({}) is above storage.max_bytes ({})", self.storage.max_value_bytes, self.storage.max_bytes));
+        }
+
+        logging::validate_spec(&self.logging.level).map_err(|e| format!("logging.level: {}", e))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Apply change to the defaults and return the validation error
+    fn invalid(change: impl FnOnce(&mut Config)) -> String {
+        let mut config = Config::default();
+        change(&mut config);
+        config.validate().unwrap_err()
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        assert_eq!(Config::default().validate(), Ok(()));
+    }
+
+    #[test]
+    fn every_section_parses() {
+        let config: Config = toml::from_str(r#"
+            [network]
+            listen = "127.0.0.1:4000"
+            advertise = "10.0.0.1:4000"
+            bootstraps = []
+            [dht]
+            k = 8
+            alpha = 2
+            [timeouts]
+            ping_ms = 500
+            request_secs = 3
+            [storage]
+            max_bytes = 1024
+            max_value_bytes = 512
+            [logging]
+            level = "warn,peer_stream::client=debug"
+        "#).unwrap();
+        assert_eq!(config.validate(), Ok(()));
+        assert_eq!(config.dht, DhtConfig {k: 8, alpha: 2});
+        assert_eq!(config.timeouts.request(), Duration::from_secs(3));
+        // Keys left out keep their defaults
+        assert_eq!(config.timeouts.drain_secs, TimeoutConfig::default().drain_secs);
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        assert!(toml::from_str::<Config>("[dht]\nkk = 3\n").is_err());
+        assert!(toml::from_str::<Config>("[dhtt]\n").is_err());
+    }
+
+    #[test]
+    fn invalid_values_name_the_key() {
+        assert!(invalid(|config| config.network.listen = "localhost".to_string()).starts_with("network.listen"));
+        assert!(invalid(|config| config.network.advertise = Some("10.0.0.1:0".to_string())).starts_with("network.advertise"));
+        assert!(invalid(|config| config.network.bootstraps = vec!["nowhere".to_string()]).starts_with("network.bootstraps"));
+        assert!(invalid(|config| config.dht.k = 0).starts_with("dht.k"));
+        assert!(invalid(|config| config.dht.k = MAX_K + 1).starts_with("dht.k"));
+        assert!(invalid(|config| config.dht.alpha = config.dht.k + 1).starts_with("dht.alpha"));
+        assert!(invalid(|config| config.timeouts.ping_ms = 0).starts_with("timeouts.ping_ms"));
+        assert!(invalid(|config| config.timeouts.request_secs = 0).starts_with("timeouts.request_secs"));
+        assert!(invalid(|config| {
+            config.storage.max_bytes = 10;
+            config.storage.max_value_bytes = 11;
+        }).starts_with("storage.max_value_bytes"));
+        assert!(invalid(|config| config.logging.level = "info!".to_string()).starts_with("logging.level"));
+    }
+
+    #[test]
+    fn load_names_the_file() {
+        let path = std::env::temp_dir().join(format!("peer_stream_config_{}.toml", std::process::id()));
+        fs::write(&path, "[dht]\nk = 0\n").unwrap();
+        let e = Config::load(&path).unwrap_err().to_string();
+        fs::remove_file(&path).unwrap();
+        assert!(e.contains(&path.display().to_string()) && e.contains("dht.k"), "{}", e);
+    }
+}
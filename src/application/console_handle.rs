@@ -1,83 +1,474 @@
-use std::io::{self, Write};
 use std::error::Error;
-use std::fs::File;
-use std::io::BufReader;
-use std::io::Read;
-use std::fs::OpenOptions;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use rustyline::completion::{Completer, FilenameCompleter, Pair};
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, ExternalPrinter, Helper};
 
-use crate::Client;
-use crate::key::Key;
-use crate::data::Data;
-use crate::data::FileMetadata;
+use peer_stream::Client;
+use peer_stream::key::Key;
+use peer_stream::data::Data;
+use peer_stream::{latency, logging, manifest, tree};
+use peer_stream::throttle::throttle;
+
+// Probes sent by PING when no count is given
+const PING_PROBES: usize = 4;
+const HISTORY_FILE: &str = ".peer_stream_history";
+
+// Name, arguments and description of every console command
+const COMMANDS: [(&str, &str, &str); 22] = [
+    ("HELP", "", "List the commands"),
+    ("LIST", "", "Print known nodes, stored data, ledger, records and topics"),
+    ("PTEST", "", "Ask the closest nodes for peers again"),
+    ("PING", "<key> [count]", "Measure the round trip time to a node"),
+    ("INSERT", "<name> <text>", "Store text under name"),
+    ("UPLOAD", "<path> [replicas|data+parity]", "Store a file or directory"),
+    ("GET", "<key|key:key|name|record:pubkey/name> <path>", "Download a file or directory to path"),
+    ("PROVIDERS", "", "Fetch the provider records of nearby nodes"),
+    ("SEARCH", "<words>", "Find files with words starting with each of these"),
+    ("AUDIT", "", "Check and repair the replicas of stored keys"),
+    ("RATES", "", "Show transfer rates and limits"),
+    ("LIMIT", "<up|down|peer-up|peer-down> <bytes/s>", "Change a rate limit, 0 for unlimited"),
+    ("RECORD", "<name> <key>", "Publish a signed record pointing name at key"),
+    ("RESOLVE", "<pubkey> <name>", "Look up the newest record"),
+    ("SUBSCRIBE", "<topic>", "Print messages published to topic, uploads announces new content"),
+    ("UNSUBSCRIBE", "<topic>", "Stop following topic"),
+    ("PUBLISH", "<topic> <text>", "Gossip text to the subscribers of topic"),
+    ("SEND", "<key> <text>", "Send a direct message to a node"),
+    ("INBOX", "", "Show received direct messages"),
+    ("LOG", "[filter]", "Show or change the log filter, e.g. debug or info,peer_stream::client=trace"),
+    ("QUIT", "", "Leave the network and exit"),
+    ("EXIT", "", "Same as QUIT"),
+];
+
+// Prints above the prompt, shared with the threads and handlers that print asynchronously
+type Printer = Option<Arc<Mutex<Box<dyn ExternalPrinter + Send>>>>;
+
+fn print_above(printer: &Printer, text: String) {
+    match printer {
+        Some(printer) => { let _ = printer.lock().unwrap().print(text); },
+        None => println!("{}", text),
+    }
+}
+
+fn usage(cmd: &str) -> String {
+    match COMMANDS.iter().find(|(name, _, _)| *name == cmd) {
+        Some((name, arguments, _)) => format!("Usage: {} {}", name, arguments),
+        None => format!("Unknown command {}", cmd),
+    }
+}
+
+// Completes command names, then node and data keys or local paths depending on the command
+struct ConsoleHelper {
+    client: Client,
+    files: FilenameCompleter,
+}
+
+impl ConsoleHelper {
+    fn keys(&self) -> Vec<String> {
+        let mut keys: Vec<u32> = self.client.known_nodes.lock().unwrap().keys().map(|key| key.key).collect();
+        keys.extend(self.client.local_hash.lock().unwrap().keys().map(|key| key.key));
+        keys.extend(self.client.providers.lock().unwrap().values().map(|key| key.key));
+        keys.sort();
+        keys.dedup();
+        keys.iter().map(|key| key.to_string()).collect()
+    }
+}
+
+impl Completer for ConsoleHelper {
+    type Candidate = Pair;
+
+    fn complete(&self, line: &str, pos: usize, ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let start = line[..pos].rfind(char::is_whitespace).map(|i| i + 1).unwrap_or(0);
+        let word = &line[start..pos];
+        let previous: Vec<&str> = line[..start].split_whitespace().collect();
+        let pair = |candidate: &str| Pair {display: candidate.to_string(), replacement: candidate.to_string()};
+
+        let cmd = match previous.first() {
+            Some(cmd) => cmd.to_uppercase(),
+            None => {
+                let candidates = COMMANDS.iter()
+                    .filter(|(name, _, _)| name.starts_with(&word.to_uppercase()))
+                    .map(|(name, _, _)| pair(name))
+                    .collect();
+                return Ok((start, candidates));
+            },
+        };
+
+        match (cmd.as_str(), previous.len()) {
+            ("UPLOAD", 1) | ("GET", 2) => self.files.complete(line, pos, ctx),
+            ("PING", 1) | ("GET", 1) | ("SEND", 1) | ("RECORD", 2) => {
+                let candidates = self.keys().iter().filter(|key| key.starts_with(word)).map(|key| pair(key)).collect();
+                Ok((start, candidates))
+            },
+            _ => Ok((start, Vec::new())),
+        }
+    }
+}
+
+impl Hinter for ConsoleHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ConsoleHelper {}
+
+impl Validator for ConsoleHelper {}
+
+impl Helper for ConsoleHelper {}
+
+// History is kept in the data directory when there is one, in the home directory otherwise
+fn history_path(data_dir: Option<&Path>) -> Option<PathBuf> {
+    match data_dir {
+        Some(data_dir) => Some(data_dir.join("history")),
+        None => std::env::var_os("HOME").map(|home| PathBuf::from(home).join(HISTORY_FILE)),
+    }
+}
+
+pub fn console(mut client : Box<Client>, data_dir: Option<PathBuf>) {
+    let mut editor: Editor<ConsoleHelper, DefaultHistory> = match Editor::new() {
+        Ok(editor) => editor,
+        Err(e) => {
+            log::error!("Could not start the console: {}", e);
+            return;
+        },
+    };
+    editor.set_helper(Some(ConsoleHelper {client: (*client).clone(), files: FilenameCompleter::new()}));
+
+    let history = history_path(data_dir.as_deref());
+    if let Some(history) = &history {
+        // A missing file just means a first run
+        let _ = editor.load_history(history);
+    }
+
+    // Print incoming messages above the prompt as they arrive
+    let printer: Printer = editor.create_external_printer().ok()
+        .map(|printer| Arc::new(Mutex::new(Box::new(printer) as Box<dyn ExternalPrinter + Send>)));
+    let inbox = client.inbox.lock().unwrap().listen();
+    let inbox_printer = printer.clone();
+    thread::spawn(move || {
+        for message in inbox {
+            print_above(&inbox_printer, format!("[{}] {}", message.from, message.text));
+        }
+    });
 
-pub fn console(client : Box<Client>) {
     loop {
-        let mut line = String::new();
-        print!(">: ");
-        io::stdout().flush();
+        let line = match editor.readline(">: ") {
+            Ok(line) => line,
+            // The terminal is in raw mode, so Ctrl-C arrives here instead of as a signal
+            Err(ReadlineError::Interrupted) => {
+                if let Err(e) = client.shutdown() {
+                    println!("Error: {}", e);
+                }
+                break;
+            },
+            Err(ReadlineError::Eof) => break,
+            Err(e) => {
+                log::error!("Console stopped: {}", e);
+                break;
+            },
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let _ = editor.add_history_entry(line.as_str());
+        if let Some(history) = &history {
+            if let Err(e) = editor.save_history(history) {
+                log::warn!("Could not save console history: {}", e);
+            }
+        }
 
-        io::stdin().read_line(&mut line).unwrap();
-        
-        handle_input_line(client.clone(), line.clone());
+        let cmd = line.split_whitespace().next().unwrap_or("").to_uppercase();
+        if cmd == "QUIT" || cmd == "EXIT" {
+            if let Err(e) = client.shutdown() {
+                println!("Error: {}", e);
+            }
+            break;
+        }
+        if let Err(e) = handle_input_line(&mut client, &printer, &line) {
+            println!("Error: {}", e);
+        }
     }
 }
 
 
 
+// key:<key> is always a key and record:<public key>/<name> follows a record. A bare number
+// is a key unless nothing is stored under it, anything else is resolved as a filename.
+fn find_target(client: &mut Client, target: &str) -> Result<(Key, Data), Box<dyn Error>> {
+    if let Some(key) = target.strip_prefix("key:") {
+        let find_key = Key {key: key.parse().map_err(|_| format!("{} is not a key", key))?};
+        return Ok((find_key, client.get_data(find_key)?));
+    }
+    if let Some(record) = target.strip_prefix("record:") {
+        let (public_key, name) = record.split_once('/').ok_or_else(|| usage("GET"))?;
+        let found = client.resolve_record(public_key, name).ok_or(format!("No record {}", record))?;
+        let find_key = Key {key: found.value};
+        return Ok((find_key, client.get_data(find_key)?));
+    }
+    let find_key = match target.parse::<u32>() {
+        Ok(parse_key) => match client.get_data(Key {key: parse_key}) {
+            Ok(data) => return Ok((Key {key: parse_key}, data)),
+            // Files may be named like numbers too
+            Err(e) => match resolve_file(client, target) {
+                Ok(find_key) => find_key,
+                Err(_) => return Err(e),
+            },
+        },
+        Err(_) => resolve_file(client, target)?,
+    };
+    Ok((find_key, client.get_data(find_key)?))
+}
+
+fn resolve_file(client: &mut Client, name: &str) -> Result<Key, Box<dyn Error>> {
+    let keys = client.resolve_name(name);
+    match keys.len() {
+        0 => Err(format!("No file named {}", name).into()),
+        1 => Ok(keys[0]),
+        _ => {
+            println!("Multiple versions of {}:", name);
+            for version in keys {
+                println!("\tkey:{}", version.key);
+            }
+            Err("Ambiguous name, GET one of the keys above".into())
+        }
+    }
+}
 
-fn handle_input_line(mut client: Box<Client>, line: String) -> Result<(), Box<dyn Error>>  {
-    let mut args = line.split(' ');
-    let cmd = args.next().unwrap();
-    let cmd = cmd.trim();      
-    println!("CMD: {}", cmd);
-    match cmd {
+fn handle_input_line(client: &mut Client, printer: &Printer, line: &str) -> Result<(), Box<dyn Error>>  {
+    let mut args = line.split_whitespace();
+    let cmd = match args.next() {
+        Some(cmd) => cmd.to_uppercase(),
+        None => return Ok(()),
+    };
+    match cmd.as_str() {
+        "HELP" => {
+            for (name, arguments, description) in COMMANDS {
+                println!("\t{:<48} {}", format!("{} {}", name, arguments), description);
+            }
+        },
         "PTEST" => {
-            client.get_peer_record();
+            println!("{} nodes answered", client.get_peer_record()?);
         },
         "PING" => {
-            let key = args.next().unwrap().trim();
-            let parse_key: u32 = key.parse::<u32>().expect(key);
-            client.ping(Key {key: parse_key});
+            let parse_key: u32 = args.next().ok_or_else(|| usage("PING"))?.parse().map_err(|_| usage("PING"))?;
+            let count: usize = match args.next().map(|arg| arg.trim()).filter(|arg| !arg.is_empty()) {
+                Some(count) => count.parse().map_err(|_| usage("PING"))?,
+                None => PING_PROBES,
+            };
+            if !client.known_nodes.lock().unwrap().contains_key(&Key {key: parse_key}) {
+                return Err(format!("Node {} is not known", parse_key).into());
+            }
+
+            let mut rtts = Vec::new();
+            for seq in 0..count {
+                match client.ping(Key {key: parse_key}) {
+                    Ok(rtt) => {
+                        println!("\treply from {} seq {} time {:.2}ms", parse_key, seq, rtt.as_secs_f64() * 1000.0);
+                        rtts.push(rtt);
+                    },
+                    Err(e) => println!("\tno reply from {} seq {}: {}", parse_key, seq, e),
+                }
+                if seq + 1 < count {
+                    thread::sleep(Duration::from_millis(200));
+                }
+            }
+
+            let ms = |rtt: Duration| rtt.as_secs_f64() * 1000.0;
+            println!("{} probes, {} lost", count, count - rtts.len());
+            if let Some((min, avg, max)) = latency::summarize(&rtts) {
+                println!("rtt min/avg/max = {:.2}/{:.2}/{:.2} ms", ms(min), ms(avg), ms(max));
+            }
         },
         "INSERT" => {
-            let name = args.next().unwrap().trim();
-            let data = args.next().unwrap().trim();
+            let name = args.next().ok_or_else(|| usage("INSERT"))?;
+            let data = args.collect::<Vec<&str>>().join(" ");
+            if data.is_empty() {
+                return Err(usage("INSERT").into());
+            }
 
-            let meta = FileMetadata {filename: name.to_string()};
-            let insert_data: Data = Data {id: 1, vec: data.to_string().into_bytes(), file_meta: meta};
+            let insert_data: Data = Data::new(name, data.to_string().into_bytes());
             client.put_data(name.to_string(), insert_data);
         },
         "GET" => {
-            let key = args.next().unwrap().trim();
-            let save_name = args.next().unwrap().trim();
+            let key = args.next().ok_or_else(|| usage("GET"))?;
+            let save_name = args.next().ok_or_else(|| usage("GET"))?;
 
-            let parse_key: u32 = key.parse::<u32>().expect(key);
+            let (find_key, data) = find_target(client, key)?;
 
-            let data = client.get_data(Key {key: parse_key}).unwrap();
+            tree::download_path(client, find_key, &data, Path::new(save_name))?;
 
-            let mut file = File::create(save_name)?;
-            file.write_all(&data.vec);
-            
-            println!("{:?}", data);
+            if manifest::is_manifest(&data) {
+                println!("{}", manifest::parse_manifest(&data)?.file_meta);
+            } else {
+                println!("{}", data.file_meta);
+            }
         }, "LIST" => {
             client.print_state();
         }, "PROVIDERS" => {
             client.get_providers();
+        }, "SEARCH" => {
+            let query = args.collect::<Vec<&str>>().join(" ");
+            let results = client.search(query.trim());
+            if results.is_empty() {
+                println!("No matches for {}", query.trim());
+            }
+            for (name, key) in results {
+                println!("\t{} {}", name, key.key);
+            }
+        }, "RATES" => {
+            let report = throttle().report();
+            let limit = |rate: u64| if rate == 0 { "unlimited".to_string() } else { format!("{} B/s", rate) };
+            println!("UPLOAD {} B/s (limit {}, per peer {})", report.upload, limit(report.limits.upload), limit(report.limits.peer_upload));
+            println!("DOWNLOAD {} B/s (limit {}, per peer {})", report.download, limit(report.limits.download), limit(report.limits.peer_download));
+            for (peer, up, down, sent, received) in report.peers {
+                println!("\t{} up {} B/s down {} B/s sent {} received {}", peer, up, down, sent, received);
+            }
+        }, "LIMIT" => {
+            let kind = args.next().ok_or_else(|| usage("LIMIT"))?;
+            let rate: u64 = args.next().ok_or_else(|| usage("LIMIT"))?.parse().map_err(|_| usage("LIMIT"))?;
+
+            let mut limits = throttle().limits();
+            match kind {
+                "up" => limits.upload = rate,
+                "down" => limits.download = rate,
+                "peer-up" => limits.peer_upload = rate,
+                "peer-down" => limits.peer_download = rate,
+                _ => return Err(format!("Unknown limit {}", kind).into()),
+            }
+            throttle().set_limits(limits);
+        }, "RECORD" => {
+            let name = args.next().ok_or_else(|| usage("RECORD"))?;
+            let value: u32 = args.next().ok_or_else(|| usage("RECORD"))?.parse().map_err(|_| usage("RECORD"))?;
+
+            let published = client.publish_record(name, Key {key: value});
+            println!("{}/{} seq {} -> {}", published.public_key, published.name, published.seq, published.value);
+        }, "RESOLVE" => {
+            let public_key = args.next().ok_or_else(|| usage("RESOLVE"))?;
+            let name = args.next().ok_or_else(|| usage("RESOLVE"))?;
+
+            let found = client.resolve_record(public_key, name).ok_or(format!("No record {}/{}", public_key, name))?;
+            println!("{}/{} seq {} -> {}", found.public_key, found.name, found.seq, found.value);
+        }, "SUBSCRIBE" => {
+            let topic = args.next().ok_or_else(|| usage("SUBSCRIBE"))?;
+            let printer = printer.clone();
+            client.subscribe(topic, move |gossip| {
+                print_above(&printer, format!("[{}] {}: {}", gossip.topic, gossip.origin, gossip.payload));
+            })?;
+        }, "UNSUBSCRIBE" => {
+            let topic = args.next().ok_or_else(|| usage("UNSUBSCRIBE"))?;
+            client.unsubscribe(topic)?;
+        }, "PUBLISH" => {
+            let topic = args.next().ok_or_else(|| usage("PUBLISH"))?;
+            let text = args.collect::<Vec<&str>>().join(" ");
+            let sent = client.publish(topic, text.trim());
+            println!("Published to {} peers", sent);
+        }, "SEND" => {
+            let key: u32 = args.next().ok_or_else(|| usage("SEND"))?.parse().map_err(|_| usage("SEND"))?;
+            let text = args.collect::<Vec<&str>>().join(" ");
+            if text.trim().is_empty() {
+                return Err(usage("SEND").into());
+            }
+            client.send_message(Key {key}, text.trim())?;
+            println!("Delivered to {}", key);
+        }, "INBOX" => {
+            for message in client.inbox.lock().unwrap().messages.iter() {
+                println!("\t[{}] {} {}", message.from, message.sent_at, message.text);
+            }
+        }, "LOG" => {
+            let spec = args.collect::<Vec<&str>>().join("");
+            if spec.is_empty() {
+                println!("{}", logging::filter().unwrap_or("none".to_string()));
+            } else {
+                logging::set_filter(&spec)?;
+                println!("Log filter {}", spec);
+            }
+        }, "AUDIT" => {
+            for (key, live, repaired) in client.audit_replicas() {
+                println!("\t{} replicas {} repaired {}", key.key, live, repaired);
+            }
         }, "UPLOAD" => {
-            let filename = args.next().unwrap().trim();
-            
-            let file = File::open(filename)?;
-            let mut reader = BufReader::new(file);
-            let mut buffer = Vec::new();
-            reader.read_to_end(&mut buffer)?;
-
-            let meta = FileMetadata {filename: filename.to_string()};
-            let insert_data: Data = Data {id: 1, vec: buffer, file_meta: meta};
-            client.put_data(filename.to_string(), insert_data);
-        },
-        _ => {
+            let filename = args.next().ok_or_else(|| usage("UPLOAD"))?;
+            // Either a replica count or <data>+<parity> shards for erasure coding
+            let mut replicas = client.replication();
+            let mut erasure: Option<(usize, usize)> = None;
+            if let Some(arg) = args.next().map(|arg| arg.trim()).filter(|arg| !arg.is_empty()) {
+                match arg.split_once('+') {
+                    Some((data_shards, parity_shards)) => erasure = Some((
+                        data_shards.parse().map_err(|_| usage("UPLOAD"))?,
+                        parity_shards.parse().map_err(|_| usage("UPLOAD"))?,
+                    )),
+                    None => replicas = arg.parse::<usize>().map_err(|_| usage("UPLOAD"))?,
+                }
+            }
+
+            let key = tree::upload_path(client, Path::new(filename), replicas, erasure)?;
+            println!("{} {}", filename, key.key);
         },
+        _ => return Err(format!("Unknown command {}, type HELP for a list", cmd).into()),
     }
-    return Ok(());
+    Ok(())
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use peer_stream::Config;
+
+    fn client() -> Box<Client> {
+        let client = Client::with_config("127.0.0.1".to_string(), "0".to_string(), Config::default());
+        client.known_nodes.lock().unwrap().clear();
+        client
+    }
+
+    fn stored(client: &Client, name: &str, text: &str) -> Key {
+        let data = Data::new(name, text.as_bytes().to_vec());
+        let key = Key::generate_hash_from_data(&data.vec);
+        client.local_hash.lock().unwrap().insert(key, data);
+        key
+    }
+
+    #[test]
+    fn numeric_names_fall_back_to_the_filename() {
+        let mut client = client();
+        let key = stored(&client, "2024", "report");
+        client.providers.lock().unwrap().insert("2024".to_string(), key);
+
+        let (found, data) = find_target(&mut client, "2024").unwrap();
+        assert_eq!(found, key);
+        assert_eq!(data.vec, b"report");
+    }
+
+    #[test]
+    fn key_prefix_never_resolves_names() {
+        let mut client = client();
+        let key = stored(&client, "notes.txt", "notes");
+        client.providers.lock().unwrap().insert("7".to_string(), key);
+
+        assert_eq!(find_target(&mut client, &format!("key:{}", key.key)).unwrap().0, key);
+        assert!(find_target(&mut client, "key:7").is_err());
+        assert!(find_target(&mut client, "key:notes.txt").is_err());
+    }
+
+    #[test]
+    fn names_with_slashes_are_files_unless_prefixed_with_record() {
+        let mut client = client();
+        let file = stored(&client, "docs/notes.txt", "file");
+        client.providers.lock().unwrap().insert("docs/notes.txt".to_string(), file);
+        let pointed = stored(&client, "other.txt", "record");
+        let public_key = client.publish_record("notes.txt", pointed).public_key;
+
+        assert_eq!(find_target(&mut client, "docs/notes.txt").unwrap().0, file);
+        assert!(find_target(&mut client, &format!("{}/notes.txt", public_key)).is_err());
+        let (found, data) = find_target(&mut client, &format!("record:{}/notes.txt", public_key)).unwrap();
+        assert_eq!(found, pointed);
+        assert_eq!(data.vec, b"record");
+        assert!(find_target(&mut client, "record:notes.txt").is_err());
+    }
+}
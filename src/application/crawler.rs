@@ -0,0 +1,323 @@
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::io::{BufReader, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use serde_json::{json, Value};
+
+use crate::Client;
+use crate::client::{create_empty_peer_record, PeerRecord};
+use crate::connection::Message;
+use crate::data::Data;
+use crate::key::Key;
+use crate::ledger::remote_ip;
+use crate::metrics::metrics;
+use crate::throttle::throttle;
+
+// Keys are 32 bits, so every node has 32 buckets
+pub const BUCKETS: usize = 32;
+// Nodes queried at the same time
+const PARALLEL: usize = 16;
+// Tries per query before a node counts as not answering it
+const ATTEMPTS: usize = 2;
+
+// What one node said about the overlay
+pub struct CrawledNode {
+    pub address: String,
+    pub neighbours: BTreeSet<u32>,
+    // None until queried, then whether any query was answered in the last round
+    pub reachable: Option<bool>,
+}
+
+// The graph of which node knows which, grown over one or more rounds
+#[derive(Default)]
+pub struct Crawl {
+    pub nodes: BTreeMap<u32, CrawledNode>,
+    pub rounds: usize,
+}
+
+// Bucket index of other in the table of node, the length of their common prefix counted from the top bit
+pub fn bucket(node: u32, other: u32) -> Option<usize> {
+    let distance = node ^ other;
+    if distance == 0 { None } else { Some(distance.leading_zeros() as usize) }
+}
+
+impl Crawl {
+    // Ask every node found so far, and every node they name, for the nodes closest to
+    // a key in each of its buckets. Nodes that answer nothing are marked unreachable.
+    pub fn round(&mut self, client: &Client) {
+        let mut queue: VecDeque<PeerRecord> = VecDeque::new();
+        queue.push_back((client.key, client.host.clone()));
+        queue.extend(self.nodes.iter().map(|(key, node)| (Key {key: *key}, node.address.clone())));
+        let mut visited: BTreeSet<u32> = BTreeSet::new();
+
+        while !queue.is_empty() {
+            let mut batch = Vec::new();
+            while batch.len() < PARALLEL {
+                match queue.pop_front() {
+                    Some(peer) if visited.insert(peer.0.key) => batch.push(peer),
+                    Some(_) => continue,
+                    None => break,
+                }
+            }
+
+            let answers: Vec<(PeerRecord, Option<BTreeSet<PeerRecord>>)> = thread::scope(|scope| {
+                let handles: Vec<_> = batch.into_iter()
+                    .map(|peer| scope.spawn(move || {
+                        let answer = query_buckets(client, &peer);
+                        (peer, answer)
+                    }))
+                    .collect();
+                handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+            });
+
+            for ((key, address), answer) in answers {
+                let node = self.nodes.entry(key.key).or_insert_with(|| CrawledNode {address: address.clone(), neighbours: BTreeSet::new(), reachable: None});
+                node.reachable = Some(answer.is_some());
+                let Some(peers) = answer else {
+                    log::info!(peer = key.key, address = address.as_str(); "Crawl found node unreachable");
+                    continue;
+                };
+                for peer in peers {
+                    if peer.0 == key {continue;}
+                    node.neighbours.insert(peer.0.key);
+                    if !visited.contains(&peer.0.key) {
+                        queue.push_back(peer);
+                    }
+                }
+            }
+            // Nodes only named by others still need an entry
+            for (key, address) in &queue {
+                self.nodes.entry(key.key).or_insert_with(|| CrawledNode {address: address.clone(), neighbours: BTreeSet::new(), reachable: None});
+            }
+        }
+        self.rounds += 1;
+    }
+
+    pub fn edges(&self) -> usize {
+        self.nodes.values().map(|node| node.neighbours.len()).sum()
+    }
+
+    pub fn unreachable(&self) -> Vec<u32> {
+        self.nodes.iter().filter(|(_, node)| node.reachable != Some(true)).map(|(key, _)| *key).collect()
+    }
+
+    // Entries per bucket in the table of key, as far as its answers revealed it
+    pub fn bucket_fill(&self, key: u32) -> [usize; BUCKETS] {
+        let mut fill = [0; BUCKETS];
+        if let Some(node) = self.nodes.get(&key) {
+            for neighbour in &node.neighbours {
+                if let Some(bucket) = bucket(key, *neighbour) {
+                    fill[bucket] += 1;
+                }
+            }
+        }
+        fill
+    }
+
+    // Per bucket fill across the reachable nodes, counting those holding k entries as full
+    pub fn bucket_stats(&self, k: usize) -> Vec<Value> {
+        let fills: Vec<[usize; BUCKETS]> = self.nodes.iter()
+            .filter(|(_, node)| node.reachable == Some(true))
+            .map(|(key, _)| self.bucket_fill(*key))
+            .collect();
+
+        (0..BUCKETS).filter_map(|bucket| {
+            let counts: Vec<usize> = fills.iter().map(|fill| fill[bucket]).collect();
+            let total: usize = counts.iter().sum();
+            if total == 0 {
+                return None;
+            }
+            Some(json!({
+                "bucket": bucket,
+                "nodes_with_entries": counts.iter().filter(|count| **count > 0).count(),
+                "mean": total as f64 / counts.len() as f64,
+                "min": counts.iter().min(),
+                "max": counts.iter().max(),
+                "full": counts.iter().filter(|count| **count >= k).count(),
+            }))
+        }).collect()
+    }
+
+    pub fn to_json(&self, k: usize) -> Value {
+        let nodes: Vec<Value> = self.nodes.iter().map(|(key, node)| json!({
+            "key": key,
+            "address": node.address,
+            "reachable": node.reachable == Some(true),
+            "queried": node.reachable.is_some(),
+            "neighbours": node.neighbours,
+            "buckets": self.bucket_fill(*key),
+        })).collect();
+        let edges: Vec<[u32; 2]> = self.nodes.iter()
+            .flat_map(|(key, node)| node.neighbours.iter().map(move |neighbour| [*key, *neighbour]))
+            .collect();
+        json!({
+            "rounds": self.rounds,
+            "nodes": nodes,
+            "edges": edges,
+            "unreachable": self.unreachable(),
+            "buckets": self.bucket_stats(k),
+        })
+    }
+
+    // Graphviz digraph, an edge a -> b when a named b, unreachable nodes dashed red
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph overlay {\n    node [shape=box];\n");
+        for (key, node) in &self.nodes {
+            let style = match node.reachable {
+                Some(true) => "",
+                Some(false) => ", style=dashed, color=red",
+                None => ", style=dotted",
+            };
+            dot += &format!("    \"{}\" [label=\"{}\\n{}\"{}];\n", key, key, node.address, style);
+        }
+        for (key, node) in &self.nodes {
+            for neighbour in &node.neighbours {
+                dot += &format!("    \"{}\" -> \"{}\";\n", key, neighbour);
+            }
+        }
+        dot += "}\n";
+        dot
+    }
+}
+
+// PEERS_I for a key in every bucket of peer over one connection, None when none was answered
+fn query_buckets(client: &Client, peer: &PeerRecord) -> Option<BTreeSet<PeerRecord>> {
+    let mut found = BTreeSet::new();
+    let mut answered = false;
+    let mut connection = None;
+    for bucket in 0..BUCKETS {
+        let target = Key {key: peer.0.key ^ (1 << (BUCKETS - 1 - bucket))};
+        let mut closest = None;
+        for _ in 0..ATTEMPTS {
+            if connection.is_none() {
+                connection = connect(client, peer);
+            }
+            let Some((stream, reader)) = connection.as_mut() else {continue};
+            closest = request_closest(client, peer, stream, reader, target);
+            if closest.is_some() {
+                break;
+            }
+            // A late reply could still arrive on this connection, so the retry gets a fresh one
+            connection = None;
+        }
+        match closest {
+            Some(closest) => {
+                answered = true;
+                found.extend(closest);
+            },
+            // A node that refuses the first query is not asked 31 more times
+            None if !answered => return None,
+            None => {},
+        }
+    }
+    if let Some((stream, _)) = connection {
+        let _ = stream.shutdown(std::net::Shutdown::Both);
+    }
+    Some(found)
+}
+
+// Bounded by the ping timeout so a silent node cannot stall the crawl
+fn connect(client: &Client, peer: &PeerRecord) -> Option<(TcpStream, BufReader<TcpStream>)> {
+    let timeout = client.config.timeouts.ping();
+    let address: SocketAddr = peer.1.parse().ok()?;
+    let stream = TcpStream::connect_timeout(&address, timeout).ok()?;
+    stream.set_read_timeout(Some(timeout)).ok()?;
+    stream.set_write_timeout(Some(timeout)).ok()?;
+    let reader = BufReader::new(stream.try_clone().ok()?);
+    Some((stream, reader))
+}
+
+// Like Client::request_closest, on a connection kept open for the next bucket
+fn request_closest(client: &Client, peer: &PeerRecord, stream: &mut TcpStream, reader: &mut BufReader<TcpStream>, target: Key) -> Option<Vec<PeerRecord>> {
+    let msg = Message::new(
+        "PEERS_I".to_string(),
+        (client.key, client.host.clone()),
+        peer.clone(),
+        create_empty_peer_record(),
+        target,
+        Data::create_empty(),
+    );
+    let bytes = msg.make_message().into_bytes();
+    throttle().acquire_upload(remote_ip(stream), bytes.len());
+    stream.write_all(&bytes).ok()?;
+    metrics().message_sent(&msg.type_of, bytes.len());
+
+    let reply = Message::read_message(reader).ok()?;
+    (reply.type_of == "PEERS_R").then_some(reply.keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use crate::Config;
+
+    #[test]
+    fn bucket_is_the_common_prefix_length() {
+        assert_eq!(bucket(0, 0), None);
+        assert_eq!(bucket(0, 1 << 31), Some(0));
+        assert_eq!(bucket(0, 1), Some(31));
+        assert_eq!(bucket(0b1010 << 28, 0b1011 << 28), Some(3));
+        assert_eq!(bucket(7, 5), bucket(5, 7));
+    }
+
+    #[test]
+    fn bucket_fill_counts_each_neighbour_once() {
+        let mut crawl = Crawl::default();
+        let neighbours = BTreeSet::from([1 << 31, 3 << 30, 1, 0]);
+        crawl.nodes.insert(0, CrawledNode {address: String::new(), neighbours, reachable: Some(true)});
+
+        let fill = crawl.bucket_fill(0);
+        assert_eq!(fill[0], 2);
+        assert_eq!(fill[31], 1);
+        assert_eq!(fill.iter().sum::<usize>(), 3);
+        assert_eq!(crawl.bucket_fill(42), [0; BUCKETS]);
+    }
+
+    // Drops its first connection, then answers every PEERS_I on the connections after it
+    fn flaky_peer(connections: Arc<AtomicUsize>) -> PeerRecord {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer = (Key {key: 0xbeef}, listener.local_addr().unwrap().to_string());
+        let me = peer.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                if connections.fetch_add(1, Ordering::SeqCst) == 0 {
+                    continue;
+                }
+                let me = me.clone();
+                thread::spawn(move || {
+                    let mut reader = BufReader::new(stream.try_clone().unwrap());
+                    while let Ok(request) = Message::read_message(&mut reader) {
+                        let mut reply = Message::new("PEERS_R".to_string(), me.clone(), request.from, create_empty_peer_record(), request.key.0, Data::create_empty());
+                        reply.keys = vec![(request.key.0, "127.0.0.1:1".to_string())];
+                        stream.write_all(reply.make_message().as_bytes()).unwrap();
+                    }
+                });
+            }
+        });
+        peer
+    }
+
+    #[test]
+    fn query_retries_then_reuses_one_connection() {
+        let client = Client::with_config("127.0.0.1".to_string(), "0".to_string(), Config::default());
+        let connections = Arc::new(AtomicUsize::new(0));
+        let peer = flaky_peer(connections.clone());
+
+        let found = query_buckets(&client, &peer).expect("node answered after a retry");
+        assert_eq!(found.len(), BUCKETS);
+        assert_eq!(connections.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn closed_node_is_unreachable() {
+        let client = Client::with_config("127.0.0.1".to_string(), "0".to_string(), Config::default());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer = (Key {key: 0xbeef}, listener.local_addr().unwrap().to_string());
+        drop(listener);
+        assert!(query_buckets(&client, &peer).is_none());
+    }
+}
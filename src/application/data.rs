@@ -1,15 +1,144 @@
 use std::fmt::{self, Display};
+use std::fs::{self, File};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH, Duration};
 use serde::{Serialize, Deserialize};
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
+use crate::key::Key;
+
+const DEFAULT_MODE: u32 = 0o644;
+// Modes come from other peers, so setuid, setgid and sticky bits are never kept
+pub const PERMISSION_BITS: u32 = 0o777;
+
+// Magic byte prefixes checked before falling back to the file extension
+const MAGIC: [(&[u8], &str); 9] = [
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"GIF8", "image/gif"),
+    (b"%PDF-", "application/pdf"),
+    (b"PK\x03\x04", "application/zip"),
+    (b"\x1f\x8b", "application/gzip"),
+    (b"ID3", "audio/mpeg"),
+    (b"RIFF", "audio/wav"),
+    (b"\x7fELF", "application/x-executable"),
+];
+
+const EXTENSIONS: [(&str, &str); 10] = [
+    ("txt", "text/plain"),
+    ("md", "text/markdown"),
+    ("html", "text/html"),
+    ("css", "text/css"),
+    ("js", "text/javascript"),
+    ("json", "application/json"),
+    ("xml", "application/xml"),
+    ("csv", "text/csv"),
+    ("rs", "text/x-rust"),
+    ("toml", "application/toml"),
+];
+
+pub fn sniff_mime(filename: &str, contents: &[u8]) -> String {
+    for (magic, mime) in MAGIC {
+        if contents.starts_with(magic) {
+            return mime.to_string();
+        }
+    }
+    if contents.len() > 8 && &contents[4..8] == b"ftyp" {
+        return "video/mp4".to_string();
+    }
+
+    let extension = Path::new(filename).extension().and_then(|ext| ext.to_str()).unwrap_or("").to_lowercase();
+    for (ext, mime) in EXTENSIONS {
+        if ext == extension {
+            return mime.to_string();
+        }
+    }
+
+    if std::str::from_utf8(contents).is_ok() {
+        "text/plain".to_string()
+    } else {
+        "application/octet-stream".to_string()
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
 pub struct FileMetadata {
     pub filename: String,
+
+    #[serde(default)]
+    pub size: u64,
+    #[serde(default)]
+    pub hash: u32,
+    #[serde(default)]
+    pub mime: String,
+    // Seconds since the unix epoch
+    #[serde(default)]
+    pub modified: u64,
+    #[serde(default)]
+    pub mode: u32,
+}
+
+impl FileMetadata {
+    pub fn new(filename: &str, contents: &[u8]) -> FileMetadata {
+        let modified = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        FileMetadata {
+            filename: filename.to_string(),
+            size: contents.len() as u64,
+            hash: Key::generate_hash_from_data(contents).key,
+            mime: sniff_mime(filename, contents),
+            modified,
+            mode: DEFAULT_MODE,
+        }
+    }
+
+    // Metadata for a file read from disk, keeping its mtime and permissions
+    pub fn from_file(filename: &str, contents: &[u8], fs_meta: &fs::Metadata) -> FileMetadata {
+        let mut meta = FileMetadata::new(filename, contents);
+        if let Ok(modified) = fs_meta.modified() {
+            meta.modified = modified.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            meta.mode = fs_meta.permissions().mode() & PERMISSION_BITS;
+        }
+        meta
+    }
+
+    // Check received bytes against the recorded length and hash
+    pub fn verify(&self, contents: &[u8]) -> Result<(), String> {
+        if self.size != contents.len() as u64 {
+            return Err(format!("Size mismatch for {}: expected {} got {}", self.filename, self.size, contents.len()));
+        }
+        let hash = Key::generate_hash_from_data(contents).key;
+        if self.hash != hash {
+            return Err(format!("Hash mismatch for {}: expected {} got {}", self.filename, self.hash, hash));
+        }
+        Ok(())
+    }
+
+    // Restore mtime and permissions on a downloaded file
+    pub fn apply(&self, file: &File) -> std::io::Result<()> {
+        if self.modified > 0 {
+            file.set_modified(UNIX_EPOCH + Duration::from_secs(self.modified))?;
+        }
+        #[cfg(unix)]
+        if self.mode & PERMISSION_BITS > 0 {
+            use std::os::unix::fs::PermissionsExt;
+            file.set_permissions(fs::Permissions::from_mode(self.mode & PERMISSION_BITS))?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for FileMetadata {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} bytes {} mtime {} mode {:o} hash {}", self.filename, self.size, self.mime, self.modified, self.mode, self.hash)
+    }
 }
 
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
 pub struct Data {
-    pub id: u32,
     pub vec: Vec<u8>,
 
     #[serde(flatten)]
@@ -17,9 +146,14 @@ pub struct Data {
 }
 
 impl Data {
+    pub fn new(filename: &str, vec: Vec<u8>) -> Data {
+        let meta = FileMetadata::new(filename, &vec);
+        Data{vec, file_meta: meta}
+    }
+
     pub fn create_empty() -> Data {
-        let meta = FileMetadata {filename: "".to_string()};
-        return Data{id: 0, vec: Vec::new(), file_meta: meta};
+        let meta = FileMetadata {filename: "".to_string(), size: 0, hash: 0, mime: "".to_string(), modified: 0, mode: 0};
+        Data{vec: Vec::new(), file_meta: meta}
     }
 }
 
@@ -29,9 +163,32 @@ impl Display for Data {
         write!(f, "{}", dat)
     }
 }
-impl PartialEq for Data {
-    fn eq(&self, other: &Data) -> bool {
-        return self.id == other.id;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn data_saved_with_an_id_still_loads() {
+        let data = Data::new("a.txt", b"text".to_vec());
+        let mut json = serde_json::to_value(&data).unwrap();
+        assert!(json.get("id").is_none());
+
+        json["id"] = 1.into();
+        assert_eq!(serde_json::from_value::<Data>(json).unwrap(), data);
     }
-}
 
+    #[cfg(unix)]
+    #[test]
+    fn apply_drops_special_mode_bits() {
+        use std::os::unix::fs::PermissionsExt;
+        let path = std::env::temp_dir().join(format!("peer_stream_data_mode_{}", std::process::id()));
+        let file = File::create(&path).unwrap();
+        let mut meta = FileMetadata::new("a.sh", b"#!/bin/sh");
+        meta.mode = 0o6755;
+        meta.apply(&file).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode() & 0o7777;
+        fs::remove_file(&path).unwrap();
+        assert_eq!(mode, 0o755);
+    }
+}
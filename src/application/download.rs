@@ -0,0 +1,314 @@
+use std::error::Error;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use serde::{Serialize, Deserialize};
+
+use crate::Client;
+use crate::key::Key;
+use crate::data::{Data, FileMetadata};
+use crate::manifest::{self, FileCheck, RangeReader};
+
+// Which chunks of a manifest are already verified and written to the .part file
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct Journal {
+    pub manifest_key: u32,
+    pub done: Vec<bool>,
+}
+
+impl Journal {
+    pub fn load(path: &Path) -> Option<Journal> {
+        let contents = fs::read(path).ok()?;
+        serde_json::from_slice(&contents).ok()
+    }
+
+    // Write to a temporary file first so a crash never leaves a torn journal
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let tmp = with_suffix(path, ".tmp");
+        fs::write(&tmp, serde_json::to_vec(self)?)?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name: OsString = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+pub fn part_path(dest: &Path) -> PathBuf {
+    with_suffix(dest, ".part")
+}
+
+pub fn journal_path(dest: &Path) -> PathBuf {
+    with_suffix(dest, ".journal")
+}
+
+// Write a retrieved value to dest, fetching the chunks of a manifest
+pub fn save_file(client: &mut Client, key: Key, data: &Data, dest: &Path) -> Result<(), Box<dyn Error>> {
+    if manifest::is_manifest(data) {
+        return fetch_manifest(client, key, data, dest);
+    }
+    data.file_meta.verify(&data.vec)?;
+    write_file(&data.vec, &data.file_meta, dest)
+}
+
+// Write through dest.part and rename, so dest only ever holds a complete file
+fn write_file(contents: &[u8], file_meta: &FileMetadata, dest: &Path) -> Result<(), Box<dyn Error>> {
+    let part = part_path(dest);
+    let mut file = File::create(&part)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    file_meta.apply(&file)?;
+    drop(file);
+
+    fs::rename(&part, dest)?;
+    Ok(())
+}
+
+// Download every chunk into dest.part, resuming from dest.journal when present.
+// Each chunk is checked against its content key as it arrives, and the whole
+// file is hashed in order as it is written and checked before dest.part is
+// renamed. Chunks are not kept in the local store.
+pub fn fetch_manifest(client: &mut Client, key: Key, data: &Data, dest: &Path) -> Result<(), Box<dyn Error>> {
+    let manifest = manifest::parse_manifest(data)?;
+    if manifest.erasure.is_some() {
+        return fetch_erasure(client, manifest, dest);
+    }
+
+    let part = part_path(dest);
+    let journal_file = journal_path(dest);
+
+    let mut journal = match Journal::load(&journal_file) {
+        Some(journal) if journal.manifest_key == key.key && journal.done.len() == manifest.chunks.len() && part.exists() => {
+            log::info!("Resuming {} with {}/{} chunks", dest.display(), journal.done.iter().filter(|done| **done).count(), journal.done.len());
+            journal
+        },
+        _ => Journal {manifest_key: key.key, done: vec![false; manifest.chunks.len()]},
+    };
+
+    let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&part)?;
+    let mut check = FileCheck::new();
+
+    for (i, chunk) in manifest.chunks.iter().enumerate() {
+        if journal.done[i] {
+            // Re-check chunks written before a restart, reading no more than the .part holds
+            let mut buffer = Vec::new();
+            file.seek(SeekFrom::Start(chunk.offset))?;
+            (&mut file).take(chunk.size).read_to_end(&mut buffer)?;
+            if buffer.len() as u64 == chunk.size && Key::generate_hash_from_data(&buffer).key == chunk.key {
+                check.update(&buffer);
+                continue;
+            }
+            journal.done[i] = false;
+        }
+
+        let chunk_data = client.fetch_value(Key {key: chunk.key}, Key {key: chunk.key}, false)?;
+        if chunk_data.vec.len() as u64 != chunk.size {
+            return Err(format!("Chunk {} of {} has the wrong size", i, dest.display()).into());
+        }
+        file.seek(SeekFrom::Start(chunk.offset))?;
+        file.write_all(&chunk_data.vec)?;
+        file.sync_data()?;
+        check.update(&chunk_data.vec);
+
+        journal.done[i] = true;
+        journal.save(&journal_file)?;
+    }
+
+    // A .part left by an earlier, larger download may run past the file
+    file.set_len(manifest.file_meta.size)?;
+    if let Err(e) = check.verify(&manifest) {
+        drop(file);
+        let _ = fs::remove_file(&part);
+        let _ = fs::remove_file(&journal_file);
+        return Err(e.into());
+    }
+    manifest.file_meta.apply(&file)?;
+    drop(file);
+
+    fs::rename(&part, dest)?;
+    fs::remove_file(&journal_file)?;
+    Ok(())
+}
+
+// Stream the data shards of an erasure coded file into dest.part, rebuilding
+// from parity only if one of them is missing
+fn fetch_erasure(client: &mut Client, manifest: manifest::Manifest, dest: &Path) -> Result<(), Box<dyn Error>> {
+    let part = part_path(dest);
+    let mut reader = RangeReader::new(client.clone(), manifest.clone(), 0, manifest.file_meta.size);
+    let mut file = File::create(&part)?;
+    let mut check = FileCheck::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let count = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(count) => count,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        file.write_all(&buffer[..count])?;
+        check.update(&buffer[..count]);
+    }
+    if let Err(e) = check.verify(&manifest) {
+        drop(file);
+        let _ = fs::remove_file(&part);
+        return Err(e.into());
+    }
+    file.sync_all()?;
+    manifest.file_meta.apply(&file)?;
+    drop(file);
+
+    fs::rename(&part, dest)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::io::BufReader;
+    use std::net::TcpListener;
+    use std::thread;
+    use crate::Config;
+    use crate::client::create_empty_peer_record;
+    use crate::connection::Message;
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("peer_stream_download_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn client() -> Client {
+        let client = Client::with_config("127.0.0.1".to_string(), "0".to_string(), Config::default());
+        client.known_nodes.lock().unwrap().clear();
+        *client
+    }
+
+    // A node answering value requests from pieces
+    fn serve_pieces(pieces: HashMap<Key, Data>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let holder = (Key {key: 0xbeef}, address.clone());
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let request = Message::read_message(&mut BufReader::new(stream.try_clone().unwrap())).unwrap();
+                let reply = match pieces.get(&request.key.0) {
+                    Some(piece) => Message::new("PEERS_R_GET".to_string(), holder.clone(), request.from, create_empty_peer_record(), request.key.0, piece.clone()),
+                    None => Message::new("NOT_FOUND".to_string(), holder.clone(), request.from, create_empty_peer_record(), request.key.0, Data::create_empty()),
+                };
+                stream.write_all(reply.make_message().as_bytes()).unwrap();
+            }
+        });
+        address
+    }
+
+    // Upload a file, then move every piece but the manifest to another node
+    fn uploaded_elsewhere(client: &mut Client, file: &[u8], erasure: bool) -> (Key, Data) {
+        let data = Data::new("file.bin", file.to_vec());
+        let key = match erasure {
+            true => manifest::put_file_erasure(client, data, 4, 2).unwrap(),
+            false => manifest::put_file(client, data, 1).unwrap(),
+        };
+        let mut local_hash = client.local_hash.lock().unwrap();
+        let manifest = local_hash.remove(&key).unwrap();
+        let address = serve_pieces(local_hash.drain().collect());
+        client.known_nodes.lock().unwrap().insert(Key {key: 0xbeef}, address);
+        (key, manifest)
+    }
+
+    fn contents(size: usize) -> Vec<u8> {
+        (0..size).map(|i| (i * 13 % 251) as u8).collect()
+    }
+
+    #[test]
+    fn chunked_download_keeps_no_chunks() {
+        let mut client = client();
+        let file = contents(manifest::CHUNK_SIZE * 2 + 100);
+        let (key, manifest) = uploaded_elsewhere(&mut client, &file, false);
+
+        let dest = scratch("chunked").join("file.bin");
+        save_file(&mut client, key, &manifest, &dest).unwrap();
+        assert_eq!(fs::read(&dest).unwrap(), file);
+        assert!(!part_path(&dest).exists() && !journal_path(&dest).exists());
+        assert!(client.local_hash.lock().unwrap().is_empty());
+        let _ = fs::remove_dir_all(dest.parent().unwrap());
+    }
+
+    #[test]
+    fn chunked_download_resumes_from_journal() {
+        let mut client = client();
+        let file = contents(manifest::CHUNK_SIZE * 2 + 100);
+        let (key, manifest) = uploaded_elsewhere(&mut client, &file, false);
+
+        // A previous run wrote the first chunk, and garbage where it claims the second is
+        let dest = scratch("resume").join("file.bin");
+        let mut part = file.clone();
+        part[manifest::CHUNK_SIZE..manifest::CHUNK_SIZE * 2].fill(0);
+        fs::write(part_path(&dest), &part).unwrap();
+        Journal {manifest_key: key.key, done: vec![true, true, false]}.save(&journal_path(&dest)).unwrap();
+
+        save_file(&mut client, key, &manifest, &dest).unwrap();
+        assert_eq!(fs::read(&dest).unwrap(), file);
+        let _ = fs::remove_dir_all(dest.parent().unwrap());
+    }
+
+    // The manifest value with its parsed form changed by edit
+    fn tampered(manifest_data: &Data, edit: impl FnOnce(&mut manifest::Manifest)) -> Data {
+        let mut parsed = manifest::parse_manifest(manifest_data).unwrap();
+        edit(&mut parsed);
+        let mut data = Data::new("file.bin", serde_json::to_vec(&parsed).unwrap());
+        data.file_meta.mime = manifest::MANIFEST_MIME.to_string();
+        data
+    }
+
+    #[test]
+    fn wrong_file_hash_is_never_put_in_place() {
+        let mut client = client();
+        for erasure in [false, true] {
+            let file = contents(manifest::CHUNK_SIZE + 100);
+            let (key, manifest) = uploaded_elsewhere(&mut client, &file, erasure);
+            let manifest = tampered(&manifest, |parsed| parsed.sha256 = Some(manifest::sha256_hex(b"something else")));
+
+            let dest = scratch("wrong_hash").join("file.bin");
+            let error = save_file(&mut client, key, &manifest, &dest).unwrap_err().to_string();
+            assert!(error.starts_with("Hash mismatch"), "{}", error);
+            assert!(!dest.exists() && !part_path(&dest).exists() && !journal_path(&dest).exists());
+            let _ = fs::remove_dir_all(dest.parent().unwrap());
+        }
+    }
+
+    #[test]
+    fn claimed_sizes_are_not_allocated() {
+        let mut client = client();
+        let file = contents(manifest::CHUNK_SIZE + 100);
+        let (key, manifest) = uploaded_elsewhere(&mut client, &file, false);
+        let manifest = tampered(&manifest, |parsed| {
+            parsed.chunks[1].size = u64::MAX / 2;
+            parsed.file_meta.size = parsed.chunks[1].offset + u64::MAX / 2;
+        });
+
+        let dest = scratch("claimed").join("file.bin");
+        assert!(save_file(&mut client, key, &manifest, &dest).is_err());
+        assert!(!dest.exists() && !part_path(&dest).exists());
+        let _ = fs::remove_dir_all(dest.parent().unwrap());
+    }
+
+    #[test]
+    fn erasure_download_streams_shards() {
+        let mut client = client();
+        let file = contents(10_000);
+        let (key, manifest) = uploaded_elsewhere(&mut client, &file, true);
+
+        let dest = scratch("erasure").join("file.bin");
+        save_file(&mut client, key, &manifest, &dest).unwrap();
+        assert_eq!(fs::read(&dest).unwrap(), file);
+        assert!(client.local_hash.lock().unwrap().is_empty());
+        let _ = fs::remove_dir_all(dest.parent().unwrap());
+    }
+}
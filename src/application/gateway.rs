@@ -0,0 +1,343 @@
+use std::error::Error;
+use std::io::Read;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use serde_json::json;
+use tiny_http::{Header, Method, Request, Response, ResponseBox, Server, StatusCode};
+
+use crate::Client;
+use crate::data::Data;
+use crate::key::Key;
+use crate::latency::NodeStats;
+use crate::manifest::{self, RangeReader};
+use crate::logging;
+
+// How often the workers check whether the node is shutting down
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+// Requests handled at once, others wait in the listener's queue
+const WORKERS: usize = 8;
+// Uploads are held in memory while they are split and stored
+pub const MAX_UPLOAD: u64 = 64 * 1024 * 1024;
+
+type HttpResponse = ResponseBox;
+
+// Serve the HTTP gateway on address until the node shuts down
+pub fn serve(client: Client, address: &str) -> Result<JoinHandle<()>, Box<dyn Error>> {
+    let server = Arc::new(Server::http(address).map_err(|e| format!("Could not listen on {}: {}", address, e))?);
+    log::info!("HTTP gateway on {}", address);
+
+    Ok(thread::spawn(move || {
+        let workers: Vec<JoinHandle<()>> = (0..WORKERS).map(|_| {
+            let server = server.clone();
+            let mut client = client.clone();
+            thread::spawn(move || {
+                while !client.stopping.load(Ordering::SeqCst) {
+                    match server.recv_timeout(POLL_INTERVAL) {
+                        Ok(Some(request)) => handle(&mut client, request),
+                        Ok(None) => continue,
+                        Err(e) => {
+                            log::warn!("HTTP gateway stopped: {}", e);
+                            return;
+                        },
+                    }
+                }
+            })
+        }).collect();
+        for worker in workers {
+            let _ = worker.join();
+        }
+    }))
+}
+
+fn handle(client: &mut Client, mut request: Request) {
+    let url = request.url().to_string();
+    let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+    let request_id = logging::request_id();
+    let method = request.method().to_string();
+    log::info!(request_id = request_id.as_str(), method = method.as_str(), path = path; "HTTP request");
+
+    let response = match (request.method(), path) {
+        (Method::Get, "/nodes") => Ok(nodes(client)),
+        (Method::Post, "/content") => upload(client, &mut request, query),
+        (Method::Get, _) if path.starts_with("/content/") => content(client, &request, &path["/content/".len()..]),
+        (_, "/nodes") | (_, "/content") => Ok(text(405, "Method not allowed")),
+        _ if path.starts_with("/content/") => Ok(text(405, "Method not allowed")),
+        _ => Ok(text(404, "Not found")),
+    };
+    let response = response.unwrap_or_else(|e| text(502, &e.to_string())).with_header(header("X-Request-Id", &request_id));
+    log::info!(request_id = request_id.as_str(), status = response.status_code().0; "HTTP response");
+    if let Err(e) = request.respond(response) {
+        log::warn!(request_id = request_id.as_str(); "Could not answer HTTP request: {}", e);
+    }
+}
+
+// Values may come from peers, anything but printable ASCII becomes '_' so the
+// header can be neither rejected nor split. Names are always our own constants.
+fn header(name: &str, value: &str) -> Header {
+    let value: String = value.chars().map(|c| if c == ' ' || c.is_ascii_graphic() { c } else { '_' }).collect();
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).expect("header names are constants")
+}
+
+// A MIME type as stored by a peer, or application/octet-stream when it is not one
+fn content_type(mime: &str) -> &str {
+    let valid = match mime.split_once('/') {
+        Some((kind, rest)) => !kind.is_empty() && !rest.is_empty()
+            && mime.chars().all(|c| c == ' ' || c.is_ascii_graphic()),
+        None => false,
+    };
+    if valid { mime } else { "application/octet-stream" }
+}
+
+// RFC 6266 with an ASCII fallback for old clients and the exact name as RFC 5987 UTF-8
+fn content_disposition(filename: &str) -> String {
+    let fallback: String = filename.chars()
+        .map(|c| if (c == ' ' || c.is_ascii_graphic()) && c != '"' && c != '\\' { c } else { '_' })
+        .collect();
+    let mut encoded = String::new();
+    for byte in filename.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'!' | b'#' | b'$' | b'&' | b'+' | b'-' | b'.' | b'^' | b'_' | b'`' | b'|' | b'~' => encoded.push(byte as char),
+            _ => encoded += &format!("%{:02X}", byte),
+        }
+    }
+    format!("inline; filename=\"{}\"; filename*=UTF-8''{}", fallback, encoded)
+}
+
+fn text(status: u16, body: &str) -> HttpResponse {
+    Response::from_data(format!("{}\n", body).into_bytes())
+        .with_status_code(status)
+        .with_header(header("Content-Type", "text/plain; charset=utf-8"))
+        .boxed()
+}
+
+fn json_response(status: u16, body: serde_json::Value) -> HttpResponse {
+    Response::from_data(body.to_string().into_bytes())
+        .with_status_code(status)
+        .with_header(header("Content-Type", "application/json"))
+        .boxed()
+}
+
+fn nodes(client: &Client) -> HttpResponse {
+    let nodes: Vec<serde_json::Value> = client.routing_table().iter().map(|((key, address), stats)| node_json(client, *key, address, stats)).collect();
+    json_response(200, json!({"key": client.key.key, "address": client.host, "nodes": nodes}))
+}
+
+// One routing table entry as served by the gateway and the RPC socket
+pub fn node_json(client: &Client, key: Key, address: &str, stats: &NodeStats) -> serde_json::Value {
+    json!({
+        "key": key.key,
+        "address": address,
+        "distance": client.key.distance(key),
+        "rtt_ms": stats.rtt.map(|rtt| rtt.as_secs_f64() * 1000.0),
+        "last_seen_secs": stats.last_seen.map(|seen| seen.elapsed().as_secs()),
+        "failures": stats.failures,
+    })
+}
+
+fn upload(client: &mut Client, request: &mut Request, query: &str) -> Result<HttpResponse, Box<dyn Error>> {
+    let name = query.split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(name, _)| *name == "name")
+        .map(|(_, value)| percent_decode(value))
+        .unwrap_or_else(|| "upload".to_string());
+
+    let too_large = format!("Uploads are limited to {} bytes", MAX_UPLOAD);
+    if request.body_length().is_some_and(|length| length as u64 > MAX_UPLOAD) {
+        return Ok(text(413, &too_large));
+    }
+    let mut body = Vec::new();
+    request.as_reader().take(MAX_UPLOAD + 1).read_to_end(&mut body)?;
+    if body.len() as u64 > MAX_UPLOAD {
+        return Ok(text(413, &too_large));
+    }
+    let size = body.len();
+    let replication = client.replication();
+    let key = manifest::put_file(client, Data::new(&name, body), replication)?;
+    Ok(json_response(201, json!({"key": key.key, "name": name, "size": size})))
+}
+
+fn content(client: &mut Client, request: &Request, key: &str) -> Result<HttpResponse, Box<dyn Error>> {
+    let key = match key.parse::<u32>() {
+        Ok(key) => Key {key},
+        Err(_) => return Ok(text(400, "Keys are unsigned integers")),
+    };
+    let data = match client.get_data(key) {
+        Ok(data) => data,
+        Err(_) => return Ok(text(404, "Not found")),
+    };
+
+    let manifest = if manifest::is_manifest(&data) { Some(manifest::parse_manifest(&data)?) } else { None };
+    let file_meta = manifest.as_ref().map(|manifest| manifest.file_meta.clone()).unwrap_or(data.file_meta.clone());
+    let size = manifest.as_ref().map(|manifest| manifest.file_meta.size).unwrap_or(data.vec.len() as u64);
+
+    let range = request.headers().iter()
+        .find(|header| header.field.equiv("Range"))
+        .map(|header| parse_range(header.value.as_str(), size));
+
+    let (status, start, end) = match range {
+        Some(Some(Ok((start, end)))) => (206, start, end),
+        Some(Some(Err(()))) => {
+            return Ok(text(416, "Range not satisfiable").with_header(header("Content-Range", &format!("bytes */{}", size))));
+        },
+        _ => (200, 0, size),
+    };
+
+    // Large files are streamed a chunk at a time rather than collected first
+    let length = (end - start) as usize;
+    let body: Box<dyn Read + Send> = match manifest {
+        Some(manifest) => Box::new(RangeReader::new(client.clone(), manifest, start, end)),
+        None => Box::new(std::io::Cursor::new(data.vec[start as usize..end as usize].to_vec())),
+    };
+
+    let mut response = Response::new(StatusCode(status), Vec::new(), body, Some(length), None)
+        .with_header(header("Content-Type", content_type(&file_meta.mime)))
+        .with_header(header("Accept-Ranges", "bytes"));
+    if !file_meta.filename.is_empty() {
+        response.add_header(header("Content-Disposition", &content_disposition(&file_meta.filename)));
+    }
+    if status == 206 {
+        response.add_header(header("Content-Range", &format!("bytes {}-{}/{}", start, end - 1, size)));
+    }
+    Ok(response)
+}
+
+// Half open byte range of a single range Range header. None means the header
+// is ignored and the whole body is sent, Err that it cannot be satisfied.
+fn parse_range(value: &str, size: u64) -> Option<Result<(u64, u64), ()>> {
+    let spec = value.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (first, last) = spec.split_once('-')?;
+    let (first, last) = (first.trim(), last.trim());
+
+    let (start, end) = if first.is_empty() {
+        // Suffix range: the last n bytes
+        let suffix: u64 = last.parse().ok()?;
+        if suffix == 0 { return Some(Err(())); }
+        (size.saturating_sub(suffix), size)
+    } else {
+        let start: u64 = first.parse().ok()?;
+        let end = if last.is_empty() { size } else { last.parse::<u64>().ok()?.saturating_add(1).min(size) };
+        (start, end)
+    };
+
+    if start >= size || start >= end {
+        return Some(Err(()));
+    }
+    Some(Ok((start, end)))
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                match std::str::from_utf8(&bytes[i + 1..i + 3]).map(|hex| u8::from_str_radix(hex, 16)) {
+                    Ok(Ok(byte)) => {
+                        decoded.push(byte);
+                        i += 2;
+                    },
+                    _ => decoded.push(b'%'),
+                }
+            },
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::net::{TcpListener, TcpStream};
+    use crate::Config;
+
+    #[test]
+    fn parse_range_forms() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Some(Ok((0, 100))));
+        assert_eq!(parse_range("bytes=900-", 1000), Some(Ok((900, 1000))));
+        assert_eq!(parse_range("bytes=-100", 1000), Some(Ok((900, 1000))));
+        assert_eq!(parse_range("bytes=-5000", 1000), Some(Ok((0, 1000))));
+        assert_eq!(parse_range("bytes=990-5000", 1000), Some(Ok((990, 1000))));
+        assert_eq!(parse_range(" bytes= 10 - 19 ", 1000), Some(Ok((10, 20))));
+    }
+
+    #[test]
+    fn parse_range_unsatisfiable() {
+        assert_eq!(parse_range("bytes=1000-", 1000), Some(Err(())));
+        assert_eq!(parse_range("bytes=-0", 1000), Some(Err(())));
+        assert_eq!(parse_range("bytes=20-10", 1000), Some(Err(())));
+        assert_eq!(parse_range("bytes=0-", 0), Some(Err(())));
+    }
+
+    #[test]
+    fn parse_range_ignored() {
+        assert_eq!(parse_range("items=0-10", 1000), None);
+        assert_eq!(parse_range("bytes=0-10,20-30", 1000), None);
+        assert_eq!(parse_range("bytes=a-b", 1000), None);
+        assert_eq!(parse_range("bytes=10", 1000), None);
+    }
+
+    #[test]
+    fn percent_decode_names() {
+        assert_eq!(percent_decode("my%20notes+v2.txt"), "my notes v2.txt");
+        assert_eq!(percent_decode("caf%C3%A9"), "café");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz"), "%zz");
+    }
+
+    #[test]
+    fn peer_metadata_makes_safe_headers() {
+        assert_eq!(content_type("text/plain; charset=utf-8"), "text/plain; charset=utf-8");
+        assert_eq!(content_type("text/plain\r\nX-Evil: 1"), "application/octet-stream");
+        assert_eq!(content_type("plain"), "application/octet-stream");
+        assert_eq!(content_type("tëxt/plain"), "application/octet-stream");
+
+        assert_eq!(content_disposition("notes.txt"), "inline; filename=\"notes.txt\"; filename*=UTF-8''notes.txt");
+        assert_eq!(content_disposition("café \"v2\".txt"), "inline; filename=\"caf_ _v2_.txt\"; filename*=UTF-8''caf%C3%A9%20%22v2%22.txt");
+        assert_eq!(header("X-Test", "a\r\nb\u{e9}").value.as_str(), "a__b_");
+    }
+
+    // Fetch path from the gateway with a plain HTTP/1.0 request, returning the raw response
+    fn http_get(address: &str, path: &str) -> String {
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(stream, "GET {} HTTP/1.0\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn serves_non_ascii_filenames() {
+        let client = Client::with_config("127.0.0.1".to_string(), "0".to_string(), Config::default());
+        client.known_nodes.lock().unwrap().clear();
+        let mut data = Data::new("café.txt", b"bonjour".to_vec());
+        data.file_meta.mime = "text/plain\r\nSet-Cookie: x".to_string();
+        let key = Key::generate_hash_from_data(&data.vec);
+        client.local_hash.lock().unwrap().insert(key, data);
+
+        let address = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+        let gateway = serve((*client).clone(), &address).unwrap();
+
+        // Every worker must survive, so ask more times than there are workers
+        for _ in 0..WORKERS + 1 {
+            let response = http_get(&address, &format!("/content/{}", key.key));
+            assert!(response.starts_with("HTTP/1.0 200") || response.starts_with("HTTP/1.1 200"), "{}", response);
+            assert!(response.contains("filename*=UTF-8''caf%C3%A9.txt"), "{}", response);
+            assert!(response.contains("Content-Type: application/octet-stream"), "{}", response);
+            assert!(!response.contains("Set-Cookie"), "{}", response);
+            assert!(response.ends_with("bonjour"), "{}", response);
+        }
+
+        client.stopping.store(true, Ordering::SeqCst);
+        gateway.join().unwrap();
+    }
+}
@@ -0,0 +1,129 @@
+use std::collections::HashMap;
+
+use crate::key::Key;
+
+// (filename, content key) pair stored under a name or keyword key
+pub type IndexEntry = (String, Key);
+
+// Splits a filename into lowercase alphanumeric keywords
+pub fn tokenize(name: &str) -> Vec<String> {
+    let mut tokens: Vec<String> = Vec::new();
+    for token in name.split(|c: char| !c.is_alphanumeric()) {
+        if token.is_empty() { continue; }
+
+        let token = token.to_lowercase();
+        if !tokens.contains(&token) {
+            tokens.push(token);
+        }
+    }
+    tokens
+}
+
+// Keywords are also published under their leading characters so SEARCH can
+// match the start of a word. Shorter prefixes would put too many entries
+// under one key, longer ones are cut to bound the keys a name is stored under.
+pub const MIN_PREFIX: usize = 3;
+pub const MAX_PREFIX: usize = 16;
+
+// The prefixes of token a name is published under, the token itself included
+pub fn prefixes(token: &str) -> Vec<String> {
+    let chars: Vec<char> = token.chars().collect();
+    let mut prefixes: Vec<String> = (MIN_PREFIX..chars.len().min(MAX_PREFIX))
+        .map(|len| chars[..len].iter().collect())
+        .collect();
+    prefixes.push(token.to_string());
+    prefixes
+}
+
+pub fn name_key(name: &str) -> Key {
+    Key::generate_hash_from_data(name.as_bytes())
+}
+
+// Where to look up a query keyword. Prefixes are published up to
+// MAX_PREFIX - 1 characters, so a longer keyword is looked up by that many
+// and the entries found are filtered by the whole keyword with matches.
+pub fn lookup_key(token: &str) -> Key {
+    match token.char_indices().nth(MAX_PREFIX - 1) {
+        Some((end, _)) => name_key(&token[..end]),
+        None => name_key(token),
+    }
+}
+
+// Every key a filename is published under: the full name plus each keyword and its prefixes
+pub fn index_keys(name: &str) -> Vec<Key> {
+    let mut keys = vec![name_key(name)];
+    for token in tokenize(name) {
+        for prefix in prefixes(&token) {
+            let key = name_key(&prefix);
+            if !keys.contains(&key) {
+                keys.push(key);
+            }
+        }
+    }
+    keys
+}
+
+pub fn add_entry(index: &mut HashMap<Key, Vec<IndexEntry>>, index_key: Key, entry: IndexEntry) {
+    let entries = index.entry(index_key).or_default();
+    if !entries.contains(&entry) {
+        entries.push(entry);
+    }
+}
+
+// True when every keyword of the query starts one of the entry's keywords
+pub fn matches(entry: &IndexEntry, query_tokens: &[String]) -> bool {
+    let tokens = tokenize(&entry.0);
+    query_tokens.iter().all(|query| tokens.iter().any(|token| token.starts_with(query.as_str())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(name: &str) -> IndexEntry {
+        (name.to_string(), Key {key: 1})
+    }
+
+    #[test]
+    fn tokenize_splits_and_lowercases() {
+        assert_eq!(tokenize("My_Holiday-Photos 2024.JPG"), vec!["my", "holiday", "photos", "2024", "jpg"]);
+        assert_eq!(tokenize("a a A"), vec!["a"]);
+    }
+
+    #[test]
+    fn prefixes_start_at_min_length() {
+        assert_eq!(prefixes("holiday"), vec!["hol", "holi", "holid", "holida", "holiday"]);
+        assert_eq!(prefixes("my"), vec!["my"]);
+        assert_eq!(prefixes("été"), vec!["été"]);
+        assert_eq!(prefixes(&"x".repeat(40)).len(), MAX_PREFIX - MIN_PREFIX + 1);
+    }
+
+    #[test]
+    fn names_are_published_under_prefixes() {
+        let keys = index_keys("holiday.jpg");
+        assert!(keys.contains(&name_key("holiday.jpg")));
+        assert!(keys.contains(&name_key("holi")));
+        assert!(keys.contains(&name_key("jpg")));
+        assert!(!keys.contains(&name_key("ho")));
+    }
+
+    #[test]
+    fn long_prefixes_are_looked_up_clipped() {
+        let word = "abcdefghijklmnopqrstuvwxyz";
+        let keys = index_keys(word);
+        for len in MIN_PREFIX..=word.len() {
+            assert!(keys.contains(&lookup_key(&word[..len])), "prefix of {} characters", len);
+        }
+        assert_eq!(lookup_key("holiday"), name_key("holiday"));
+        assert_eq!(lookup_key(&"é".repeat(20)), name_key(&"é".repeat(MAX_PREFIX - 1)));
+    }
+
+    #[test]
+    fn matches_word_prefixes_only() {
+        let holiday = entry("Holiday Photos.jpg");
+        assert!(matches(&holiday, &tokenize("holi")));
+        assert!(matches(&holiday, &tokenize("phot holid")));
+        assert!(!matches(&holiday, &tokenize("iday")));
+        assert!(!matches(&holiday, &tokenize("holiday video")));
+    }
+}
@@ -1,18 +1,33 @@
+use std::fs;
+use std::path::Path;
 use farmhash::fingerprint32;
+use rand::Rng;
 
-#[derive(Hash, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+#[derive(Hash, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug)]
 pub struct Key {
     pub key: u32
 }
 
 impl Key {
     pub fn distance(self, other_key : Key) -> u32 {
-        return self.key ^ other_key.key;
+        self.key ^ other_key.key
     }
 
     pub fn generate_hash_from_data(data: &[u8]) -> Key {
        let hash = fingerprint32(data);
 
-        return Key{key:hash};
+        Key{key:hash}
+    }
+
+    // Reuse the node key stored at path, creating a random one on first start
+    pub fn load_or_create(path: &Path) -> std::io::Result<Key> {
+        if let Ok(contents) = fs::read_to_string(path) {
+            if let Ok(key) = contents.trim().parse::<u32>() {
+                return Ok(Key{key});
+            }
+        }
+        let key = Key{key: rand::thread_rng().gen::<u32>()};
+        fs::write(path, key.key.to_string())?;
+        Ok(key)
     }
 } 
@@ -0,0 +1,128 @@
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use rand::Rng;
+use serde::{Serialize, Deserialize};
+
+use crate::data::Data;
+
+pub const PING_MIME: &str = "application/x-p2p-ping";
+
+fn now_micros() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_micros() as u64).unwrap_or(0)
+}
+
+// Body of a PING, echoed back unchanged by the peer
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct Probe {
+    pub nonce: u64,
+    // Microseconds since the epoch when the probe left, for the peer's logs.
+    // The sender times the round trip with its own monotonic clock.
+    pub sent_at: u64,
+}
+
+impl Probe {
+    pub fn new() -> Probe {
+        Probe {nonce: rand::thread_rng().gen::<u64>(), sent_at: now_micros()}
+    }
+
+    pub fn to_data(&self) -> Data {
+        let mut data = Data::new("", serde_json::to_vec(self).unwrap());
+        data.file_meta.mime = PING_MIME.to_string();
+        data
+    }
+
+    pub fn from_data(data: &Data) -> Option<Probe> {
+        if data.file_meta.mime != PING_MIME { return None; }
+        serde_json::from_slice(&data.vec).ok()
+    }
+}
+
+impl Default for Probe {
+    fn default() -> Probe {
+        Probe::new()
+    }
+}
+
+// Liveness of one routing table entry
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct NodeStats {
+    pub last_seen: Option<Instant>,
+    // Smoothed round trip time, as TCP keeps it
+    pub rtt: Option<Duration>,
+    // Requests unanswered in a row
+    pub failures: u32,
+}
+
+impl NodeStats {
+    // Any reply counts as a sign of life, not only an answered probe
+    pub fn seen(&mut self) {
+        self.last_seen = Some(Instant::now());
+        self.failures = 0;
+    }
+
+    pub fn record_rtt(&mut self, rtt: Duration) {
+        self.seen();
+        self.rtt = Some(match self.rtt {
+            Some(smoothed) => (smoothed * 7 + rtt) / 8,
+            None => rtt,
+        });
+    }
+
+    pub fn record_failure(&mut self) {
+        self.failures += 1;
+    }
+
+    // Sort key for choosing peers: responsive first, then by latency
+    pub fn rank(&self) -> (bool, Duration) {
+        (self.failures > 0, self.rtt.unwrap_or(Duration::MAX))
+    }
+}
+
+// Min, average and max of the probes that came back
+pub fn summarize(rtts: &[Duration]) -> Option<(Duration, Duration, Duration)> {
+    let min = *rtts.iter().min()?;
+    let max = *rtts.iter().max()?;
+    let avg = rtts.iter().sum::<Duration>() / rtts.len() as u32;
+    Some((min, avg, max))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn any_reply_clears_failures() {
+        let mut stats = NodeStats::default();
+        stats.record_failure();
+        stats.record_failure();
+        assert!(stats.rank().0);
+        stats.seen();
+        assert_eq!(stats.failures, 0);
+        assert!(stats.last_seen.is_some());
+    }
+
+    #[test]
+    fn rtt_is_smoothed() {
+        let mut stats = NodeStats::default();
+        stats.record_rtt(Duration::from_millis(80));
+        stats.record_rtt(Duration::from_millis(160));
+        assert_eq!(stats.rtt, Some(Duration::from_millis(90)));
+    }
+
+    #[test]
+    fn responsive_nodes_rank_first() {
+        let mut failing = NodeStats::default();
+        failing.record_rtt(Duration::from_millis(1));
+        failing.record_failure();
+        let mut slow = NodeStats::default();
+        slow.record_rtt(Duration::from_millis(500));
+        assert!(slow.rank() < failing.rank());
+        assert!(slow.rank() < NodeStats::default().rank());
+    }
+
+    #[test]
+    fn summary_of_probes() {
+        let rtts = [Duration::from_millis(10), Duration::from_millis(30), Duration::from_millis(20)];
+        assert_eq!(summarize(&rtts), Some((Duration::from_millis(10), Duration::from_millis(20), Duration::from_millis(30))));
+        assert_eq!(summarize(&[]), None);
+    }
+}
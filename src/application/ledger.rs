@@ -0,0 +1,161 @@
+use std::collections::HashMap;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, TcpStream};
+use std::path::PathBuf;
+use rand::Rng;
+use serde::{Serialize, Deserialize};
+
+use crate::throttle::throttle;
+
+// Bytes a peer may take from us before reciprocity is checked
+const FREE_BYTES: u64 = 4 * 1024 * 1024;
+// Received / sent ratio below which an indebted peer is choked
+const MIN_RATIO: f64 = 0.5;
+// Chance that a choked request is served anyway, so newcomers can build credit
+const OPTIMISTIC_UNCHOKE: f64 = 0.1;
+
+#[derive(Clone, Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct LedgerEntry {
+    pub sent: u64,
+    pub received: u64,
+    pub exchanges: u64,
+}
+
+impl LedgerEntry {
+    pub fn ratio(&self) -> f64 {
+        (self.received as f64 + 1.0) / (self.sent as f64 + 1.0)
+    }
+
+    pub fn debt(&self) -> u64 {
+        self.sent.saturating_sub(self.received)
+    }
+
+    pub fn in_good_standing(&self) -> bool {
+        self.debt() <= FREE_BYTES || self.ratio() >= MIN_RATIO
+    }
+}
+
+// The address bytes were really exchanged with. Unlike the node key a peer
+// claims in FROM it cannot be borrowed from a peer in good standing.
+pub fn remote_ip(stream: &TcpStream) -> IpAddr {
+    stream.peer_addr().map(|address| address.ip()).unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+}
+
+// Bytes exchanged with each peer, keyed by remote IP and optionally kept on
+// disk. Standing is passed on to the throttle, which is keyed the same way.
+pub struct Ledger {
+    pub entries: HashMap<IpAddr, LedgerEntry>,
+    path: Option<PathBuf>,
+    dirty: bool,
+}
+
+impl Ledger {
+    pub fn new() -> Ledger {
+        Ledger {entries: HashMap::new(), path: None, dirty: false}
+    }
+
+    pub fn load(path: PathBuf) -> Ledger {
+        let entries: HashMap<IpAddr, LedgerEntry> = fs::read(&path).ok()
+            .and_then(|contents| serde_json::from_slice(&contents).ok())
+            .unwrap_or_default();
+        Ledger {entries, path: Some(path), dirty: false}
+    }
+
+    pub fn save(&mut self) -> std::io::Result<()> {
+        if let Some(path) = &self.path {
+            let tmp = path.with_extension("tmp");
+            fs::write(&tmp, serde_json::to_vec(&self.entries)?)?;
+            fs::rename(&tmp, path)?;
+        }
+        self.dirty = false;
+        Ok(())
+    }
+
+    fn changed(&mut self, peer: IpAddr) {
+        self.dirty = true;
+        let standing = self.entry(peer).in_good_standing();
+        throttle().set_preferred(peer, standing);
+    }
+
+    // Save only if something changed since the last save
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        if self.dirty { self.save()?; }
+        Ok(())
+    }
+
+    pub fn entry(&mut self, peer: IpAddr) -> &mut LedgerEntry {
+        self.entries.entry(peer).or_default()
+    }
+
+    pub fn record_sent(&mut self, peer: IpAddr, bytes: usize) {
+        let entry = self.entry(peer);
+        entry.sent += bytes as u64;
+        entry.exchanges += 1;
+        self.changed(peer);
+    }
+
+    pub fn record_received(&mut self, peer: IpAddr, bytes: usize) {
+        let entry = self.entry(peer);
+        entry.received += bytes as u64;
+        entry.exchanges += 1;
+        self.changed(peer);
+    }
+
+    // Choking policy for a request from peer to send it bytes
+    pub fn should_serve(&mut self, peer: IpAddr) -> bool {
+        let standing = self.entry(peer).in_good_standing();
+        throttle().set_preferred(peer, standing);
+        standing || rand::thread_rng().gen::<f64>() < OPTIMISTIC_UNCHOKE
+    }
+}
+
+impl Default for Ledger {
+    fn default() -> Ledger {
+        Ledger::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(10, 0, 0, last))
+    }
+
+    #[test]
+    fn debt_within_free_bytes_is_fine() {
+        let entry = LedgerEntry {sent: FREE_BYTES, received: 0, exchanges: 1};
+        assert!(entry.in_good_standing());
+        let entry = LedgerEntry {sent: FREE_BYTES + 1, received: 0, exchanges: 2};
+        assert!(!entry.in_good_standing());
+        let entry = LedgerEntry {sent: 4 * FREE_BYTES, received: 2 * FREE_BYTES, exchanges: 2};
+        assert!(entry.in_good_standing());
+    }
+
+    #[test]
+    fn credit_follows_the_address() {
+        let mut ledger = Ledger::new();
+        ledger.record_received(ip(1), 10 * FREE_BYTES as usize);
+        ledger.record_sent(ip(2), 2 * FREE_BYTES as usize);
+
+        // Another address does not share its credit
+        assert!(ledger.entry(ip(1)).in_good_standing());
+        assert!(!ledger.entry(ip(2)).in_good_standing());
+        ledger.record_sent(ip(2), 1);
+        assert_eq!(ledger.entry(ip(2)).debt(), 2 * FREE_BYTES + 1);
+    }
+
+    #[test]
+    fn ledger_survives_a_restart() {
+        let path = std::env::temp_dir().join(format!("peer_stream_ledger_{}.json", std::process::id()));
+        let mut ledger = Ledger::load(path.clone());
+        ledger.record_sent(ip(3), 100);
+        ledger.record_received("::1".parse().unwrap(), 50);
+        ledger.flush().unwrap();
+
+        let loaded = Ledger::load(path.clone());
+        assert_eq!(loaded.entries, ledger.entries);
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,217 @@
+use std::error::Error;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::{Mutex, OnceLock, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use env_logger::filter::{Builder, Filter};
+use log::kv::{self, VisitSource};
+use log::{Log, Metadata, Record};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+/// How log lines are written.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// `time LEVEL target: message key=value ...`
+    #[default]
+    Text,
+    /// One JSON object per line, fields next to `msg`.
+    Json,
+}
+
+struct Logger {
+    spec: RwLock<(String, Filter)>,
+    out: Mutex<Box<dyn Write + Send>>,
+    format: LogFormat,
+}
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+/// Log to file, or stderr when there is none, keeping records that pass the
+/// `RUST_LOG` style filter spec, e.g. `info,peer_stream::client=debug`.
+pub fn init(spec: &str, file: Option<&PathBuf>, format: LogFormat) -> Result<(), Box<dyn Error>> {
+    validate_spec(spec)?;
+    let out: Box<dyn Write + Send> = match file {
+        Some(file) => Box::new(OpenOptions::new().create(true).append(true).open(file)
+            .map_err(|e| format!("Could not open log file {}: {}", file.display(), e))?),
+        None => Box::new(io::stderr()),
+    };
+
+    let filter = build(spec);
+    log::set_max_level(filter.filter());
+    let logger = LOGGER.get_or_init(|| Logger {spec: RwLock::new((spec.to_string(), filter)), out: Mutex::new(out), format});
+    log::set_logger(logger).map_err(|_| "A logger is already installed".into())
+}
+
+/// Replace the filter of the installed logger.
+pub fn set_filter(spec: &str) -> Result<(), String> {
+    validate_spec(spec)?;
+    let logger = LOGGER.get().ok_or("Logging is not initialised")?;
+    let filter = build(spec);
+    log::set_max_level(filter.filter());
+    *logger.spec.write().unwrap() = (spec.to_string(), filter);
+    Ok(())
+}
+
+/// The filter spec in use.
+pub fn filter() -> Option<String> {
+    LOGGER.get().map(|logger| logger.spec.read().unwrap().0.clone())
+}
+
+/// Short random id tying together the log lines of one request.
+pub fn request_id() -> String {
+    format!("{:08x}", rand::random::<u32>())
+}
+
+fn build(spec: &str) -> Filter {
+    Builder::new().parse(spec).build()
+}
+
+/// Check a spec of comma separated `level`, `module` or `module=level`
+/// directives with an optional `/regex` on the message.
+pub fn validate_spec(spec: &str) -> Result<(), String> {
+    let mut parts = spec.split('/');
+    let directives = parts.next().unwrap_or("");
+    if parts.nth(1).is_some() {
+        return Err(format!("Log filter {:?} has more than one '/'", spec));
+    }
+    for directive in directives.split(',').map(str::trim).filter(|directive| !directive.is_empty()) {
+        if let Some((module, level)) = directive.split_once('=') {
+            if !is_module_path(module.trim()) || level.contains('=') {
+                return Err(format!("Log filter directive {:?} is not module=level", directive));
+            }
+            if !level.trim().is_empty() && level.trim().parse::<log::LevelFilter>().is_err() {
+                return Err(format!("Unknown log level {:?}, expected off, error, warn, info, debug or trace", level.trim()));
+            }
+        } else if directive.parse::<log::LevelFilter>().is_err() && !is_module_path(directive) {
+            return Err(format!("Unknown log level {:?}, expected off, error, warn, info, debug or trace, or a module path", directive));
+        }
+    }
+    Ok(())
+}
+
+// Identifiers joined by `::`, such as `tiny_http` or `peer_stream::client`
+fn is_module_path(module: &str) -> bool {
+    module.split("::").all(|segment| {
+        let mut chars = segment.chars();
+        chars.next().is_some_and(|c| c.is_alphabetic() || c == '_') && chars.all(|c| c.is_alphanumeric() || c == '_')
+    })
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.spec.read().unwrap().1.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.spec.read().unwrap().1.matches(record) {
+            return;
+        }
+
+        let mut fields = Fields(Map::new());
+        let _ = record.key_values().visit(&mut fields);
+        let line = match self.format {
+            LogFormat::Text => {
+                let mut line = format!("{} {:<5} {}: {}", timestamp(), record.level(), record.target(), record.args());
+                for (key, value) in fields.0 {
+                    match value {
+                        Value::String(value) if value.contains(char::is_whitespace) => line += &format!(" {}={:?}", key, value),
+                        Value::String(value) => line += &format!(" {}={}", key, value),
+                        value => line += &format!(" {}={}", key, value),
+                    }
+                }
+                line
+            },
+            LogFormat::Json => {
+                let mut object = Map::new();
+                object.insert("ts".to_string(), Value::from(timestamp()));
+                object.insert("level".to_string(), Value::from(record.level().as_str()));
+                object.insert("target".to_string(), Value::from(record.target()));
+                object.insert("msg".to_string(), Value::from(record.args().to_string()));
+                object.extend(fields.0);
+                Value::Object(object).to_string()
+            },
+        };
+
+        let mut out = self.out.lock().unwrap();
+        let _ = writeln!(out, "{}", line);
+    }
+
+    fn flush(&self) {
+        let _ = self.out.lock().unwrap().flush();
+    }
+}
+
+// Key value pairs of a record, numbers kept as numbers
+struct Fields(Map<String, Value>);
+
+impl<'kvs> VisitSource<'kvs> for Fields {
+    fn visit_pair(&mut self, key: kv::Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+        let value = match (value.to_u64(), value.to_i64(), value.to_f64(), value.to_bool()) {
+            (Some(number), _, _, _) => Value::from(number),
+            (_, Some(number), _, _) => Value::from(number),
+            (_, _, Some(number), _) => Value::from(number),
+            (_, _, _, Some(flag)) => Value::from(flag),
+            _ => Value::from(value.to_string()),
+        };
+        self.0.insert(key.to_string(), value);
+        Ok(())
+    }
+}
+
+// UTC time as 2024-01-31T12:00:00.000Z
+fn timestamp() -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = now.as_secs();
+    let (days, rest) = (secs / 86400, secs % 86400);
+
+    // Howard Hinnant's civil_from_days
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z", year, month, day, rest / 3600, rest % 3600 / 60, rest % 60, now.subsec_millis())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valid_specs() {
+        for spec in ["", "info", "DEBUG", "peer_stream", "peer_stream::client", "warn,peer_stream::gateway=debug",
+                     "tiny_http=off", "peer_stream::client=", "info/lookup", " info , peer_stream=trace ",
+                     "tiny_http", "rustyline", "warn,tiny_http,rustyline=error"] {
+            assert_eq!(validate_spec(spec), Ok(()), "{}", spec);
+        }
+    }
+
+    #[test]
+    fn misspelled_levels_are_rejected() {
+        assert!(validate_spec("peer_stream=verbose").is_err());
+        assert!(validate_spec("tiny_http=infoo").is_err());
+    }
+
+    #[test]
+    fn only_module_paths_stand_alone() {
+        for spec in ["info!", "my module", "peer_stream:client", "::client", "peer_stream::", "1st", "warn,-"] {
+            assert!(validate_spec(spec).is_err(), "{}", spec);
+        }
+    }
+
+    #[test]
+    fn malformed_directives_are_rejected() {
+        assert!(validate_spec("=info").is_err());
+        assert!(validate_spec("a b=info").is_err());
+        assert!(validate_spec("peer_stream=info=debug").is_err());
+        assert!(validate_spec("info/a/b").is_err());
+    }
+}
@@ -0,0 +1,536 @@
+use std::error::Error;
+use std::io::{self, Read};
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+
+use crate::Client;
+use crate::client::Placement;
+use crate::key::Key;
+use crate::data::{Data, FileMetadata};
+
+pub const MANIFEST_MIME: &str = "application/x-p2p-manifest";
+pub const CHUNK_SIZE: usize = 256 * 1024;
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct ChunkRef {
+    pub key: u32,
+    pub offset: u64,
+    pub size: u64,
+}
+
+// Reed-Solomon layout: any data_shards of the shards rebuild the file
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+pub struct ErasureParams {
+    pub data_shards: usize,
+    pub parity_shards: usize,
+    pub shard_size: u64,
+}
+
+// Ordered chunk list for a file too large to store as a single value, or
+// the shard list of an erasure coded file
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct Manifest {
+    pub file_meta: FileMetadata,
+    pub chunks: Vec<ChunkRef>,
+
+    #[serde(default)]
+    pub erasure: Option<ErasureParams>,
+    #[serde(default)]
+    pub shards: Vec<ChunkRef>,
+    // SHA-256 of the whole file, hex. Unlike file_meta.hash it can be computed
+    // while the file streams to disk. Older manifests do not have it.
+    #[serde(default)]
+    pub sha256: Option<String>,
+}
+
+impl Manifest {
+    // Shards are stored on the nodes closest to the hash of the whole file
+    pub fn object_key(&self) -> Key {
+        Key {key: self.file_meta.hash}
+    }
+
+    // Manifests come from other nodes, so the layout is checked before any
+    // offset or shard index derived from it is used
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        let size = self.file_meta.size;
+        let Some(params) = self.erasure else {
+            let mut end: u64 = 0;
+            for (i, chunk) in self.chunks.iter().enumerate() {
+                if chunk.size > CHUNK_SIZE as u64 {
+                    return Err(format!("Chunk {} of the manifest is larger than {} bytes", i, CHUNK_SIZE).into());
+                }
+                if chunk.offset != end {
+                    return Err(format!("Chunk {} of the manifest does not start where the one before ends", i).into());
+                }
+                end = end.checked_add(chunk.size).ok_or("Manifest chunks overflow")?;
+            }
+            if end != size {
+                return Err(format!("Manifest chunks hold {} bytes, the file has {}", end, size).into());
+            }
+            return Ok(());
+        };
+
+        if params.shard_size == 0 || params.data_shards == 0 {
+            return Err("Manifest has empty erasure shards".into());
+        }
+        if self.shards.len() != params.data_shards.saturating_add(params.parity_shards) {
+            return Err(format!("Manifest lists {} shards, its erasure layout has {}", self.shards.len(), params.data_shards.saturating_add(params.parity_shards)).into());
+        }
+        let capacity = (params.data_shards as u64).checked_mul(params.shard_size).ok_or("Manifest shards overflow")?;
+        if size > capacity {
+            return Err(format!("Manifest shards hold {} bytes, the file has {}", capacity, size).into());
+        }
+        Ok(())
+    }
+}
+
+pub fn sha256_hex(contents: &[u8]) -> String {
+    hex::encode(Sha256::digest(contents))
+}
+
+// Hashes a file as it is written piece by piece, then checks it against
+// its manifest before the file is put into place
+pub struct FileCheck {
+    hasher: Sha256,
+    size: u64,
+}
+
+impl FileCheck {
+    pub fn new() -> FileCheck {
+        FileCheck {hasher: Sha256::new(), size: 0}
+    }
+
+    pub fn update(&mut self, piece: &[u8]) {
+        self.hasher.update(piece);
+        self.size += piece.len() as u64;
+    }
+
+    pub fn verify(self, manifest: &Manifest) -> Result<(), String> {
+        let file_meta = &manifest.file_meta;
+        if self.size != file_meta.size {
+            return Err(format!("Size mismatch for {}: expected {} got {}", file_meta.filename, file_meta.size, self.size));
+        }
+        let hash = hex::encode(self.hasher.finalize());
+        match &manifest.sha256 {
+            Some(expected) if *expected != hash => Err(format!("Hash mismatch for {}: expected {} got {}", file_meta.filename, expected, hash)),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Default for FileCheck {
+    fn default() -> FileCheck {
+        FileCheck::new()
+    }
+}
+
+pub fn is_manifest(data: &Data) -> bool {
+    data.file_meta.mime == MANIFEST_MIME
+}
+
+pub fn parse_manifest(data: &Data) -> Result<Manifest, Box<dyn Error>> {
+    let manifest: Manifest = serde_json::from_slice(&data.vec)?;
+    manifest.validate()?;
+    Ok(manifest)
+}
+
+// Store a file, splitting it into CHUNK_SIZE values behind a manifest when large
+pub fn put_file(client: &mut Client, data: Data, replicas: usize) -> Result<Key, Box<dyn Error>> {
+    let name = data.file_meta.filename.clone();
+    if data.vec.len() <= CHUNK_SIZE {
+        return Ok(client.put_data_with_replicas(name, data, replicas));
+    }
+
+    let mut chunks: Vec<ChunkRef> = Vec::new();
+    for (i, chunk) in data.vec.chunks(CHUNK_SIZE).enumerate() {
+        let chunk_data = Data::new("", chunk.to_vec());
+        let key = client.store_value("", &chunk_data, replicas);
+        chunks.push(ChunkRef {key: key.key, offset: (i * CHUNK_SIZE) as u64, size: chunk.len() as u64});
+    }
+
+    let manifest = Manifest {file_meta: data.file_meta.clone(), chunks, erasure: None, shards: Vec::new(), sha256: Some(sha256_hex(&data.vec))};
+    store_manifest(client, &data, manifest, replicas)
+}
+
+// Split a file into data_shards + parity_shards Reed-Solomon shards, each on
+// a different node near the object key, behind a manifest
+pub fn put_file_erasure(client: &mut Client, data: Data, data_shards: usize, parity_shards: usize) -> Result<Key, Box<dyn Error>> {
+    let coder = ReedSolomon::new(data_shards, parity_shards)?;
+    let shard_size = data.vec.len().div_ceil(data_shards).max(1);
+
+    let mut shards: Vec<Vec<u8>> = Vec::new();
+    for i in 0..data_shards + parity_shards {
+        let mut shard = vec![0; shard_size];
+        if i < data_shards {
+            let start = (i * shard_size).min(data.vec.len());
+            let end = (start + shard_size).min(data.vec.len());
+            shard[..end - start].copy_from_slice(&data.vec[start..end]);
+        }
+        shards.push(shard);
+    }
+    coder.encode(&mut shards)?;
+
+    let object_key = Key {key: data.file_meta.hash};
+    let mut refs: Vec<ChunkRef> = Vec::new();
+    for (i, shard) in shards.into_iter().enumerate() {
+        let shard_data = Data::new("", shard);
+        let key = client.store_value_at("", &shard_data, Placement {near: object_key, offset: i, replicas: 1});
+        refs.push(ChunkRef {key: key.key, offset: (i * shard_size) as u64, size: shard_size as u64});
+    }
+
+    let erasure = ErasureParams {data_shards, parity_shards, shard_size: shard_size as u64};
+    let manifest = Manifest {file_meta: data.file_meta.clone(), chunks: Vec::new(), erasure: Some(erasure), shards: refs, sha256: Some(sha256_hex(&data.vec))};
+    // The manifest has to survive as many lost nodes as the shards do
+    store_manifest(client, &data, manifest, parity_shards + 1)
+}
+
+// The data shards of an erasure coded file, rebuilt from the first data_shards
+// shards that arrive intact. Padding is left on the last one.
+fn rebuild_data_shards(client: &mut Client, manifest: &Manifest) -> Result<Vec<Vec<u8>>, Box<dyn Error>> {
+    let params = manifest.erasure.ok_or("Manifest is not erasure coded")?;
+    let coder = ReedSolomon::new(params.data_shards, params.parity_shards)?;
+
+    let mut shards: Vec<Option<Vec<u8>>> = vec![None; manifest.shards.len()];
+    let mut found = 0;
+    for (i, shard) in manifest.shards.iter().enumerate() {
+        if found >= params.data_shards { break; }
+
+        match fetch_shard(client, manifest, i) {
+            Ok(shard_data) => {
+                shards[i] = Some(shard_data);
+                found += 1;
+            },
+            _ => log::warn!("Shard {} of {} unavailable (key {})", i, manifest.file_meta.filename, shard.key),
+        }
+    }
+    if found < params.data_shards {
+        return Err(format!("Only {} of {} shards needed for {} are available", found, params.data_shards, manifest.file_meta.filename).into());
+    }
+    coder.reconstruct_data(&mut shards)?;
+
+    shards.into_iter().take(params.data_shards)
+        .map(|shard| shard.ok_or_else(|| "Shard missing after reconstruction".into()))
+        .collect()
+}
+
+fn fetch_shard(client: &mut Client, manifest: &Manifest, index: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+    let params = manifest.erasure.ok_or("Manifest is not erasure coded")?;
+    let shard = manifest.shards.get(index).ok_or("No such shard")?;
+    let max_value_bytes = client.config.storage.max_value_bytes;
+    if max_value_bytes > 0 && params.shard_size > max_value_bytes {
+        return Err(format!("Shards of {} are larger than the {} bytes a value may have", manifest.file_meta.filename, max_value_bytes).into());
+    }
+    let shard_data = client.fetch_value(Key {key: shard.key}, manifest.object_key(), false)?;
+    if shard_data.vec.len() as u64 != params.shard_size {
+        return Err(format!("Shard {} of {} has the wrong size", index, manifest.file_meta.filename).into());
+    }
+    Ok(shard_data.vec)
+}
+
+// Rebuild an erasure coded file from the first data_shards shards that arrive intact
+pub fn fetch_erasure(client: &mut Client, manifest: &Manifest) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut contents: Vec<u8> = rebuild_data_shards(client, manifest)?.concat();
+    contents.truncate(manifest.file_meta.size as usize);
+    manifest.file_meta.verify(&contents)?;
+    Ok(contents)
+}
+
+// Fetch and reassemble the whole file a manifest describes in memory
+pub fn fetch_file(client: &mut Client, manifest: &Manifest) -> Result<Vec<u8>, Box<dyn Error>> {
+    if manifest.erasure.is_some() {
+        return fetch_erasure(client, manifest);
+    }
+
+    // Grows with the chunks that arrive, not with the size the manifest claims
+    let mut contents: Vec<u8> = Vec::new();
+    for (i, chunk) in manifest.chunks.iter().enumerate() {
+        let chunk_data = client.fetch_value(Key {key: chunk.key}, Key {key: chunk.key}, false)?;
+        if chunk_data.vec.len() as u64 != chunk.size || contents.len() as u64 != chunk.offset {
+            return Err(format!("Chunk {} of {} does not fit the manifest", i, manifest.file_meta.filename).into());
+        }
+        contents.extend(chunk_data.vec);
+    }
+    manifest.file_meta.verify(&contents)?;
+    Ok(contents)
+}
+
+// Bytes start..end of a file, fetching only the chunks or shards that overlap them
+pub fn fetch_range(client: &mut Client, manifest: &Manifest, start: u64, end: u64) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut contents: Vec<u8> = Vec::with_capacity(end.saturating_sub(start).min(CHUNK_SIZE as u64) as usize);
+    RangeReader::new(client.clone(), manifest.clone(), start, end).read_to_end(&mut contents)?;
+    Ok(contents)
+}
+
+// Streams bytes start..end of a file, holding one chunk or shard at a time.
+// Pieces are not cached and are checked against their content key on arrival,
+// the whole file hash cannot be checked without holding all of it.
+pub struct RangeReader {
+    client: Client,
+    manifest: Manifest,
+    position: u64,
+    end: u64,
+    // The piece position falls in and its offset in the file
+    piece: Vec<u8>,
+    piece_offset: u64,
+    // Data shards rebuilt once one of them was missing
+    rebuilt: Option<Vec<Vec<u8>>>,
+}
+
+impl RangeReader {
+    pub fn new(client: Client, manifest: Manifest, start: u64, end: u64) -> RangeReader {
+        let end = end.min(manifest.file_meta.size);
+        RangeReader {client, manifest, position: start.min(end), end, piece: Vec::new(), piece_offset: 0, rebuilt: None}
+    }
+
+    // The chunk or data shard holding byte position, and its offset
+    fn fetch_piece(&mut self, position: u64) -> Result<(u64, Vec<u8>), Box<dyn Error>> {
+        let size = self.manifest.file_meta.size;
+        let Some(params) = self.manifest.erasure else {
+            let chunk = self.manifest.chunks.iter()
+                .find(|chunk| chunk.offset <= position && position < chunk.offset + chunk.size)
+                .ok_or_else(|| format!("No chunk of {} holds byte {}", self.manifest.file_meta.filename, position))?;
+            let chunk_data = self.client.fetch_value(Key {key: chunk.key}, Key {key: chunk.key}, false)?;
+            if chunk_data.vec.len() as u64 != chunk.size {
+                return Err(format!("Chunk at {} of {} has the wrong size", chunk.offset, self.manifest.file_meta.filename).into());
+            }
+            return Ok((chunk.offset, chunk_data.vec));
+        };
+
+        let index = position.checked_div(params.shard_size).ok_or("Manifest has empty erasure shards")? as usize;
+        if index >= params.data_shards {
+            return Err(format!("No shard of {} holds byte {}", self.manifest.file_meta.filename, position).into());
+        }
+        let offset = index as u64 * params.shard_size;
+        let mut shard = match &self.rebuilt {
+            Some(rebuilt) => rebuilt.get(index).cloned().ok_or("Shard missing after reconstruction")?,
+            None => match fetch_shard(&mut self.client, &self.manifest, index) {
+                Ok(shard) => shard,
+                Err(e) => {
+                    log::warn!("Shard {} of {} unavailable, rebuilding: {}", index, self.manifest.file_meta.filename, e);
+                    let rebuilt = rebuild_data_shards(&mut self.client, &self.manifest)?;
+                    let shard = rebuilt.get(index).cloned().ok_or("Shard missing after reconstruction")?;
+                    self.rebuilt = Some(rebuilt);
+                    shard
+                },
+            },
+        };
+        // Drop the padding of the last data shard
+        shard.truncate((size - offset) as usize);
+        Ok((offset, shard))
+    }
+}
+
+impl Read for RangeReader {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.end || out.is_empty() {
+            return Ok(0);
+        }
+        if self.position < self.piece_offset || self.position >= self.piece_offset + self.piece.len() as u64 {
+            let (offset, piece) = self.fetch_piece(self.position).map_err(|e| io::Error::other(e.to_string()))?;
+            if offset + (piece.len() as u64) <= self.position {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Manifest ends before the file does"));
+            }
+            self.piece = piece;
+            self.piece_offset = offset;
+        }
+
+        let from = (self.position - self.piece_offset) as usize;
+        let to = self.piece.len().min((self.end - self.piece_offset) as usize);
+        let count = out.len().min(to - from);
+        out[..count].copy_from_slice(&self.piece[from..from + count]);
+        self.position += count as u64;
+        Ok(count)
+    }
+}
+
+fn store_manifest(client: &mut Client, data: &Data, manifest: Manifest, replicas: usize) -> Result<Key, Box<dyn Error>> {
+    let name = data.file_meta.filename.clone();
+    let vec = serde_json::to_vec(&manifest)?;
+    let mut file_meta = FileMetadata::new(&name, &vec);
+    file_meta.mime = MANIFEST_MIME.to_string();
+    file_meta.modified = data.file_meta.modified;
+    file_meta.mode = data.file_meta.mode;
+
+    Ok(client.put_data_with_replicas(name, Data {vec, file_meta}, replicas))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Config;
+
+    // A node that knows nobody, so only values held locally can be fetched
+    fn client() -> Client {
+        let client = Client::with_config("127.0.0.1".to_string(), "0".to_string(), Config::default());
+        client.known_nodes.lock().unwrap().clear();
+        *client
+    }
+
+    fn hold(client: &Client, bytes: Vec<u8>) -> ChunkRef {
+        let data = Data::new("", bytes);
+        let key = Key::generate_hash_from_data(&data.vec);
+        let size = data.vec.len() as u64;
+        client.local_hash.lock().unwrap().insert(key, data);
+        ChunkRef {key: key.key, offset: 0, size}
+    }
+
+    fn contents(size: usize) -> Vec<u8> {
+        (0..size).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    fn chunked(client: &Client, contents: &[u8], chunk_size: usize) -> Manifest {
+        let chunks = contents.chunks(chunk_size).enumerate()
+            .map(|(i, chunk)| ChunkRef {offset: (i * chunk_size) as u64, ..hold(client, chunk.to_vec())})
+            .collect();
+        Manifest {file_meta: FileMetadata::new("file", contents), chunks, erasure: None, shards: Vec::new(), sha256: Some(sha256_hex(contents))}
+    }
+
+    fn erasure_coded(client: &Client, contents: &[u8], data_shards: usize, parity_shards: usize) -> Manifest {
+        let shard_size = contents.len().div_ceil(data_shards);
+        let mut shards: Vec<Vec<u8>> = (0..data_shards + parity_shards).map(|i| {
+            let mut shard = vec![0; shard_size];
+            let start = (i * shard_size).min(contents.len());
+            let end = (start + shard_size).min(contents.len());
+            if i < data_shards {
+                shard[..end - start].copy_from_slice(&contents[start..end]);
+            }
+            shard
+        }).collect();
+        ReedSolomon::new(data_shards, parity_shards).unwrap().encode(&mut shards).unwrap();
+
+        let refs = shards.into_iter().enumerate()
+            .map(|(i, shard)| ChunkRef {offset: (i * shard_size) as u64, ..hold(client, shard)})
+            .collect();
+        let erasure = ErasureParams {data_shards, parity_shards, shard_size: shard_size as u64};
+        Manifest {file_meta: FileMetadata::new("file", contents), chunks: Vec::new(), erasure: Some(erasure), shards: refs, sha256: Some(sha256_hex(contents))}
+    }
+
+    #[test]
+    fn range_across_chunks() {
+        let mut client = client();
+        let file = contents(1000);
+        let manifest = chunked(&client, &file, 300);
+
+        assert_eq!(fetch_range(&mut client, &manifest, 0, 1000).unwrap(), file);
+        assert_eq!(fetch_range(&mut client, &manifest, 250, 650).unwrap(), &file[250..650]);
+        assert_eq!(fetch_range(&mut client, &manifest, 999, 5000).unwrap(), &file[999..]);
+        assert!(fetch_range(&mut client, &manifest, 500, 500).unwrap().is_empty());
+    }
+
+    #[test]
+    fn reader_hands_out_small_reads() {
+        let client = client();
+        let file = contents(1000);
+        let manifest = chunked(&client, &file, 300);
+
+        let mut reader = RangeReader::new(client, manifest, 10, 990);
+        let mut buffer = [0; 7];
+        let mut read = Vec::new();
+        loop {
+            let count = reader.read(&mut buffer).unwrap();
+            if count == 0 { break; }
+            read.extend_from_slice(&buffer[..count]);
+        }
+        assert_eq!(read, &file[10..990]);
+    }
+
+    #[test]
+    fn range_reports_missing_chunk() {
+        let mut client = client();
+        let file = contents(1000);
+        let manifest = chunked(&client, &file, 300);
+        client.local_hash.lock().unwrap().remove(&Key {key: manifest.chunks[1].key});
+
+        assert_eq!(fetch_range(&mut client, &manifest, 0, 300).unwrap(), &file[..300]);
+        assert!(fetch_range(&mut client, &manifest, 0, 400).is_err());
+    }
+
+    #[test]
+    fn erasure_range_without_rebuild() {
+        let mut client = client();
+        let file = contents(1000);
+        let manifest = erasure_coded(&client, &file, 4, 2);
+        // Only the shards the range needs are there
+        for shard in &manifest.shards[2..] {
+            client.local_hash.lock().unwrap().remove(&Key {key: shard.key});
+        }
+        assert_eq!(fetch_range(&mut client, &manifest, 100, 480).unwrap(), &file[100..480]);
+        assert!(fetch_range(&mut client, &manifest, 600, 700).is_err());
+    }
+
+    #[test]
+    fn erasure_range_rebuilds_missing_shard() {
+        let mut client = client();
+        let file = contents(1000);
+        let manifest = erasure_coded(&client, &file, 4, 2);
+        client.local_hash.lock().unwrap().remove(&Key {key: manifest.shards[1].key});
+
+        assert_eq!(fetch_range(&mut client, &manifest, 200, 800).unwrap(), &file[200..800]);
+        assert_eq!(fetch_range(&mut client, &manifest, 900, 1000).unwrap(), &file[900..]);
+        assert_eq!(fetch_erasure(&mut client, &manifest).unwrap(), file);
+    }
+
+    fn as_data(manifest: &Manifest) -> Data {
+        let mut data = Data::new("file", serde_json::to_vec(manifest).unwrap());
+        data.file_meta.mime = MANIFEST_MIME.to_string();
+        data
+    }
+
+    #[test]
+    fn inconsistent_manifests_are_rejected() {
+        let client = client();
+        let file = contents(1000);
+
+        let chunks = chunked(&client, &file, 300);
+        assert!(parse_manifest(&as_data(&chunks)).is_ok());
+        let mut gap = chunks.clone();
+        gap.chunks[2].offset += 1;
+        assert!(parse_manifest(&as_data(&gap)).is_err());
+        let mut short = chunks.clone();
+        short.chunks.pop();
+        assert!(parse_manifest(&as_data(&short)).is_err());
+
+        let erasure = erasure_coded(&client, &file, 4, 2);
+        assert!(parse_manifest(&as_data(&erasure)).is_ok());
+        let mut empty_shards = erasure.clone();
+        empty_shards.erasure.as_mut().unwrap().shard_size = 0;
+        assert!(parse_manifest(&as_data(&empty_shards)).is_err());
+        let mut missing_shard = erasure.clone();
+        missing_shard.shards.pop();
+        assert!(parse_manifest(&as_data(&missing_shard)).is_err());
+        let mut too_large = erasure.clone();
+        too_large.file_meta.size = 1001;
+        assert!(parse_manifest(&as_data(&too_large)).is_err());
+    }
+
+    #[test]
+    fn erasure_range_past_the_data_shards_is_an_error() {
+        let mut client = client();
+        let file = contents(1000);
+        let mut manifest = erasure_coded(&client, &file, 4, 2);
+        // Bigger than the data shards hold, as only parse_manifest would catch
+        manifest.file_meta.size = 2000;
+        client.local_hash.lock().unwrap().remove(&Key {key: manifest.shards[0].key});
+
+        assert!(fetch_range(&mut client, &manifest, 1500, 1600).is_err());
+        manifest.erasure.as_mut().unwrap().shard_size = 0;
+        assert!(fetch_range(&mut client, &manifest, 0, 10).is_err());
+    }
+
+    #[test]
+    fn identical_shards_keep_their_own_placements() {
+        let mut client = client();
+        // Every data and parity shard of an all zero file is the same
+        let file = vec![0; 4000];
+        let key = put_file_erasure(&mut client, Data::new("zeros", file.clone()), 4, 2).unwrap();
+        let manifest = parse_manifest(&client.get_data(key).unwrap()).unwrap();
+        assert!(manifest.shards.iter().all(|shard| shard.key == manifest.shards[0].key));
+
+        let owned = client.owned.lock().unwrap();
+        let offsets: Vec<usize> = owned[&Key {key: manifest.shards[0].key}].iter().map(|placement| placement.offset).collect();
+        assert_eq!(offsets, vec![0, 1, 2, 3, 4, 5]);
+        drop(owned);
+        assert_eq!(fetch_erasure(&mut client, &manifest).unwrap(), file);
+    }
+}
@@ -0,0 +1,309 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use tiny_http::{Header, Method, Response, Server};
+
+use crate::Client;
+
+// Upper bounds of the histogram buckets, +Inf is implied
+const LATENCY_BUCKETS: [f64; 10] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+const HOP_BUCKETS: [f64; 8] = [1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 8.0, 10.0];
+
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+// Message types counted by name. The type comes from the peer, anything
+// else is counted as "other" so peers cannot grow the label set.
+const MESSAGE_TYPES: [&str; 26] = [
+    "CHAT", "CHAT_ACK", "CHOKED", "GOSSIP", "HAS", "HAVE", "INDEX_GET", "INDEX_GET_REPLY", "INDEX_INSERT",
+    "INIT", "INSERT", "LEAVE", "NOT_FOUND", "PEERS_I", "PEERS_I_GET", "PEERS_R", "PEERS_R_GET", "PING",
+    "PROVIDERS_GET_REPLY", "PROVIDER_GET", "RECORD_GET", "RECORD_GET_REPLY", "RECORD_PUT", "SUBSCRIBE",
+    "SUBSCRIBERS", "UNSUBSCRIBE",
+];
+
+fn type_label(type_of: &str) -> &'static str {
+    MESSAGE_TYPES.iter().find(|known| **known == type_of).copied().unwrap_or("other")
+}
+
+// Label values in the text format escape backslash, double quote and newline
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+struct Histogram {
+    bounds: &'static [f64],
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Histogram {
+        Histogram {bounds, counts: vec![0; bounds.len()], sum: 0.0, count: 0}
+    }
+
+    fn observe(&mut self, value: f64) {
+        for (bound, count) in self.bounds.iter().zip(self.counts.iter_mut()) {
+            if value <= *bound {
+                *count += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let separator = if labels.is_empty() { "" } else { "," };
+        for (bound, count) in self.bounds.iter().zip(&self.counts) {
+            let _ = writeln!(out, "{}_bucket{{{}{}le=\"{}\"}} {}", name, labels, separator, bound, count);
+        }
+        let _ = writeln!(out, "{}_bucket{{{}{}le=\"+Inf\"}} {}", name, labels, separator, self.count);
+        let labels = if labels.is_empty() { String::new() } else { format!("{{{}}}", labels) };
+        let _ = writeln!(out, "{}_sum{} {}", name, labels, self.sum);
+        let _ = writeln!(out, "{}_count{} {}", name, labels, self.count);
+    }
+}
+
+// Counters since the process started, shared by every connection thread
+pub struct Metrics {
+    // Message type to (messages, bytes)
+    sent: Mutex<BTreeMap<&'static str, (u64, u64)>>,
+    received: Mutex<BTreeMap<&'static str, (u64, u64)>>,
+    // Lookup kind to histogram
+    lookup_seconds: Mutex<BTreeMap<&'static str, Histogram>>,
+    lookup_hops: Mutex<Histogram>,
+    lookup_failures: Mutex<BTreeMap<&'static str, u64>>,
+    bytes_stored: AtomicU64,
+    bytes_served: AtomicU64,
+    inbound: AtomicU64,
+    outbound: AtomicU64,
+    open: AtomicI64,
+}
+
+static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+pub fn metrics() -> &'static Metrics {
+    METRICS.get_or_init(Metrics::new)
+}
+
+// Decrements the open connection gauge when the connection's reader stops
+pub struct OpenConnection;
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        metrics().open.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Metrics {
+    fn new() -> Metrics {
+        Metrics {
+            sent: Mutex::new(BTreeMap::new()),
+            received: Mutex::new(BTreeMap::new()),
+            lookup_seconds: Mutex::new(BTreeMap::new()),
+            lookup_hops: Mutex::new(Histogram::new(&HOP_BUCKETS)),
+            lookup_failures: Mutex::new(BTreeMap::new()),
+            bytes_stored: AtomicU64::new(0),
+            bytes_served: AtomicU64::new(0),
+            inbound: AtomicU64::new(0),
+            outbound: AtomicU64::new(0),
+            open: AtomicI64::new(0),
+        }
+    }
+
+    pub fn message_sent(&self, type_of: &str, bytes: usize) {
+        let mut sent = self.sent.lock().unwrap();
+        let entry = sent.entry(type_label(type_of)).or_default();
+        entry.0 += 1;
+        entry.1 += bytes as u64;
+    }
+
+    pub fn message_received(&self, type_of: &str, bytes: usize) {
+        let mut received = self.received.lock().unwrap();
+        let entry = received.entry(type_label(type_of)).or_default();
+        entry.0 += 1;
+        entry.1 += bytes as u64;
+    }
+
+    // kind is "node" or "value", hops is only known for node lookups
+    pub fn lookup(&self, kind: &'static str, elapsed: Duration, hops: Option<usize>, found: bool) {
+        self.lookup_seconds.lock().unwrap().entry(kind).or_insert_with(|| Histogram::new(&LATENCY_BUCKETS)).observe(elapsed.as_secs_f64());
+        if let Some(hops) = hops {
+            self.lookup_hops.lock().unwrap().observe(hops as f64);
+        }
+        if !found {
+            *self.lookup_failures.lock().unwrap().entry(kind).or_default() += 1;
+        }
+    }
+
+    pub fn stored(&self, bytes: usize) {
+        self.bytes_stored.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn served(&self, bytes: usize) {
+        self.bytes_served.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn connection_opened(&self, inbound: bool) -> Option<OpenConnection> {
+        if !inbound {
+            self.outbound.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        self.inbound.fetch_add(1, Ordering::Relaxed);
+        self.open.fetch_add(1, Ordering::Relaxed);
+        Some(OpenConnection)
+    }
+
+    // Prometheus text exposition, gauges read from client at the time of the scrape
+    pub fn render(&self, client: &Client) -> String {
+        let mut out = String::new();
+
+        header(&mut out, "peer_stream_messages_sent_total", "counter", "Messages written to peers by type.");
+        for (type_of, (count, _)) in self.sent.lock().unwrap().iter() {
+            let _ = writeln!(out, "peer_stream_messages_sent_total{{type=\"{}\"}} {}", escape_label(type_of), count);
+        }
+        header(&mut out, "peer_stream_message_bytes_sent_total", "counter", "Bytes written to peers by message type.");
+        for (type_of, (_, bytes)) in self.sent.lock().unwrap().iter() {
+            let _ = writeln!(out, "peer_stream_message_bytes_sent_total{{type=\"{}\"}} {}", escape_label(type_of), bytes);
+        }
+        header(&mut out, "peer_stream_messages_received_total", "counter", "Messages read from peers by type.");
+        for (type_of, (count, _)) in self.received.lock().unwrap().iter() {
+            let _ = writeln!(out, "peer_stream_messages_received_total{{type=\"{}\"}} {}", escape_label(type_of), count);
+        }
+        header(&mut out, "peer_stream_message_bytes_received_total", "counter", "Bytes read from peers by message type.");
+        for (type_of, (_, bytes)) in self.received.lock().unwrap().iter() {
+            let _ = writeln!(out, "peer_stream_message_bytes_received_total{{type=\"{}\"}} {}", escape_label(type_of), bytes);
+        }
+
+        header(&mut out, "peer_stream_lookup_duration_seconds", "histogram", "Time taken by node and value lookups.");
+        for (kind, histogram) in self.lookup_seconds.lock().unwrap().iter() {
+            histogram.render(&mut out, "peer_stream_lookup_duration_seconds", &format!("kind=\"{}\"", escape_label(kind)));
+        }
+        header(&mut out, "peer_stream_lookup_hops", "histogram", "Rounds of queries a node lookup needed.");
+        self.lookup_hops.lock().unwrap().render(&mut out, "peer_stream_lookup_hops", "");
+        header(&mut out, "peer_stream_lookup_failures_total", "counter", "Lookups that found nothing.");
+        for (kind, count) in self.lookup_failures.lock().unwrap().iter() {
+            let _ = writeln!(out, "peer_stream_lookup_failures_total{{kind=\"{}\"}} {}", escape_label(kind), count);
+        }
+
+        header(&mut out, "peer_stream_stored_bytes_total", "counter", "Bytes of values accepted from other nodes.");
+        let _ = writeln!(out, "peer_stream_stored_bytes_total {}", self.bytes_stored.load(Ordering::Relaxed));
+        header(&mut out, "peer_stream_served_bytes_total", "counter", "Bytes of values served to other nodes.");
+        let _ = writeln!(out, "peer_stream_served_bytes_total {}", self.bytes_served.load(Ordering::Relaxed));
+        let (values, bytes) = client.local_hash.lock().unwrap().values().fold((0, 0), |(values, bytes), data| (values + 1, bytes + data.vec.len()));
+        header(&mut out, "peer_stream_held_values", "gauge", "Values currently held.");
+        let _ = writeln!(out, "peer_stream_held_values {}", values);
+        header(&mut out, "peer_stream_held_bytes", "gauge", "Bytes of values currently held.");
+        let _ = writeln!(out, "peer_stream_held_bytes {}", bytes);
+
+        header(&mut out, "peer_stream_connections_total", "counter", "Connections opened by direction.");
+        let _ = writeln!(out, "peer_stream_connections_total{{direction=\"inbound\"}} {}", self.inbound.load(Ordering::Relaxed));
+        let _ = writeln!(out, "peer_stream_connections_total{{direction=\"outbound\"}} {}", self.outbound.load(Ordering::Relaxed));
+        header(&mut out, "peer_stream_connections_open", "gauge", "Inbound connections currently open.");
+        let _ = writeln!(out, "peer_stream_connections_open {}", self.open.load(Ordering::Relaxed));
+
+        header(&mut out, "peer_stream_routing_table_nodes", "gauge", "Nodes in the routing table.");
+        let _ = writeln!(out, "peer_stream_routing_table_nodes {}", client.known_nodes.lock().unwrap().len());
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+// Serve GET /metrics on address until the node shuts down
+pub fn serve(client: Client, address: &str) -> Result<JoinHandle<()>, Box<dyn Error>> {
+    let server = Server::http(address).map_err(|e| format!("Could not listen on {}: {}", address, e))?;
+    log::info!("Metrics on http://{}/metrics", address);
+
+    Ok(thread::spawn(move || {
+        while !client.stopping.load(Ordering::SeqCst) {
+            let request = match server.recv_timeout(POLL_INTERVAL) {
+                Ok(Some(request)) => request,
+                Ok(None) => continue,
+                Err(e) => {
+                    log::warn!("Metrics endpoint stopped: {}", e);
+                    break;
+                },
+            };
+
+            let path = request.url().split('?').next().unwrap_or("");
+            let response = match (request.method(), path) {
+                (Method::Get, "/metrics") => Response::from_string(metrics().render(&client))
+                    .with_header(Header::from_bytes("Content-Type", "text/plain; version=0.0.4").unwrap()),
+                (_, "/metrics") => Response::from_string("Method not allowed").with_status_code(405),
+                _ => Response::from_string("Not found").with_status_code(404),
+            };
+            let _ = request.respond(response);
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_are_cumulative() {
+        let mut histogram = Histogram::new(&[1.0, 5.0, 10.0]);
+        for value in [0.5, 1.0, 3.0, 20.0] {
+            histogram.observe(value);
+        }
+        assert_eq!(histogram.counts, vec![2, 3, 3]);
+        assert_eq!(histogram.count, 4);
+        assert_eq!(histogram.sum, 24.5);
+    }
+
+    #[test]
+    fn render_with_labels() {
+        let mut histogram = Histogram::new(&[0.1, 1.0]);
+        histogram.observe(0.5);
+        let mut out = String::new();
+        histogram.render(&mut out, "lookup_seconds", "kind=\"node\"");
+        assert_eq!(out, concat!(
+            "lookup_seconds_bucket{kind=\"node\",le=\"0.1\"} 0\n",
+            "lookup_seconds_bucket{kind=\"node\",le=\"1\"} 1\n",
+            "lookup_seconds_bucket{kind=\"node\",le=\"+Inf\"} 1\n",
+            "lookup_seconds_sum{kind=\"node\"} 0.5\n",
+            "lookup_seconds_count{kind=\"node\"} 1\n",
+        ));
+    }
+
+    #[test]
+    fn render_without_labels() {
+        let mut histogram = Histogram::new(&[2.0]);
+        histogram.observe(3.0);
+        let mut out = String::new();
+        histogram.render(&mut out, "hops", "");
+        assert_eq!(out, "hops_bucket{le=\"2\"} 0\nhops_bucket{le=\"+Inf\"} 1\nhops_sum 3\nhops_count 1\n");
+    }
+
+    #[test]
+    fn peer_types_are_bounded_and_escaped() {
+        let metrics = Metrics::new();
+        metrics.message_received("PING", 10);
+        metrics.message_received("EVIL\"} 1\nforged_metric{a=\"", 20);
+        for i in 0..100 {
+            metrics.message_received(&format!("RANDOM_{}", i), 1);
+        }
+        assert_eq!(metrics.received.lock().unwrap().len(), 2);
+
+        let client = Client::with_config("127.0.0.1".to_string(), "0".to_string(), crate::Config::default());
+        let out = metrics.render(&client);
+        assert!(out.contains("peer_stream_messages_received_total{type=\"PING\"} 1\n"));
+        assert!(out.contains("peer_stream_messages_received_total{type=\"other\"} 101\n"));
+        assert!(out.contains("peer_stream_message_bytes_received_total{type=\"other\"} 120\n"));
+        assert!(!out.contains("forged_metric"));
+    }
+
+    #[test]
+    fn label_values_are_escaped() {
+        assert_eq!(escape_label("plain"), "plain");
+        assert_eq!(escape_label("a\\b\"c\nd"), "a\\\\b\\\"c\\nd");
+    }
+}
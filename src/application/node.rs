@@ -0,0 +1,309 @@
+use std::error::Error;
+use std::net::{Ipv4Addr, SocketAddr, TcpListener};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::Ordering;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use crossbeam::channel::Receiver;
+
+use crate::Client;
+use crate::client::PeerRecord;
+use crate::config::Config;
+use crate::chat::ChatMessage;
+use crate::data::Data;
+use crate::key::Key;
+use crate::ledger::Ledger;
+use crate::manifest;
+use crate::pubsub::TopicMessage;
+use crate::record;
+use crate::throttle::{throttle, Limits};
+
+/// Port the bootnode listens on.
+pub const BOOTNODE_PORT: u16 = 12345;
+const LEDGER_FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Configures and starts a [`Node`].
+#[derive(Clone, Debug)]
+pub struct NodeBuilder {
+    config: Config,
+}
+
+impl NodeBuilder {
+    pub fn new() -> NodeBuilder {
+        NodeBuilder {config: Config::default()}
+    }
+
+    /// Start from a loaded [`Config`], the other setters adjust it.
+    pub fn config(mut self, config: Config) -> NodeBuilder {
+        self.config = config;
+        self
+    }
+
+    /// Run as the bootnode with the well known key 1, on [`BOOTNODE_PORT`] unless a port is set.
+    pub fn bootnode(mut self, bootnode: bool) -> NodeBuilder {
+        self.config.network.bootnode = bootnode;
+        self
+    }
+
+    /// Nodes to join the network through, none to start a network of its own.
+    pub fn bootstraps(mut self, bootstraps: Vec<String>) -> NodeBuilder {
+        self.config.network.bootstraps = bootstraps;
+        self
+    }
+
+    /// Port to listen on, 0 picks a free one.
+    pub fn port(mut self, port: u16) -> NodeBuilder {
+        let ip = self.config.network.listen.rsplit_once(':').map(|(ip, _)| ip.to_string()).unwrap_or(Ipv4Addr::LOCALHOST.to_string());
+        self.config.network.listen = format!("{}:{}", ip, port);
+        self
+    }
+
+    /// Keep the node key, signing key and peer ledgers in this directory between runs.
+    pub fn data_dir(mut self, data_dir: impl Into<PathBuf>) -> NodeBuilder {
+        self.config.storage.data_dir = Some(data_dir.into());
+        self
+    }
+
+    /// Upload and download limits in bytes per second, shared by every node in the process.
+    pub fn limits(mut self, limits: Limits) -> NodeBuilder {
+        self.config.limits = limits;
+        self
+    }
+
+    /// How often the replicas of stored keys are checked and repaired, in whole seconds,
+    /// `None` to never check.
+    pub fn audit_interval(mut self, audit_interval: Option<Duration>) -> NodeBuilder {
+        self.config.timeouts.audit_secs = audit_interval.map(|interval| interval.as_secs().max(1)).unwrap_or(0);
+        self
+    }
+
+    /// Validate the config, bind the listener, join the network and start serving requests.
+    pub fn start(self) -> Result<Node, Box<dyn Error>> {
+        let config = self.config;
+        config.validate()?;
+        throttle().set_limits(config.limits);
+
+        let mut listen: SocketAddr = config.network.listen.parse()?;
+        if config.network.bootnode && listen.port() == 0 {
+            listen.set_port(BOOTNODE_PORT);
+        }
+        let listener = TcpListener::bind(listen)?;
+        let advertise: SocketAddr = match &config.network.advertise {
+            Some(advertise) => advertise.parse()?,
+            None => listener.local_addr()?,
+        };
+
+        let data_dir = config.storage.data_dir.clone();
+        let audit_interval = config.timeouts.audit();
+        let mut client = Client::with_config(advertise.ip().to_string(), advertise.port().to_string(), config);
+        if let Some(data_dir) = &data_dir {
+            std::fs::create_dir_all(data_dir)?;
+            if !client.config.network.bootnode {
+                client.key = Key::load_or_create(&data_dir.join("node_key"))?;
+            }
+            client.signing_key = record::load_or_create_signing_key(&data_dir.join("signing_key"))?;
+            client.ledger = Arc::new(Mutex::new(Ledger::load(data_dir.join("ledger.json"))));
+
+            let flush_client = client.clone();
+            thread::spawn(move || {
+                while !flush_client.stopped.load(Ordering::SeqCst) {
+                    thread::sleep(LEDGER_FLUSH_INTERVAL);
+                    if let Err(e) = flush_client.ledger.lock().unwrap().flush() {
+                        log::warn!("Could not save ledger: {}", e);
+                    }
+                }
+            });
+        }
+
+        // Join before serving so the node is usable once start returns
+        if let Err(e) = client.get_peer_record() {
+            client.stopped.store(true, Ordering::SeqCst);
+            return Err(e);
+        }
+
+        let mut threads = Vec::new();
+        let mut run_client = client.clone();
+        threads.push(thread::spawn(move || run_client.run(listener)));
+        let mut poll_client = client.clone();
+        threads.push(thread::spawn(move || poll_client.poll()));
+
+        if let Some(audit_interval) = audit_interval {
+            let mut audit_client = client.clone();
+            thread::spawn(move || {
+                while !audit_client.stopping.load(Ordering::SeqCst) {
+                    thread::sleep(audit_interval);
+                    for (key, live, repaired) in audit_client.audit_replicas() {
+                        if repaired > 0 {
+                            log::info!("Repaired {} replicas of {}, {} live", repaired, key.key, live);
+                        }
+                    }
+                }
+            });
+        }
+
+        Ok(Node {client: *client, threads})
+    }
+}
+
+impl Default for NodeBuilder {
+    fn default() -> NodeBuilder {
+        NodeBuilder::new()
+    }
+}
+
+/// A running peer. Cheap operations borrow the node, network operations
+/// block the calling thread until they finish.
+pub struct Node {
+    client: Client,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl Node {
+    pub fn builder() -> NodeBuilder {
+        NodeBuilder::new()
+    }
+
+    /// Key of this node in the DHT.
+    pub fn key(&self) -> Key {
+        self.client.key
+    }
+
+    /// Address other nodes reach this one on.
+    pub fn address(&self) -> &str {
+        &self.client.host
+    }
+
+    /// Hex encoded public key that signs this node's mutable records.
+    pub fn public_key(&self) -> String {
+        record::public_key_hex(&self.client.signing_key)
+    }
+
+    /// Handle to the underlying client for operations not wrapped here.
+    pub fn client(&self) -> Client {
+        self.client.clone()
+    }
+
+    /// Store contents under name with the default replication, returning its content key.
+    /// Large contents are chunked behind a manifest.
+    pub fn put(&self, name: &str, contents: Vec<u8>) -> Result<Key, Box<dyn Error>> {
+        let mut client = self.client();
+        let replication = client.replication();
+        manifest::put_file(&mut client, Data::new(name, contents), replication)
+    }
+
+    /// Fetch the contents stored under key, reassembling chunked files.
+    pub fn get(&self, key: Key) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut client = self.client();
+        let data = client.get_data(key)?;
+        if manifest::is_manifest(&data) {
+            return manifest::fetch_file(&mut client, &manifest::parse_manifest(&data)?);
+        }
+        Ok(data.vec)
+    }
+
+    /// Address of the node with key, looked up through the network if not known.
+    pub fn find_node(&self, key: Key) -> Result<PeerRecord, Box<dyn Error>> {
+        if let Some(address) = self.client.known_nodes.lock().unwrap().get(&key) {
+            return Ok((key, address.clone()));
+        }
+        self.client().find_node(key).ok_or_else(|| format!("Node {} not found", key.key).into())
+    }
+
+    /// Round trip time of one probe to the node with key.
+    pub fn ping(&self, key: Key) -> Result<Duration, Box<dyn Error>> {
+        self.find_node(key)?;
+        self.client().ping(key)
+    }
+
+    /// Call handler for every message published to topic from now on, returning
+    /// how many other subscribers were reached. Fails if already subscribed.
+    pub fn subscribe<F>(&self, topic: &str, handler: F) -> Result<usize, Box<dyn Error>> where F: Fn(&TopicMessage) + Send + Sync + 'static {
+        self.client().subscribe(topic, handler)
+    }
+
+    /// Stop calling the handler registered for topic. Fails if not subscribed.
+    pub fn unsubscribe(&self, topic: &str) -> Result<(), Box<dyn Error>> {
+        self.client().unsubscribe(topic)
+    }
+
+    /// Gossip payload to the subscribers of topic, returning how many peers it was handed to.
+    pub fn publish(&self, topic: &str, payload: &str) -> Result<usize, Box<dyn Error>> {
+        match self.client().publish(topic, payload) {
+            0 => Err(format!("No peers reachable for topic {}", topic).into()),
+            sent => Ok(sent),
+        }
+    }
+
+    /// Send a direct message, returning once the recipient acknowledged it.
+    pub fn send(&self, to: Key, text: &str) -> Result<ChatMessage, Box<dyn Error>> {
+        self.client().send_message(to, text)
+    }
+
+    /// Direct messages received from now on.
+    pub fn messages(&self) -> Receiver<ChatMessage> {
+        self.client.inbox.lock().unwrap().listen()
+    }
+
+    /// Hand off stored data, tell peers we are leaving and stop serving.
+    /// Fails if the node is already shutting down or its ledger could not be saved.
+    pub fn shutdown(&self) -> Result<(), Box<dyn Error>> {
+        self.client().shutdown()
+    }
+
+    /// Block until the node has shut down.
+    pub fn wait(self) {
+        for thread in self.threads {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpStream;
+
+    fn start(bootstraps: Vec<String>) -> Node {
+        Node::builder().bootstraps(bootstraps).audit_interval(None).start().unwrap()
+    }
+
+    #[test]
+    fn put_on_one_node_get_on_another() {
+        let first = start(Vec::new());
+        let second = start(vec![first.address().to_string()]);
+        assert_eq!(second.find_node(first.key()).unwrap(), (first.key(), first.address().to_string()));
+
+        let key = second.put("hello.txt", b"hello world".to_vec()).unwrap();
+        assert_eq!(first.get(key).unwrap(), b"hello world");
+        let large: Vec<u8> = (0..manifest::CHUNK_SIZE * 2 + 10).map(|i| (i % 251) as u8).collect();
+        let key = first.put("large.bin", large.clone()).unwrap();
+        assert_eq!(second.get(key).unwrap(), large);
+
+        second.shutdown().unwrap();
+        first.shutdown().unwrap();
+        second.wait();
+        first.wait();
+    }
+
+    #[test]
+    fn shutdown_stops_serving_once() {
+        let node = start(Vec::new());
+        let address = node.address().to_string();
+        let lone = node.publish("news", "nobody listens");
+        assert!(lone.is_err());
+
+        node.shutdown().unwrap();
+        assert!(node.shutdown().is_err());
+        node.wait();
+        assert!(TcpStream::connect(&address).is_err());
+    }
+
+    #[test]
+    fn start_fails_without_a_reachable_bootstrap() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        drop(listener);
+        assert!(Node::builder().bootstraps(vec![address]).start().is_err());
+    }
+}
@@ -0,0 +1,210 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use rand::Rng;
+use rand::seq::SliceRandom;
+use serde::{Serialize, Deserialize};
+
+use crate::key::Key;
+use crate::data::{Data, FileMetadata};
+use crate::client::PeerRecord;
+
+pub const GOSSIP_MIME: &str = "application/x-p2p-gossip";
+// Mesh peers each message is forwarded to
+pub const FANOUT: usize = 6;
+// Forwarding stops after this many hops even if a message is still new somewhere
+const MAX_HOPS: u32 = 16;
+// Message ids remembered for deduplication
+const SEEN_CAPACITY: usize = 4096;
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct TopicMessage {
+    pub id: u64,
+    pub topic: String,
+    // Node key of the publisher
+    pub origin: u32,
+    pub payload: String,
+    pub hops: u32,
+}
+
+// Every node announces the named values it uploads on this topic
+pub const UPLOADS_TOPIC: &str = "uploads";
+
+pub type Handler = Arc<dyn Fn(&TopicMessage) + Send + Sync>;
+
+// Nodes closest to this key keep the member list of the topic
+pub fn topic_key(topic: &str) -> Key {
+    Key::generate_hash_from_data(format!("topic:{}", topic).as_bytes())
+}
+
+// Payload announcing an upload on UPLOADS_TOPIC
+pub fn upload_notice(key: Key, file_meta: &FileMetadata) -> String {
+    serde_json::json!({"key": key.key, "name": file_meta.filename, "mime": file_meta.mime}).to_string()
+}
+
+impl TopicMessage {
+    pub fn new(topic: &str, origin: Key, payload: &str) -> TopicMessage {
+        TopicMessage {
+            id: rand::thread_rng().gen::<u64>(),
+            topic: topic.to_string(),
+            origin: origin.key,
+            payload: payload.to_string(),
+            hops: 0,
+        }
+    }
+
+    pub fn expired(&self) -> bool {
+        self.hops >= MAX_HOPS
+    }
+
+    // Gossip travels as the JSON body of a Data
+    pub fn to_data(&self) -> Data {
+        let mut data = Data::new(&self.topic, serde_json::to_vec(self).unwrap());
+        data.file_meta.mime = GOSSIP_MIME.to_string();
+        data
+    }
+
+    pub fn from_data(data: &Data) -> Option<TopicMessage> {
+        if data.file_meta.mime != GOSSIP_MIME { return None; }
+        serde_json::from_slice(&data.vec).ok()
+    }
+}
+
+// Local subscriptions, the mesh of peers known per topic and recently seen message ids
+#[derive(Default)]
+pub struct PubSub {
+    handlers: HashMap<String, Vec<Handler>>,
+    mesh: HashMap<String, HashMap<Key, String>>,
+    seen: HashSet<u64>,
+    seen_order: VecDeque<u64>,
+}
+
+impl PubSub {
+    pub fn new() -> PubSub {
+        PubSub::default()
+    }
+
+    // False when already subscribed, the first handler is kept
+    pub fn subscribe(&mut self, topic: &str, handler: Handler) -> bool {
+        if self.is_subscribed(topic) {
+            return false;
+        }
+        self.handlers.insert(topic.to_string(), vec![handler]);
+        true
+    }
+
+    // False when not subscribed
+    pub fn unsubscribe(&mut self, topic: &str) -> bool {
+        self.handlers.remove(topic).is_some()
+    }
+
+    pub fn is_subscribed(&self, topic: &str) -> bool {
+        self.handlers.contains_key(topic)
+    }
+
+    pub fn topics(&self) -> Vec<String> {
+        let mut topics: Vec<String> = self.handlers.keys().cloned().collect();
+        topics.sort();
+        topics
+    }
+
+    pub fn add_peer(&mut self, topic: &str, peer: PeerRecord) {
+        self.mesh.entry(topic.to_string()).or_default().insert(peer.0, peer.1);
+    }
+
+    pub fn remove_peer(&mut self, topic: &str, peer: Key) {
+        if let Some(members) = self.mesh.get_mut(topic) {
+            members.remove(&peer);
+            if members.is_empty() {
+                self.mesh.remove(topic);
+            }
+        }
+    }
+
+    // Drop a node that left the network from every topic
+    pub fn remove_node(&mut self, peer: Key) {
+        for members in self.mesh.values_mut() {
+            members.remove(&peer);
+        }
+        self.mesh.retain(|_, members| !members.is_empty());
+    }
+
+    pub fn peers(&self, topic: &str) -> Vec<PeerRecord> {
+        self.mesh.get(topic)
+            .map(|members| members.iter().map(|(key, address)| (*key, address.clone())).collect())
+            .unwrap_or_default()
+    }
+
+    // Up to FANOUT random mesh peers, leaving out the ones in exclude
+    pub fn fanout(&self, topic: &str, exclude: &[Key]) -> Vec<PeerRecord> {
+        let mut peers: Vec<PeerRecord> = self.peers(topic).into_iter().filter(|peer| !exclude.contains(&peer.0)).collect();
+        peers.shuffle(&mut rand::thread_rng());
+        peers.truncate(FANOUT);
+        peers
+    }
+
+    // True the first time an id is seen
+    pub fn mark_seen(&mut self, id: u64) -> bool {
+        if !self.seen.insert(id) {
+            return false;
+        }
+        self.seen_order.push_back(id);
+        if self.seen_order.len() > SEEN_CAPACITY {
+            if let Some(oldest) = self.seen_order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        true
+    }
+
+    pub fn handlers(&self, topic: &str) -> Vec<Handler> {
+        self.handlers.get(topic).cloned().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handler() -> Handler {
+        Arc::new(|_: &TopicMessage| {})
+    }
+
+    #[test]
+    fn subscribe_once_per_topic() {
+        let mut pubsub = PubSub::new();
+        assert!(pubsub.subscribe("news", handler()));
+        assert!(!pubsub.subscribe("news", handler()));
+        assert_eq!(pubsub.handlers("news").len(), 1);
+        assert!(pubsub.unsubscribe("news"));
+        assert!(!pubsub.unsubscribe("news"));
+        assert!(!pubsub.is_subscribed("news"));
+    }
+
+    #[test]
+    fn seen_ids_are_forgotten_oldest_first() {
+        let mut pubsub = PubSub::new();
+        assert!(pubsub.mark_seen(1));
+        assert!(!pubsub.mark_seen(1));
+        for id in 2..=SEEN_CAPACITY as u64 + 1 {
+            pubsub.mark_seen(id);
+        }
+        assert!(pubsub.mark_seen(1));
+        assert!(!pubsub.mark_seen(SEEN_CAPACITY as u64 + 1));
+    }
+
+    #[test]
+    fn upload_notice_names_the_key() {
+        let file_meta = FileMetadata::new("my notes.txt", b"hello");
+        let notice: serde_json::Value = serde_json::from_str(&upload_notice(Key {key: 42}, &file_meta)).unwrap();
+        assert_eq!(notice["key"], 42);
+        assert_eq!(notice["name"], "my notes.txt");
+        assert_eq!(notice["mime"], file_meta.mime.as_str());
+    }
+
+    #[test]
+    fn gossip_round_trips_through_data() {
+        let gossip = TopicMessage::new("news", Key {key: 7}, "hello");
+        assert_eq!(TopicMessage::from_data(&gossip.to_data()), Some(gossip));
+        assert_eq!(TopicMessage::from_data(&Data::new("news", b"{}".to_vec())), None);
+    }
+}
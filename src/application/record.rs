@@ -0,0 +1,157 @@
+use std::fs;
+use std::path::Path;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::{Serialize, Deserialize};
+
+use crate::key::Key;
+use crate::data::Data;
+
+pub const RECORD_MIME: &str = "application/x-p2p-record";
+
+// Updatable pointer to a content key, owned by whoever holds the signing key
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct MutableRecord {
+    // Hex encoded ed25519 public key of the publisher
+    pub public_key: String,
+    pub name: String,
+    pub seq: u64,
+    pub value: u32,
+    pub signature: String,
+}
+
+// DHT key of the record a publisher keeps under name
+pub fn record_key(public_key: &str, name: &str) -> Key {
+    Key::generate_hash_from_data(format!("{}/{}", public_key.to_lowercase(), name).as_bytes())
+}
+
+pub fn public_key_hex(signing_key: &SigningKey) -> String {
+    hex::encode(signing_key.verifying_key().to_bytes())
+}
+
+// Reuse the signing key stored at path, creating one on first start
+pub fn load_or_create_signing_key(path: &Path) -> std::io::Result<SigningKey> {
+    if let Ok(contents) = fs::read_to_string(path) {
+        if let Ok(bytes) = hex::decode(contents.trim()) {
+            if let Ok(secret) = <[u8; 32]>::try_from(bytes.as_slice()) {
+                return Ok(SigningKey::from_bytes(&secret));
+            }
+        }
+    }
+    let signing_key = SigningKey::generate(&mut rand::rngs::OsRng);
+    fs::write(path, hex::encode(signing_key.to_bytes()))?;
+    Ok(signing_key)
+}
+
+impl MutableRecord {
+    pub fn signed(signing_key: &SigningKey, name: &str, seq: u64, value: Key) -> MutableRecord {
+        let mut record = MutableRecord {
+            public_key: public_key_hex(signing_key),
+            name: name.to_string(),
+            seq,
+            value: value.key,
+            signature: "".to_string(),
+        };
+        record.signature = hex::encode(signing_key.sign(&record.signing_bytes()).to_bytes());
+        record
+    }
+
+    pub fn key(&self) -> Key {
+        record_key(&self.public_key, &self.name)
+    }
+
+    // Whether this is the record publisher keeps under name. Keys are only
+    // 32 bits, so a matching key alone does not say who the record belongs to.
+    pub fn is_owned_by(&self, public_key: &str, name: &str) -> bool {
+        self.public_key.eq_ignore_ascii_case(public_key) && self.name == name
+    }
+
+    fn signing_bytes(&self) -> Vec<u8> {
+        format!("P2P-RECORD\n{}\n{}\n{}\n{}", self.public_key.to_lowercase(), self.name, self.seq, self.value).into_bytes()
+    }
+
+    pub fn verify(&self) -> bool {
+        let public_key = match hex::decode(&self.public_key).ok().and_then(|bytes| <[u8; 32]>::try_from(bytes.as_slice()).ok()) {
+            Some(bytes) => bytes,
+            None => return false,
+        };
+        let signature = match hex::decode(&self.signature).ok().and_then(|bytes| <[u8; 64]>::try_from(bytes.as_slice()).ok()) {
+            Some(bytes) => Signature::from_bytes(&bytes),
+            None => return false,
+        };
+        match VerifyingKey::from_bytes(&public_key) {
+            Ok(verifying_key) => verifying_key.verify(&self.signing_bytes(), &signature).is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    // Records travel as the JSON body of a Data
+    pub fn to_data(&self) -> Data {
+        let mut data = Data::new(&self.name, serde_json::to_vec(self).unwrap());
+        data.file_meta.mime = RECORD_MIME.to_string();
+        data
+    }
+
+    pub fn from_data(data: &Data) -> Option<MutableRecord> {
+        if data.file_meta.mime != RECORD_MIME { return None; }
+        serde_json::from_slice(&data.vec).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signing_key(seed: u8) -> SigningKey {
+        SigningKey::from_bytes(&[seed; 32])
+    }
+
+    #[test]
+    fn signed_record_verifies() {
+        let record = MutableRecord::signed(&signing_key(1), "site", 3, Key {key: 42});
+        assert!(record.verify());
+        assert_eq!(record.key(), record_key(&record.public_key, "site"));
+    }
+
+    #[test]
+    fn tampered_record_fails_verification() {
+        let record = MutableRecord::signed(&signing_key(1), "site", 3, Key {key: 42});
+
+        let mut value = record.clone();
+        value.value = 43;
+        assert!(!value.verify());
+
+        let mut seq = record.clone();
+        seq.seq = 4;
+        assert!(!seq.verify());
+
+        // Re-signed by someone else but claiming the original publisher
+        let mut forged = MutableRecord::signed(&signing_key(2), "site", 4, Key {key: 43});
+        forged.public_key = record.public_key.clone();
+        assert!(!forged.verify());
+    }
+
+    #[test]
+    fn public_key_case_does_not_matter() {
+        let mut record = MutableRecord::signed(&signing_key(1), "site", 1, Key {key: 42});
+        let public_key = record.public_key.clone();
+        record.public_key = public_key.to_uppercase();
+        assert!(record.verify());
+        assert!(record.is_owned_by(&public_key, "site"));
+        assert_eq!(record.key(), record_key(&public_key, "site"));
+    }
+
+    #[test]
+    fn ownership_needs_publisher_and_name() {
+        let record = MutableRecord::signed(&signing_key(1), "site", 1, Key {key: 42});
+        let other = public_key_hex(&signing_key(2));
+        assert!(!record.is_owned_by(&other, "site"));
+        assert!(!record.is_owned_by(&record.public_key, "blog"));
+    }
+
+    #[test]
+    fn record_round_trips_through_data() {
+        let record = MutableRecord::signed(&signing_key(1), "site", 1, Key {key: 42});
+        assert_eq!(MutableRecord::from_data(&record.to_data()), Some(record));
+        assert_eq!(MutableRecord::from_data(&Data::new("site", b"{}".to_vec())), None);
+    }
+}
@@ -0,0 +1,325 @@
+use std::error::Error;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use serde_json::{json, Value};
+
+use crate::Client;
+use crate::data::Data;
+use crate::gateway::node_json;
+use crate::key::Key;
+use crate::{latency, logging, manifest, tree};
+
+// JSON-RPC 2.0 error codes
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const SERVER_ERROR: i64 = -32000;
+
+// How often the accept loop checks whether the node is shutting down
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+const PING_PROBES: usize = 4;
+// Each probe may wait the full ping timeout, so a call cannot ask for many
+const MAX_PING_PROBES: u64 = 100;
+
+pub const METHODS: [&str; 6] = ["insert", "upload", "get", "list", "providers", "ping"];
+
+#[derive(Debug)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> RpcError {
+        RpcError {code, message: message.into()}
+    }
+}
+
+impl From<Box<dyn Error>> for RpcError {
+    fn from(e: Box<dyn Error>) -> RpcError {
+        RpcError::new(SERVER_ERROR, e.to_string())
+    }
+}
+
+// Answer newline delimited JSON-RPC requests on a Unix socket at path until the node shuts down
+pub fn serve(client: Client, path: &Path) -> Result<JoinHandle<()>, Box<dyn Error>> {
+    // A socket left behind by a node that died is replaced, a live one is not
+    if path.exists() {
+        if UnixStream::connect(path).is_ok() {
+            return Err(format!("A node is already listening on {}", path.display()).into());
+        }
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path).map_err(|e| format!("Could not listen on {}: {}", path.display(), e))?;
+    // Anyone who can connect can read and write files as this user
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    listener.set_nonblocking(true)?;
+    log::info!("RPC socket on {}", path.display());
+
+    let path = path.to_path_buf();
+    Ok(thread::spawn(move || {
+        while !client.stopping.load(Ordering::SeqCst) {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    let mut connection_client = client.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = handle_connection(&mut connection_client, stream) {
+                            log::warn!("RPC connection closed: {}", e);
+                        }
+                    });
+                },
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => thread::sleep(POLL_INTERVAL),
+                Err(e) => {
+                    log::warn!("RPC socket stopped: {}", e);
+                    break;
+                },
+            }
+        }
+        let _ = fs::remove_file(&path);
+    }))
+}
+
+fn handle_connection(client: &mut Client, stream: UnixStream) -> Result<(), Box<dyn Error>> {
+    stream.set_nonblocking(false)?;
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = handle_request(client, &line);
+        writeln!(writer, "{}", response)?;
+    }
+    Ok(())
+}
+
+fn handle_request(client: &mut Client, line: &str) -> Value {
+    let request: Value = match serde_json::from_str(line) {
+        Ok(request) => request,
+        Err(e) => return error_response(Value::Null, RpcError::new(PARSE_ERROR, e.to_string())),
+    };
+    let id = request.get("id").cloned().unwrap_or(Value::Null);
+    let method = match request.get("method").and_then(Value::as_str) {
+        Some(method) => method,
+        None => return error_response(id, RpcError::new(INVALID_REQUEST, "Missing method")),
+    };
+    let params = request.get("params").cloned().unwrap_or(json!({}));
+    // The caller's id when it sent one, so its logs and ours line up
+    let request_id = match &id {
+        Value::Null => logging::request_id(),
+        Value::String(id) => id.clone(),
+        id => id.to_string(),
+    };
+    log::info!(request_id = request_id.as_str(), method = method; "RPC request");
+
+    match dispatch(client, method, &params) {
+        Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+        Err(e) => {
+            log::warn!(request_id = request_id.as_str(), method = method, code = e.code; "RPC failed: {}", e.message);
+            error_response(id, e)
+        },
+    }
+}
+
+fn error_response(id: Value, e: RpcError) -> Value {
+    json!({"jsonrpc": "2.0", "id": id, "error": {"code": e.code, "message": e.message}})
+}
+
+fn dispatch(client: &mut Client, method: &str, params: &Value) -> Result<Value, RpcError> {
+    match method {
+        "insert" => {
+            let name = string_param(params, "name")?;
+            let data = string_param(params, "data")?;
+            let key = client.put_data(name.clone(), Data::new(&name, data.into_bytes()));
+            Ok(json!({"key": key.key}))
+        },
+        "upload" => {
+            let path = PathBuf::from(string_param(params, "path")?);
+            let replicas = optional_param(params, "replicas")?.map(|replicas| replicas as usize).unwrap_or(client.replication());
+            let erasure = match (optional_param(params, "data_shards")?, optional_param(params, "parity_shards")?) {
+                (Some(data_shards), Some(parity_shards)) => Some((data_shards as usize, parity_shards as usize)),
+                (None, None) => None,
+                _ => return Err(RpcError::new(INVALID_PARAMS, "data_shards and parity_shards go together")),
+            };
+            let key = tree::upload_path(client, &path, replicas, erasure)?;
+            Ok(json!({"key": key.key, "path": path}))
+        },
+        "get" => {
+            let key = Key {key: key_param(params)?};
+            let data = client.get_data(key)?;
+            let file_meta = match manifest::is_manifest(&data) {
+                true => manifest::parse_manifest(&data)?.file_meta,
+                false => data.file_meta.clone(),
+            };
+
+            // Written to output when given, otherwise returned hex encoded
+            match params.get("output").and_then(Value::as_str) {
+                Some(output) => {
+                    tree::download_path(client, key, &data, Path::new(output))?;
+                    Ok(json!({"key": key.key, "file_meta": file_meta, "output": output}))
+                },
+                None if tree::is_tree(&data) => Err(RpcError::new(INVALID_PARAMS, "Directories need an output path")),
+                None => {
+                    let contents = match manifest::is_manifest(&data) {
+                        true => manifest::fetch_file(client, &manifest::parse_manifest(&data)?)?,
+                        false => data.vec,
+                    };
+                    Ok(json!({"key": key.key, "file_meta": file_meta, "data_hex": hex::encode(contents)}))
+                },
+            }
+        },
+        "list" => {
+            let nodes: Vec<Value> = client.routing_table().iter().map(|((key, address), stats)| node_json(client, *key, address, stats)).collect();
+            let mut stored: Vec<Value> = client.local_hash.lock().unwrap().iter()
+                .map(|(key, data)| json!({"key": key.key, "file_meta": data.file_meta}))
+                .collect();
+            stored.sort_by_key(|entry| entry["key"].as_u64());
+            Ok(json!({"key": client.key.key, "address": client.host, "nodes": nodes, "stored": stored}))
+        },
+        "providers" => {
+            client.get_providers();
+            let mut providers: Vec<Value> = client.providers.lock().unwrap().iter()
+                .map(|(name, key)| json!({"name": name, "key": key.key}))
+                .collect();
+            providers.sort_by(|a, b| a["name"].as_str().cmp(&b["name"].as_str()));
+            Ok(json!(providers))
+        },
+        "ping" => {
+            let key = Key {key: key_param(params)?};
+            let count = optional_param(params, "count")?.unwrap_or(PING_PROBES as u64);
+            if count > MAX_PING_PROBES {
+                return Err(RpcError::new(INVALID_PARAMS, format!("count must be at most {}", MAX_PING_PROBES)));
+            }
+            let count = count as usize;
+            if !client.known_nodes.lock().unwrap().contains_key(&key) {
+                return Err(RpcError::new(SERVER_ERROR, format!("Node {} is not known", key.key)));
+            }
+
+            let mut rtts = Vec::new();
+            for _ in 0..count {
+                if let Ok(rtt) = client.ping(key) {
+                    rtts.push(rtt);
+                }
+            }
+            let ms = |rtt: Duration| rtt.as_secs_f64() * 1000.0;
+            let summary = latency::summarize(&rtts).map(|(min, avg, max)| json!({"min_ms": ms(min), "avg_ms": ms(avg), "max_ms": ms(max)}));
+            Ok(json!({
+                "key": key.key,
+                "sent": count,
+                "lost": count - rtts.len(),
+                "rtts_ms": rtts.iter().map(|rtt| ms(*rtt)).collect::<Vec<f64>>(),
+                "summary": summary,
+            }))
+        },
+        _ => Err(RpcError::new(METHOD_NOT_FOUND, format!("Unknown method {}, expected one of {}", method, METHODS.join(", ")))),
+    }
+}
+
+fn string_param(params: &Value, name: &str) -> Result<String, RpcError> {
+    params.get(name).and_then(Value::as_str).map(str::to_string)
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, format!("Missing string parameter {}", name)))
+}
+
+fn optional_param(params: &Value, name: &str) -> Result<Option<u64>, RpcError> {
+    match params.get(name) {
+        None | Some(Value::Null) => Ok(None),
+        Some(value) => value.as_u64().map(Some).ok_or_else(|| RpcError::new(INVALID_PARAMS, format!("{} must be an unsigned integer", name))),
+    }
+}
+
+fn key_param(params: &Value) -> Result<u32, RpcError> {
+    params.get("key").and_then(Value::as_u64).and_then(|key| u32::try_from(key).ok())
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, "Missing key parameter"))
+}
+
+// Send one request to the node listening on path and wait for its result
+pub fn call(path: &Path, method: &str, params: Value) -> Result<Value, Box<dyn Error>> {
+    let mut stream = UnixStream::connect(path).map_err(|e| format!("Could not connect to {}: {}", path.display(), e))?;
+    let request = json!({"jsonrpc": "2.0", "id": 1, "method": method, "params": params});
+    writeln!(stream, "{}", request)?;
+
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    let response: Value = serde_json::from_str(&line)?;
+    match response.get("error") {
+        Some(error) => Err(error["message"].as_str().unwrap_or("RPC failed").to_string().into()),
+        None => Ok(response["result"].clone()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Config;
+
+    fn client() -> Client {
+        let client = Client::with_config("127.0.0.1".to_string(), "0".to_string(), Config::default());
+        client.known_nodes.lock().unwrap().clear();
+        *client
+    }
+
+    fn error_code(response: &Value) -> i64 {
+        response["error"]["code"].as_i64().unwrap()
+    }
+
+    #[test]
+    fn malformed_requests_get_error_responses() {
+        let mut client = client();
+        let response = handle_request(&mut client, "{\"method\": ");
+        assert_eq!(error_code(&response), PARSE_ERROR);
+        assert_eq!(response["id"], Value::Null);
+
+        let response = handle_request(&mut client, r#"{"jsonrpc": "2.0", "id": 7, "params": {}}"#);
+        assert_eq!(error_code(&response), INVALID_REQUEST);
+        assert_eq!(response["id"], 7);
+
+        let response = handle_request(&mut client, r#"{"jsonrpc": "2.0", "id": "a", "method": "delete_everything"}"#);
+        assert_eq!(error_code(&response), METHOD_NOT_FOUND);
+        assert_eq!(response["id"], "a");
+    }
+
+    #[test]
+    fn dispatch_checks_params() {
+        let mut client = client();
+        assert_eq!(dispatch(&mut client, "insert", &json!({"name": "a.txt"})).unwrap_err().code, INVALID_PARAMS);
+        assert_eq!(dispatch(&mut client, "get", &json!({"key": -1})).unwrap_err().code, INVALID_PARAMS);
+        assert_eq!(dispatch(&mut client, "upload", &json!({"path": "/tmp", "data_shards": 4})).unwrap_err().code, INVALID_PARAMS);
+        assert_eq!(dispatch(&mut client, "ping", &json!({"key": 5, "count": MAX_PING_PROBES + 1})).unwrap_err().code, INVALID_PARAMS);
+        assert_eq!(dispatch(&mut client, "ping", &json!({"key": 5, "count": 1})).unwrap_err().code, SERVER_ERROR);
+    }
+
+    #[test]
+    fn inserted_values_are_listed_and_returned() {
+        let mut client = client();
+        let response = handle_request(&mut client, r#"{"jsonrpc": "2.0", "id": 1, "method": "insert", "params": {"name": "a.txt", "data": "hello"}}"#);
+        let key = response["result"]["key"].as_u64().unwrap();
+
+        let list = dispatch(&mut client, "list", &json!({})).unwrap();
+        assert_eq!(list["stored"][0]["key"], key);
+        let got = dispatch(&mut client, "get", &json!({"key": key})).unwrap();
+        assert_eq!(got["data_hex"], hex::encode("hello"));
+    }
+
+    #[test]
+    fn socket_is_private_to_the_user() {
+        let client = client();
+        let path = std::env::temp_dir().join(format!("peer_stream_rpc_{}.sock", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let server = serve(client.clone(), &path).unwrap();
+
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert_eq!(call(&path, "list", json!({})).unwrap()["key"], client.key.key);
+        assert!(call(&path, "nothing", json!({})).unwrap_err().to_string().starts_with("Unknown method"));
+
+        client.stopping.store(true, Ordering::SeqCst);
+        server.join().unwrap();
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,295 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use serde::{Serialize, Deserialize};
+
+use crate::Client;
+use crate::key::Key;
+use crate::data::{Data, FileMetadata, PERMISSION_BITS};
+use crate::{download, manifest};
+
+pub const TREE_MIME: &str = "application/x-p2p-tree";
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct TreeEntry {
+    pub name: String,
+    pub mode: u32,
+    pub key: u32,
+    pub is_dir: bool,
+}
+
+// Content addressed directory listing, stored as the JSON body of a Data
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct Tree {
+    pub entries: Vec<TreeEntry>,
+}
+
+pub fn is_tree(data: &Data) -> bool {
+    data.file_meta.mime == TREE_MIME
+}
+
+pub fn parse_tree(data: &Data) -> Result<Tree, Box<dyn Error>> {
+    Ok(serde_json::from_slice(&data.vec)?)
+}
+
+fn entry_name(path: &Path) -> String {
+    path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default()
+}
+
+fn mode_of(meta: &fs::Metadata) -> u32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        meta.permissions().mode() & PERMISSION_BITS
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = meta;
+        0
+    }
+}
+
+// Upload every file below a directory and return the key of its tree object.
+// With erasure each file is erasure coded, tree objects are still replicated.
+pub fn upload_directory(client: &mut Client, path: &Path, replicas: usize, erasure: Option<(usize, usize)>) -> Result<Key, Box<dyn Error>> {
+    let mut uploaded: HashMap<Key, Key> = HashMap::new();
+    upload_tree(client, path, replicas, erasure, &mut uploaded)
+}
+
+fn upload_tree(client: &mut Client, path: &Path, replicas: usize, erasure: Option<(usize, usize)>, uploaded: &mut HashMap<Key, Key>) -> Result<Key, Box<dyn Error>> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(path)?.map(|entry| entry.map(|e| e.path())).collect::<Result<_, _>>()?;
+    paths.sort();
+
+    let mut tree = Tree {entries: Vec::new()};
+    for child in paths {
+        let meta = fs::symlink_metadata(&child)?;
+        let name = entry_name(&child);
+
+        if meta.is_dir() {
+            let key = upload_tree(client, &child, replicas, erasure, uploaded)?;
+            tree.entries.push(TreeEntry {name, mode: mode_of(&meta), key: key.key, is_dir: true});
+        } else if meta.is_file() {
+            let buffer = fs::read(&child)?;
+            let content_key = Key::generate_hash_from_data(&buffer);
+
+            // Identical files anywhere in the tree are stored once
+            let key = match uploaded.get(&content_key) {
+                Some(key) => *key,
+                None => {
+                    let file_meta = FileMetadata::from_file(&name, &buffer, &meta);
+                    let key = put_file(client, Data {vec: buffer, file_meta}, replicas, erasure)?;
+                    uploaded.insert(content_key, key);
+                    key
+                }
+            };
+            tree.entries.push(TreeEntry {name, mode: mode_of(&meta), key: key.key, is_dir: false});
+        }
+    }
+
+    let name = entry_name(&path.canonicalize()?);
+    let vec = serde_json::to_vec(&tree)?;
+    let mut file_meta = FileMetadata::from_file(&name, &vec, &fs::metadata(path)?);
+    file_meta.mime = TREE_MIME.to_string();
+
+    let key = Key::generate_hash_from_data(&vec);
+    client.put_data_with_replicas(name, Data {vec, file_meta}, replicas);
+    Ok(key)
+}
+
+// Upload a file or a whole directory. Files are either replicated or split
+// into <data>+<parity> erasure coded shards.
+pub fn upload_path(client: &mut Client, path: &Path, replicas: usize, erasure: Option<(usize, usize)>) -> Result<Key, Box<dyn Error>> {
+    if path.is_dir() {
+        return upload_directory(client, path, replicas, erasure);
+    }
+
+    let vec = fs::read(path)?;
+    let filename = path.to_string_lossy();
+    let file_meta = FileMetadata::from_file(&filename, &vec, &fs::metadata(path)?);
+    put_file(client, Data {vec, file_meta}, replicas, erasure)
+}
+
+fn put_file(client: &mut Client, data: Data, replicas: usize, erasure: Option<(usize, usize)>) -> Result<Key, Box<dyn Error>> {
+    match erasure {
+        Some((data_shards, parity_shards)) => manifest::put_file_erasure(client, data, data_shards, parity_shards),
+        None => manifest::put_file(client, data, replicas),
+    }
+}
+
+// Save the value fetched for key to dest, restoring directories
+pub fn download_path(client: &mut Client, key: Key, data: &Data, dest: &Path) -> Result<(), Box<dyn Error>> {
+    if is_tree(data) {
+        restore_tree(client, data, dest)
+    } else {
+        download::save_file(client, key, data, dest)
+    }
+}
+
+// Recreate a downloaded tree object under dest
+pub fn restore_tree(client: &mut Client, data: &Data, dest: &Path) -> Result<(), Box<dyn Error>> {
+    let mut restored: HashMap<Key, PathBuf> = HashMap::new();
+    restore(client, data, dest, &mut restored)
+}
+
+fn restore(client: &mut Client, data: &Data, dest: &Path, restored: &mut HashMap<Key, PathBuf>) -> Result<(), Box<dyn Error>> {
+    let tree = parse_tree(data)?;
+    fs::create_dir_all(dest)?;
+
+    for entry in tree.entries {
+        if entry.name.is_empty() || entry.name == "." || entry.name == ".." || entry.name.contains('/') || entry.name.contains('\\') {
+            return Err(format!("Invalid tree entry name {:?}", entry.name).into());
+        }
+        let path = dest.join(&entry.name);
+        let key = Key {key: entry.key};
+
+        if entry.is_dir {
+            let child = client.get_data(key)?;
+            if !is_tree(&child) {
+                return Err(format!("Entry {} is not a directory", entry.name).into());
+            }
+            restore(client, &child, &path, restored)?;
+        } else if let Some(existing) = restored.get(&key) {
+            fs::copy(existing, &path)?;
+            File::open(&path)?.set_modified(fs::metadata(existing)?.modified()?)?;
+        } else {
+            let child = client.get_data(key)?;
+            download::save_file(client, key, &child, &path)?;
+            restored.insert(key, path.clone());
+        }
+        set_mode(&path, entry.mode)?;
+    }
+    data.file_meta.apply(&File::open(dest)?)?;
+    Ok(())
+}
+
+fn set_mode(path: &Path, mode: u32) -> std::io::Result<()> {
+    #[cfg(unix)]
+    if mode & PERMISSION_BITS > 0 {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(mode & PERMISSION_BITS))?;
+    }
+    #[cfg(not(unix))]
+    let _ = (path, mode);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Config;
+
+    // An empty directory under the system temp dir, unique to this test
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("peer_stream_tree_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn client() -> Client {
+        let client = Client::with_config("127.0.0.1".to_string(), "0".to_string(), Config::default());
+        client.known_nodes.lock().unwrap().clear();
+        *client
+    }
+
+    #[test]
+    fn directory_files_are_erasure_coded() {
+        let mut client = client();
+        let source = scratch("erasure_source");
+        fs::create_dir_all(source.join("sub")).unwrap();
+        fs::write(source.join("a.txt"), b"first file").unwrap();
+        fs::write(source.join("sub").join("b.bin"), vec![7; 5000]).unwrap();
+
+        let key = upload_path(&mut client, &source, 3, Some((2, 1))).unwrap();
+        let root = client.get_data(key).unwrap();
+        let tree = parse_tree(&root).unwrap();
+        let file = client.get_data(Key {key: tree.entries.iter().find(|entry| entry.name == "a.txt").unwrap().key}).unwrap();
+        let manifest = manifest::parse_manifest(&file).unwrap();
+        assert_eq!(manifest.erasure.map(|params| (params.data_shards, params.parity_shards)), Some((2, 1)));
+
+        let dest = scratch("erasure_dest");
+        download_path(&mut client, key, &root, &dest.join("copy")).unwrap();
+        assert_eq!(fs::read(dest.join("copy").join("a.txt")).unwrap(), b"first file");
+        assert_eq!(fs::read(dest.join("copy").join("sub").join("b.bin")).unwrap(), vec![7; 5000]);
+
+        let _ = fs::remove_dir_all(&source);
+        let _ = fs::remove_dir_all(&dest);
+    }
+
+    // A tree value with a single file entry, the file held by client
+    fn tree_with_entry(client: &Client, name: &str) -> Data {
+        let file = Data::new("escape.txt", b"outside".to_vec());
+        let key = Key::generate_hash_from_data(&file.vec);
+        client.local_hash.lock().unwrap().insert(key, file);
+
+        let tree = Tree {entries: vec![TreeEntry {name: name.to_string(), mode: 0o644, key: key.key, is_dir: false}]};
+        let mut data = Data::new("evil", serde_json::to_vec(&tree).unwrap());
+        data.file_meta.mime = TREE_MIME.to_string();
+        data
+    }
+
+    #[test]
+    fn restore_rejects_escaping_names() {
+        let mut client = client();
+        let dest = scratch("escape");
+        for name in ["..", ".", "", "/tmp/escape.txt", "sub/escape.txt", "..\\escape.txt"] {
+            let tree = tree_with_entry(&client, name);
+            let error = restore_tree(&mut client, &tree, &dest.join("copy")).unwrap_err().to_string();
+            assert!(error.starts_with("Invalid tree entry name"), "{}: {}", name, error);
+        }
+        assert!(!dest.join("escape.txt").exists());
+        assert_eq!(fs::read_dir(dest.join("copy")).unwrap().count(), 0);
+        let _ = fs::remove_dir_all(&dest);
+    }
+
+    #[test]
+    fn identical_files_are_stored_once() {
+        let mut client = client();
+        let source = scratch("dedup_source");
+        fs::create_dir_all(source.join("sub")).unwrap();
+        fs::write(source.join("a.txt"), b"same bytes").unwrap();
+        fs::write(source.join("sub").join("b.txt"), b"same bytes").unwrap();
+
+        let key = upload_path(&mut client, &source, 1, None).unwrap();
+        // The file once, plus a tree for each directory
+        assert_eq!(client.local_hash.lock().unwrap().len(), 3);
+        let root = client.get_data(key).unwrap();
+        let tree = parse_tree(&root).unwrap();
+        let sub = parse_tree(&client.get_data(Key {key: tree.entries[1].key}).unwrap()).unwrap();
+        assert_eq!(tree.entries[0].key, sub.entries[0].key);
+
+        let dest = scratch("dedup_dest");
+        download_path(&mut client, key, &root, &dest.join("copy")).unwrap();
+        assert_eq!(fs::read(dest.join("copy").join("a.txt")).unwrap(), b"same bytes");
+        assert_eq!(fs::read(dest.join("copy").join("sub").join("b.txt")).unwrap(), b"same bytes");
+        let _ = fs::remove_dir_all(&source);
+        let _ = fs::remove_dir_all(&dest);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn modes_round_trip_without_special_bits() {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o7777;
+        let mut client = client();
+        let source = scratch("mode_source");
+        fs::create_dir_all(source.join("private")).unwrap();
+        fs::write(source.join("run.sh"), b"#!/bin/sh").unwrap();
+        fs::write(source.join("private").join("notes.txt"), b"notes").unwrap();
+        fs::set_permissions(source.join("run.sh"), fs::Permissions::from_mode(0o4750)).unwrap();
+        fs::set_permissions(source.join("private").join("notes.txt"), fs::Permissions::from_mode(0o600)).unwrap();
+        fs::set_permissions(source.join("private"), fs::Permissions::from_mode(0o700)).unwrap();
+
+        let key = upload_path(&mut client, &source, 1, None).unwrap();
+        let root = client.get_data(key).unwrap();
+        let dest = scratch("mode_dest").join("copy");
+        download_path(&mut client, key, &root, &dest).unwrap();
+
+        assert_eq!(mode(&dest.join("run.sh")), 0o750);
+        assert_eq!(mode(&dest.join("private")), 0o700);
+        assert_eq!(mode(&dest.join("private").join("notes.txt")), 0o600);
+        let _ = fs::remove_dir_all(&source);
+        let _ = fs::remove_dir_all(dest.parent().unwrap());
+    }
+}
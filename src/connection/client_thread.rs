@@ -1,21 +1,21 @@
 use std::net::TcpStream;
 use std::io::Write;
 use std::io::BufReader;
+use std::sync::atomic::Ordering;
 
-use crate::client::{PeerRecord, parse_peer_record, create_empty_peer_record, DhtType, parse_providers};
-use crate::client::empty_data;
+use crate::client::create_empty_peer_record;
 use crate::connection::{Message, ConnectionRef, DHTMessage};
-use crate::data::Data;
+use crate::metrics::metrics;
+use crate::throttle::{throttle, SLICE_SIZE};
 
-use log::info;
 
 pub fn read_thread(stream: TcpStream, connection: ConnectionRef) -> Result<(), &'static str> {
     let mut reader = BufReader::new(stream);
+    let _open = metrics().connection_opened(true);
     loop {
         let msg = Message::read_message(&mut reader)?;
 
-        let output = format!("RECIEVED: {} FROM- ({},{}) TO- ({},{})",  msg.type_of, msg.from.0.key, msg.from.1, msg.to.0.key, msg.to.1);
-        log::info!("{}", output);
+        log::debug!(peer = msg.from.0.key, address = msg.from.1.as_str(), msg_type = msg.type_of.as_str(); "Received");
         
         if msg.type_of == "INIT" {
 
@@ -26,21 +26,22 @@ pub fn read_thread(stream: TcpStream, connection: ConnectionRef) -> Result<(), &
             new_msg.type_of = "PEERS_R".to_string();
             
             let dht_msg = DHTMessage {
-                type_of: "k_peers".to_string(), 
+                type_of: "find_node".to_string(), 
                 sending_node: msg.from, 
-                key: create_empty_peer_record(), 
+                key: msg.key.clone(), 
                 keys: Vec::new(),
                 data: new_msg.data.clone(),
                 providers: Vec::new(),
                 name: "".to_string(),
             };
-            connection.send_dht.send(dht_msg);
-            let key_msg : DHTMessage = connection.recieve_dht.recv().unwrap();
+            let _ = connection.send_dht.send(dht_msg);
+            let key_msg : DHTMessage = connection.recieve_reply.recv().unwrap();
 
+            new_msg.from = key_msg.sending_node.clone();
             new_msg.keys = key_msg.keys.clone();
             new_msg.data = key_msg.data.clone();
             
-            connection.sender.send(new_msg);
+            let _ = connection.sender.send(new_msg);
             
             {
                 let mut conn = connection.finished.lock().unwrap();
@@ -61,12 +62,12 @@ pub fn read_thread(stream: TcpStream, connection: ConnectionRef) -> Result<(), &
                 providers: Vec::new(),
                 name: "".to_string(),
             };
-            connection.send_dht.send(dht_msg);
-            let key_msg : DHTMessage = connection.recieve_dht.recv().unwrap();
+            let _ = connection.send_dht.send(dht_msg);
+            let key_msg : DHTMessage = connection.recieve_reply.recv().unwrap();
 
             new_msg.providers = key_msg.providers.clone();
             
-            connection.sender.send(new_msg);
+            let _ = connection.sender.send(new_msg);
             
             {
                 let mut conn = connection.finished.lock().unwrap();
@@ -76,7 +77,7 @@ pub fn read_thread(stream: TcpStream, connection: ConnectionRef) -> Result<(), &
             let mut new_msg = msg.clone();
             new_msg.from = msg.to;
             new_msg.to = msg.from.clone();
-            connection.sender.send(new_msg);
+            let _ = connection.sender.send(new_msg);
         } else if msg.type_of == "INSERT" {
             let dht_msg = DHTMessage {
                 type_of: "insert".to_string(), 
@@ -87,7 +88,154 @@ pub fn read_thread(stream: TcpStream, connection: ConnectionRef) -> Result<(), &
                 providers: Vec::new(),
                 name: msg.key.1.clone(),
             };
-            connection.send_dht.send(dht_msg);
+            let _ = connection.send_dht.send(dht_msg);
+        } else if msg.type_of == "INDEX_INSERT" {
+            let dht_msg = DHTMessage {
+                type_of: "index_insert".to_string(), 
+                sending_node: msg.from, 
+                key: msg.key.clone(), 
+                keys: Vec::new(),
+                data: msg.data,
+                providers: msg.providers,
+                name: "".to_string(),
+            };
+            let _ = connection.send_dht.send(dht_msg);
+        } else if msg.type_of == "RECORD_PUT" {
+            let dht_msg = DHTMessage {
+                type_of: "record_put".to_string(), 
+                sending_node: msg.from, 
+                key: msg.key.clone(), 
+                keys: Vec::new(),
+                data: msg.data,
+                providers: Vec::new(),
+                name: "".to_string(),
+            };
+            let _ = connection.send_dht.send(dht_msg);
+        } else if msg.type_of == "RECORD_GET" {
+            let mut new_msg = msg.clone();
+            new_msg.from = msg.to;
+            new_msg.to = msg.from.clone();
+            new_msg.type_of = "RECORD_GET_REPLY".to_string();
+
+            let dht_msg = DHTMessage {
+                type_of: "record_get".to_string(), 
+                sending_node: msg.from, 
+                key: msg.key.clone(), 
+                keys: Vec::new(),
+                data: new_msg.data.clone(),
+                providers: Vec::new(),
+                name: "".to_string(),
+            };
+            let _ = connection.send_dht.send(dht_msg);
+            let key_msg : DHTMessage = connection.recieve_reply.recv().unwrap();
+
+            new_msg.data = key_msg.data.clone();
+            if key_msg.type_of == "not_found" {
+                new_msg.type_of = "NOT_FOUND".to_string();
+            }
+            let _ = connection.sender.send(new_msg);
+        } else if msg.type_of == "UNSUBSCRIBE" || msg.type_of == "GOSSIP" || msg.type_of == "LEAVE" {
+            let dht_msg = DHTMessage {
+                type_of: msg.type_of.to_lowercase(), 
+                sending_node: msg.from, 
+                key: msg.key.clone(), 
+                keys: Vec::new(),
+                data: msg.data,
+                providers: Vec::new(),
+                name: "".to_string(),
+            };
+            let _ = connection.send_dht.send(dht_msg);
+        } else if msg.type_of == "SUBSCRIBE" {
+            let mut new_msg = msg.clone();
+            new_msg.from = msg.to;
+            new_msg.to = msg.from.clone();
+            new_msg.type_of = "SUBSCRIBERS".to_string();
+
+            let dht_msg = DHTMessage {
+                type_of: "subscribe".to_string(), 
+                sending_node: msg.from, 
+                key: msg.key.clone(), 
+                keys: Vec::new(),
+                data: new_msg.data.clone(),
+                providers: Vec::new(),
+                name: "".to_string(),
+            };
+            let _ = connection.send_dht.send(dht_msg);
+            let key_msg : DHTMessage = connection.recieve_reply.recv().unwrap();
+
+            new_msg.keys = key_msg.keys.clone();
+            let _ = connection.sender.send(new_msg);
+        } else if msg.type_of == "CHAT" {
+            let mut new_msg = msg.clone();
+            new_msg.from = msg.to.clone();
+            new_msg.to = msg.from.clone();
+            new_msg.type_of = "CHAT_ACK".to_string();
+
+            // The recipient checks the message was meant for its key
+            let dht_msg = DHTMessage {
+                type_of: "chat".to_string(), 
+                sending_node: msg.from, 
+                key: msg.to, 
+                keys: Vec::new(),
+                data: new_msg.data.clone(),
+                providers: Vec::new(),
+                name: "".to_string(),
+            };
+            let _ = connection.send_dht.send(dht_msg);
+            let key_msg : DHTMessage = connection.recieve_reply.recv().unwrap();
+
+            if key_msg.type_of == "not_found" {
+                new_msg.type_of = "NOT_FOUND".to_string();
+            }
+            let _ = connection.sender.send(new_msg);
+        } else if msg.type_of == "HAS" {
+            let mut new_msg = msg.clone();
+            new_msg.from = msg.to;
+            new_msg.to = msg.from.clone();
+            new_msg.type_of = "HAVE".to_string();
+
+            let dht_msg = DHTMessage {
+                type_of: "has".to_string(), 
+                sending_node: msg.from, 
+                key: msg.key.clone(), 
+                keys: Vec::new(),
+                data: new_msg.data.clone(),
+                providers: Vec::new(),
+                name: "".to_string(),
+            };
+            let _ = connection.send_dht.send(dht_msg);
+            let key_msg : DHTMessage = connection.recieve_reply.recv().unwrap();
+
+            if key_msg.type_of == "not_found" {
+                new_msg.type_of = "NOT_FOUND".to_string();
+            }
+            let _ = connection.sender.send(new_msg);
+        } else if msg.type_of == "INDEX_GET" {
+            let mut new_msg = msg.clone();
+            new_msg.from = msg.to;
+            new_msg.to = msg.from.clone();
+            new_msg.type_of = "INDEX_GET_REPLY".to_string();
+            
+            let dht_msg = DHTMessage {
+                type_of: "index_get".to_string(), 
+                sending_node: msg.from, 
+                key: msg.key.clone(), 
+                keys: Vec::new(),
+                data: new_msg.data.clone(),
+                providers: Vec::new(),
+                name: "".to_string(),
+            };
+            let _ = connection.send_dht.send(dht_msg);
+            let key_msg : DHTMessage = connection.recieve_reply.recv().unwrap();
+
+            new_msg.providers = key_msg.providers.clone();
+            
+            let _ = connection.sender.send(new_msg);
+            
+            {
+                let mut conn = connection.finished.lock().unwrap();
+                *conn = true;
+            }
         } else if msg.type_of == "PEERS_I_GET" {
             let mut new_msg = msg.clone();
             new_msg.from = msg.to;
@@ -103,13 +251,18 @@ pub fn read_thread(stream: TcpStream, connection: ConnectionRef) -> Result<(), &
                 providers: Vec::new(),
                 name: new_msg.data.0.key.to_string(),
             };
-            connection.send_dht.send(dht_msg);
-            let key_msg : DHTMessage = connection.recieve_dht.recv().unwrap();
+            let _ = connection.send_dht.send(dht_msg);
+            let key_msg : DHTMessage = connection.recieve_reply.recv().unwrap();
 
             new_msg.keys = key_msg.keys.clone();
             new_msg.data = key_msg.data.clone();
+            if key_msg.type_of == "not_found" {
+                new_msg.type_of = "NOT_FOUND".to_string();
+            } else if key_msg.type_of == "choked" {
+                new_msg.type_of = "CHOKED".to_string();
+            }
             
-            connection.sender.send(new_msg);
+            let _ = connection.sender.send(new_msg);
             
             {
                 let mut conn = connection.finished.lock().unwrap();
@@ -122,11 +275,25 @@ pub fn read_thread(stream: TcpStream, connection: ConnectionRef) -> Result<(), &
 pub fn write_thread(mut stream: TcpStream, connection: ConnectionRef) {
     loop {
         let msg : Message = connection.receiver.recv().unwrap();
-        let _res = stream.write(msg.make_message().as_bytes()).unwrap();
-        stream.flush().unwrap();
+        connection.writing.store(true, Ordering::SeqCst);
+
+        // Hand the message to the socket in slices so every peer gets its turn
+        let bytes = msg.make_message().into_bytes();
+        for slice in bytes.chunks(SLICE_SIZE) {
+            throttle().acquire_upload(connection.remote, slice.len());
+            if stream.write_all(slice).is_err() {
+                connection.writing.store(false, Ordering::SeqCst);
+                return;
+            }
+        }
+        let flushed = stream.flush();
+        connection.writing.store(false, Ordering::SeqCst);
+        if flushed.is_err() {
+            return;
+        }
         
-        let output = format!("SENT: {} FROM- ({},{}) TO- ({},{})", msg.type_of, msg.from.0.key, msg.from.1, msg.to.0.key, msg.to.1);
-        log::info!("{}", output);
+        metrics().message_sent(&msg.type_of, bytes.len());
+        log::debug!(peer = msg.to.0.key, address = msg.to.1.as_str(), msg_type = msg.type_of.as_str(), bytes = bytes.len(); "Sent");
     }
     
 }
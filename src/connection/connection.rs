@@ -1,19 +1,26 @@
-use std::net::{TcpStream};
+use std::net::{IpAddr, TcpStream};
 use std::io::{BufReader, BufRead};
 use std::sync::{Mutex, Arc};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
 use rand::Rng;
 
 use crossbeam::channel::unbounded;
 
 use crate::key::Key;
-use crate::client::{PeerRecord, parse_peer_record, create_empty_peer_record, DhtType, parse_providers};
+use crate::client::{PeerRecord, parse_peer_record, create_empty_peer_record, DhtType, parse_providers, format_providers};
 use crate::client_thread::{read_thread, write_thread};
 use crate::data::Data;
+use crate::ledger::remote_ip;
+use crate::metrics::metrics;
+use crate::throttle::throttle;
 
 
 pub type ConnectionRef = Arc<Connection>;
 
+// Error of read_message when a peer sent something that does not parse
+pub const MALFORMED: &str = "Error Parsing";
+
 #[derive(Clone)]
 pub struct DHTMessage {
     pub type_of: String,
@@ -38,7 +45,7 @@ pub struct Message {
 
 impl Message {
     pub fn new(type_of : String, from: PeerRecord, to: PeerRecord, key: PeerRecord, data_key: Key, data: DhtType) -> Message {
-        return Message {type_of : type_of, from: from, to: to, key: key, keys: Vec::new(), data: (data_key, data), providers: Vec::new()};
+        Message {type_of, from, to, key, keys: Vec::new(), data: (data_key, data), providers: Vec::new()}
     }
     
     pub fn make_message(&self) -> String {
@@ -46,30 +53,20 @@ impl Message {
             let output = format!("P2P/1.0 INIT\r\nFROM- ({},{})\r\nTO- ({},{})\r\n\r\n\r\n", self.from.0.key, self.from.1, self.to.0.key, self.to.1);
             return output;
         } else if self.type_of == "PEERS_I" {
-            let output = format!("P2P/1.0 {}\r\nFROM- ({},{})\r\nTO- ({},{})\r\n\r\n\r\n", self.type_of, self.from.0.key, self.from.1, self.to.0.key, self.to.1);
+            let output = format!("P2P/1.0 {}\r\nFROM- ({},{})\r\nTO- ({},{})\r\nDATA_KEY- {}\r\n\r\n\r\n", self.type_of, self.from.0.key, self.from.1, self.to.0.key, self.to.1, self.data.0.key);
             return output;
         } else if self.type_of == "PEERS_R" {
-            let mut keys = "".to_string();
-            for (key, addr) in &self.keys {
-                keys +=  &("(".to_string() + &key.key.clone().to_string() + &",".to_string() + addr + &") ".to_string());
-            }
-            let mut output = format!("P2P/1.0 {}\r\nFROM- ({},{})\r\nTO- ({},{})\r\nKEYS- {}", self.type_of, self.from.0.key, self.from.1, self.to.0.key, self.to.1, keys);
+            let mut output = format!("P2P/1.0 {}\r\nFROM- ({},{})\r\nTO- ({},{})\r\nKEYS- {}", self.type_of, self.from.0.key, self.from.1, self.to.0.key, self.to.1, self.format_keys());
             output += "\r\n\r\n\r\n";
             return output;
         } else if self.type_of == "PROVIDER_GET" {
             let output = format!("P2P/1.0 {}\r\nFROM- ({},{})\r\nTO- ({},{})\r\n\r\n\r\n", self.type_of, self.from.0.key, self.from.1, self.to.0.key, self.to.1);
             return output;
         } else if self.type_of == "PROVIDERS_GET_REPLY" {
-            let mut providers = "".to_string();
-            for (name, key) in &self.providers {
-                providers +=  &("(".to_string() + &name.clone().to_string() + &",".to_string() + &key.key.clone().to_string() + &") ".to_string());
-            }
+            let providers = self.format_providers();
             let mut output = format!("P2P/1.0 {}\r\nFROM- ({},{})\r\nTO- ({},{})\r\nPROVIDERS-{}\r\n", self.type_of, self.from.0.key, self.from.1, self.to.0.key, self.to.1, providers);
             output += "\r\n\r\n\r\n";
             return output;
-        } else if self.type_of == "PING" {
-            let output = format!("P2P/1.0 {}\r\nFROM- ({},{})\r\nTO- ({},{})\r\n\r\n\r\n", self.type_of, self.from.0.key, self.from.1, self.to.0.key, self.to.1);
-            return output;
         } else if self.type_of == "INSERT" {
             let data_str = serde_json::to_string(&self.data.1).unwrap();
             let output = format!("P2P/1.0 {}\r\nFROM- ({},{})\r\nTO- ({},{})\r\nPROVIDER-{}\r\nDATA_KEY- {}\r\n\r\n{}\r\n", self.type_of, self.from.0.key, self.from.1, self.to.0.key, self.to.1, self.key.1, self.data.0.key,  data_str);
@@ -77,30 +74,54 @@ impl Message {
         } else if self.type_of == "PEERS_I_GET" {
             let output = format!("P2P/1.0 {}\r\nFROM- ({},{})\r\nTO- ({},{})\r\nDATA_KEY- {}\r\n\r\n{}\r\n", self.type_of, self.from.0.key, self.from.1, self.to.0.key, self.to.1, self.data.0.key, self.data.1);
             return output;
-        } else if self.type_of == "PEERS_R_GET" {
+        } else if self.type_of == "INDEX_GET" || self.type_of == "NOT_FOUND" || self.type_of == "CHOKED"
+            || self.type_of == "HAS" || self.type_of == "HAVE" || self.type_of == "RECORD_GET" || self.type_of == "LEAVE" {
+            let output = format!("P2P/1.0 {}\r\nFROM- ({},{})\r\nTO- ({},{})\r\nDATA_KEY- {}\r\n\r\n\r\n", self.type_of, self.from.0.key, self.from.1, self.to.0.key, self.to.1, self.data.0.key);
+            return output;
+        } else if self.type_of == "INDEX_INSERT" || self.type_of == "INDEX_GET_REPLY" {
+            let output = format!("P2P/1.0 {}\r\nFROM- ({},{})\r\nTO- ({},{})\r\nDATA_KEY- {}\r\nPROVIDERS- {}\r\n\r\n\r\n", self.type_of, self.from.0.key, self.from.1, self.to.0.key, self.to.1, self.data.0.key, self.format_providers());
+            return output;
+        } else if self.type_of == "PEERS_R_GET" || self.type_of == "RECORD_PUT" || self.type_of == "RECORD_GET_REPLY"
+            || self.type_of == "CHAT" || self.type_of == "CHAT_ACK" || self.type_of == "PING" {
             let out_data = serde_json::to_string(&self.data.1).unwrap();
             let output = format!("P2P/1.0 {}\r\nFROM- ({},{})\r\nTO- ({},{})\r\nDATA_KEY- {}\r\n\r\n{}\r\n", self.type_of, self.from.0.key, self.from.1, self.to.0.key, self.to.1, self.data.0.key, out_data);
             return output;
+        } else if self.type_of == "SUBSCRIBE" || self.type_of == "SUBSCRIBERS" || self.type_of == "UNSUBSCRIBE" || self.type_of == "GOSSIP" {
+            let out_data = serde_json::to_string(&self.data.1).unwrap();
+            let output = format!("P2P/1.0 {}\r\nFROM- ({},{})\r\nTO- ({},{})\r\nKEYS- {}\r\nDATA_KEY- {}\r\n\r\n{}\r\n", self.type_of, self.from.0.key, self.from.1, self.to.0.key, self.to.1, self.format_keys(), self.data.0.key, out_data);
+            return output;
         }
         "".to_string()
     }
 
-    
+
+    fn format_keys(&self) -> String {
+        let mut keys = "".to_string();
+        for (key, addr) in &self.keys {
+            keys +=  &("(".to_string() + &key.key.to_string() + "," + addr + ") ");
+        }
+        keys
+    }
+
+    fn format_providers(&self) -> String {
+        format_providers(&self.providers)
+    }
+
+    // Err(MALFORMED) when the peer sent something that is not a message, any other Err when the connection failed
     pub fn read_message(reader: &mut BufReader<TcpStream>) -> Result<Message, &'static str>  {
         let mut line = String::with_capacity(512);
 
-        let res = reader.read_line(&mut line).unwrap();
+        let res = reader.read_line(&mut line).map_err(|_| "Error")?;
+        let mut total = res;
 
         if res == 0 {
             return Err("Error");
         }
 
-        line.split(' ');
-        let mut args = line.split(' ');
-        args.next().unwrap();
-
-        let type_of : String = args.next().unwrap().to_string();
-        let type_of = type_of.trim().to_string();
+        let type_of = match line.trim_end().split_once(' ') {
+            Some((_, type_of)) if !type_of.trim().is_empty() => type_of.trim().to_string(),
+            _ => return Err(MALFORMED),
+        };
 
 
         let mut from = create_empty_peer_record();
@@ -109,47 +130,40 @@ impl Message {
         let mut keys: Vec<PeerRecord> = Vec::new();
         let mut data: (Key, DhtType) = (Key{key:0}, Data::create_empty());
         let mut data_key = Key{key:0};
-        let mut data_val = "".to_string();
         let mut providers: Vec<(String, Key)> = Vec::new();
         loop  {
             let mut line = String::with_capacity(512);
-            reader.read_line(&mut line).unwrap();
+            total += reader.read_line(&mut line).map_err(|_| "Error")?;
             if line == "\r\n" {
                 break;
             }
             line.pop();
             line.pop();
             
-            let mut args = line.split('-');
-            let key = args.next().ok_or("Error Parsing")?;
-            let mut val = args.next().ok_or("Error Parsing")?;
+            let mut args = line.splitn(2, '-');
+            let key = args.next().ok_or(MALFORMED)?;
+            let val = args.next().ok_or(MALFORMED)?;
 
             if key == "FROM" {
-                from = parse_peer_record(val);
+                from = parse_peer_record(val)?;
             } else if key == "TO" {
-                to = parse_peer_record(val);
+                to = parse_peer_record(val)?;
             } else if key == "KEY" {
-                found_key = parse_peer_record(val);
+                found_key = parse_peer_record(val)?;
             } else if key == "KEYS" {
                 let trimmed = val.trim();
-                if trimmed.len() == 0 { continue; }
+                if trimmed.is_empty() { continue; }
                 let key_list : Vec<&str> = trimmed.split(' ').collect();
                 for new_key in key_list {
-                    let peer = parse_peer_record(new_key.clone());
+                    let peer = parse_peer_record(new_key)?;
                     keys.push(peer);
                 }              
             } else if key == "DATA_KEY" {
-                let key = val.trim().parse::<u32>().unwrap();
-                data_key = Key{key:key};
+                let key = val.trim().parse::<u32>().map_err(|_| MALFORMED)?;
+                data_key = Key{key};
                 found_key.0 = data_key;
             } else if key == "PROVIDERS" {
-                let trimmed = val.trim();
-                if trimmed.len() == 0 { continue; }
-                let p_list : Vec<&str> = trimmed.split(' ').collect();
-                for provider in p_list {
-                    let peer = parse_providers(provider.clone());
-                    providers.push(peer);
-                }
+                providers = parse_providers(val).map_err(|_| MALFORMED)?;
             } else if key == "PROVIDER" {
                 let trimmed = val.trim();
                 found_key.1 = trimmed.to_string();
@@ -157,16 +171,18 @@ impl Message {
         }  
 
         let mut line = String::with_capacity(512);
-        reader.read_line(&mut line).unwrap();
+        total += reader.read_line(&mut line).map_err(|_| "Error")?;
         line.pop();
         line.pop();
-        line.trim();
 
-        if (line != "") {
-            let data_obj: Data = serde_json::from_str(&line).unwrap();
+        if !line.is_empty()  {
+            let data_obj: Data = serde_json::from_str(&line).map_err(|_| MALFORMED)?;
             data = (data_key, data_obj);
         }        
-        return Ok(Message {type_of : type_of, from: from, to: to, key: found_key, keys: keys, data: data, providers: providers});
+        throttle().account_download(remote_ip(reader.get_ref()), total);
+        metrics().message_received(&type_of, total);
+
+        Ok(Message {type_of, from, to, key: found_key, keys, data, providers})
     }
 }
 
@@ -178,20 +194,35 @@ pub struct Connection {
     pub send_dht: crossbeam::channel::Sender<DHTMessage>,
     pub recieve_dht: crossbeam::channel::Receiver<DHTMessage>,
 
+    pub send_reply: crossbeam::channel::Sender<DHTMessage>,
+    pub recieve_reply: crossbeam::channel::Receiver<DHTMessage>,
+
     pub finished: Arc<Mutex<bool>>,
+    // Set while the write thread has a message on the wire
+    pub writing: Arc<AtomicBool>,
+    // Address of the other end, for the ledger
+    pub remote: IpAddr,
 }
 
 impl Clone for Connection {
     fn clone(&self) -> Connection {
-        return Connection {id: self.id.clone(), sender: self.sender.clone(), receiver: self.receiver.clone(), 
-            send_dht: self.send_dht.clone(), recieve_dht: self.recieve_dht.clone(), finished: self.finished.clone()};
+        Connection {id: self.id, sender: self.sender.clone(), receiver: self.receiver.clone(), 
+            send_dht: self.send_dht.clone(), recieve_dht: self.recieve_dht.clone(), 
+            send_reply: self.send_reply.clone(), recieve_reply: self.recieve_reply.clone(), finished: self.finished.clone(),
+            writing: self.writing.clone(), remote: self.remote}
     }
 }
 
 impl Connection { 
+    // Nothing queued or being written
+    pub fn is_idle(&self) -> bool {
+        self.receiver.is_empty() && !self.writing.load(Ordering::SeqCst)
+    }
+
     pub fn new(stream : TcpStream, read: bool, write: bool) -> ConnectionRef {
         let (send_job, recieve_job): (crossbeam::channel::Sender<Message>, crossbeam::channel::Receiver<Message>)= unbounded();
         let (send_dht, recieve_dht): (crossbeam::channel::Sender<DHTMessage>, crossbeam::channel::Receiver<DHTMessage>)= unbounded();
+        let (send_reply, recieve_reply): (crossbeam::channel::Sender<DHTMessage>, crossbeam::channel::Receiver<DHTMessage>)= unbounded();
 
         let mut rng = rand::thread_rng();
         let rand_id = rng.gen::<u32>();
@@ -199,9 +230,13 @@ impl Connection {
             id: rand_id,
             sender: send_job, 
             receiver: recieve_job, 
-            send_dht: send_dht, 
-            recieve_dht: recieve_dht, 
+            send_dht, 
+            recieve_dht, 
+            send_reply,
+            recieve_reply,
             finished: Arc::new(Mutex::new(false)),
+            writing: Arc::new(AtomicBool::new(false)),
+            remote: remote_ip(&stream),
         };
         let console_ptr = Arc::new(conn);
         
@@ -209,13 +244,13 @@ impl Connection {
             let ptr_read = console_ptr.clone();
             let stream_read = stream.try_clone().unwrap();
             thread::spawn(move || {
-                match read_thread(stream_read, ptr_read) {
-                   Err(_) => {},
-                   _ => {} ,
-                }
+                if read_thread(stream_read, ptr_read).is_err() {}
             });
         }
         
+        if !read {
+            metrics().connection_opened(false);
+        }
         if write {
             let ptr_write= console_ptr.clone();
             let stream_write = stream.try_clone().unwrap();
@@ -228,3 +263,55 @@ impl Connection {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::net::TcpListener;
+
+    // Write raw bytes to one end of a loopback connection and read a message off the other
+    fn read_back(bytes: &[u8]) -> Result<Message, &'static str> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut writer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (reader, _) = listener.accept().unwrap();
+        writer.write_all(bytes).unwrap();
+        drop(writer);
+        Message::read_message(&mut BufReader::new(reader))
+    }
+
+    fn message(type_of: &str, providers: Vec<(String, Key)>) -> Message {
+        let mut msg = Message::new(type_of.to_string(), (Key {key: 1}, "127.0.0.1:1".to_string()), (Key {key: 2}, "127.0.0.1:2".to_string()),
+            create_empty_peer_record(), Key {key: 3}, Data::create_empty());
+        msg.providers = providers;
+        msg
+    }
+
+    #[test]
+    fn provider_names_with_spaces_survive_the_wire() {
+        let providers = vec![("my notes.txt".to_string(), Key {key: 10}), ("x, y".to_string(), Key {key: 11})];
+        for type_of in ["PROVIDERS_GET_REPLY", "INDEX_INSERT", "INDEX_GET_REPLY"] {
+            let msg = read_back(message(type_of, providers.clone()).make_message().as_bytes()).unwrap();
+            assert_eq!(msg.type_of, type_of);
+            assert_eq!(msg.providers, providers);
+        }
+    }
+
+    #[test]
+    fn malformed_headers_are_errors_not_panics() {
+        let old_format = b"P2P/1.0 INDEX_INSERT\r\nFROM- (1,127.0.0.1:1)\r\nTO- (2,127.0.0.1:2)\r\nDATA_KEY- 3\r\nPROVIDERS- (my notes.txt,10) \r\n\r\n\r\n";
+        assert!(read_back(old_format).is_err());
+        let bad_key = b"P2P/1.0 HAS\r\nFROM- (1,127.0.0.1:1)\r\nTO- (2,127.0.0.1:2)\r\nDATA_KEY- nope\r\n\r\n\r\n";
+        assert_eq!(read_back(bad_key).err(), Some(MALFORMED));
+        let bad_body = b"P2P/1.0 PEERS_R_GET\r\nFROM- (1,127.0.0.1:1)\r\nTO- (2,127.0.0.1:2)\r\nDATA_KEY- 3\r\n\r\n{\"vec\": [1, 2\r\n";
+        assert_eq!(read_back(bad_body).err(), Some(MALFORMED));
+        let bad_keys = b"P2P/1.0 PEERS_R\r\nFROM- (1,127.0.0.1:1)\r\nTO- (2,127.0.0.1:2)\r\nKEYS- (4,127.0.0.1:4) (x,127.0.0.1:5) \r\n\r\n\r\n";
+        assert_eq!(read_back(bad_keys).err(), Some(MALFORMED));
+        let unbracketed = b"P2P/1.0 PING\r\nFROM- 1,127.0.0.1:1\r\n\r\n\r\n";
+        assert_eq!(read_back(unbracketed).err(), Some(MALFORMED));
+        let empty_record = b"P2P/1.0 PING\r\nFROM- ()\r\n\r\n\r\n";
+        assert_eq!(read_back(empty_record).err(), Some(MALFORMED));
+        assert_eq!(read_back(b"GARBAGE\r\n\r\n\r\n").err(), Some(MALFORMED));
+        assert_eq!(read_back(b"P2P/1.0 \r\n\r\n\r\n").err(), Some(MALFORMED));
+    }
+}
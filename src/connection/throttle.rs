@@ -0,0 +1,377 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Condvar, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+use serde::Deserialize;
+
+// Largest write handed to the socket at once, so peers interleave at this granularity
+pub const SLICE_SIZE: usize = 16 * 1024;
+
+const MAX_WAIT: Duration = Duration::from_millis(50);
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+// Peers with no traffic for this long are forgotten, their buckets would be full again
+const PEER_IDLE: Duration = Duration::from_secs(60);
+
+// Bytes per second, zero meaning unlimited
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    pub upload: u64,
+    pub download: u64,
+    pub peer_upload: u64,
+    pub peer_download: u64,
+}
+
+pub struct TokenBucket {
+    rate: u64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64) -> TokenBucket {
+        TokenBucket {rate, tokens: capacity(rate), last: Instant::now()}
+    }
+
+    pub fn set_rate(&mut self, rate: u64) {
+        self.rate = rate;
+        self.tokens = self.tokens.min(capacity(rate));
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(capacity(self.rate));
+    }
+
+    // Time until n bytes may pass, zero when they may pass now
+    pub fn wait_time(&mut self, n: usize) -> Duration {
+        if self.rate == 0 { return Duration::ZERO; }
+        self.refill();
+        if self.tokens >= n as f64 { return Duration::ZERO; }
+        Duration::from_secs_f64((n as f64 - self.tokens) / self.rate as f64)
+    }
+
+    // Take n tokens, possibly going into debt that later callers pay off
+    pub fn take(&mut self, n: usize) {
+        if self.rate == 0 { return; }
+        self.refill();
+        self.tokens -= n as f64;
+    }
+}
+
+fn capacity(rate: u64) -> f64 {
+    rate.max(SLICE_SIZE as u64) as f64
+}
+
+// Bytes moved during the current window and the rate of the last full window
+pub struct RateMeter {
+    start: Instant,
+    bytes: u64,
+    rate: u64,
+    pub total: u64,
+}
+
+impl RateMeter {
+    pub fn new() -> RateMeter {
+        RateMeter {start: Instant::now(), bytes: 0, rate: 0, total: 0}
+    }
+
+    pub fn record(&mut self, n: usize) {
+        self.roll();
+        self.bytes += n as u64;
+        self.total += n as u64;
+    }
+
+    fn roll(&mut self) {
+        let elapsed = self.start.elapsed();
+        if elapsed >= RATE_WINDOW {
+            self.rate = (self.bytes as f64 / elapsed.as_secs_f64()) as u64;
+            self.bytes = 0;
+            self.start = Instant::now();
+            // A window with no traffic at all means the peer went quiet
+            if elapsed >= RATE_WINDOW * 2 { self.rate = 0; }
+        }
+    }
+
+    pub fn rate(&mut self) -> u64 {
+        self.roll();
+        self.rate
+    }
+}
+
+impl Default for RateMeter {
+    fn default() -> RateMeter {
+        RateMeter::new()
+    }
+}
+
+pub struct PeerState {
+    upload: TokenBucket,
+    download: TokenBucket,
+    pub sent: RateMeter,
+    pub received: RateMeter,
+    last_served: u64,
+    // Peers in good standing with the ledger are scheduled ahead of the rest
+    preferred: bool,
+    last_active: Instant,
+}
+
+struct ThrottleState {
+    limits: Limits,
+    upload: TokenBucket,
+    download: TokenBucket,
+    sent: RateMeter,
+    received: RateMeter,
+    // Keyed by the remote address of the socket, not the key a peer claims,
+    // so a peer cannot spread its traffic over made up keys
+    peers: HashMap<IpAddr, PeerState>,
+    // (ticket, peer) for every writer waiting for its turn
+    waiting: Vec<(u64, IpAddr)>,
+    next_ticket: u64,
+    served: u64,
+}
+
+impl ThrottleState {
+    fn peer(&mut self, peer: IpAddr) -> &mut PeerState {
+        if !self.peers.contains_key(&peer) {
+            self.evict_idle();
+        }
+        let limits = self.limits;
+        let state = self.peers.entry(peer).or_insert_with(|| PeerState {
+            upload: TokenBucket::new(limits.peer_upload),
+            download: TokenBucket::new(limits.peer_download),
+            sent: RateMeter::new(),
+            received: RateMeter::new(),
+            last_served: 0,
+            preferred: true,
+            last_active: Instant::now(),
+        });
+        state.last_active = Instant::now();
+        state
+    }
+
+    fn evict_idle(&mut self) {
+        let waiting = &self.waiting;
+        self.peers.retain(|peer, state| state.last_active.elapsed() < PEER_IDLE || waiting.iter().any(|(_, other)| other == peer));
+    }
+}
+
+// Shared upload and download limiter for every connection of this node
+pub struct Throttle {
+    state: Mutex<ThrottleState>,
+    turn: Condvar,
+}
+
+// Current rates in bytes per second
+pub struct RateReport {
+    pub limits: Limits,
+    pub upload: u64,
+    pub download: u64,
+    pub peers: Vec<(IpAddr, u64, u64, u64, u64)>,
+}
+
+static THROTTLE: OnceLock<Throttle> = OnceLock::new();
+
+pub fn throttle() -> &'static Throttle {
+    THROTTLE.get_or_init(|| Throttle::new(Limits::default()))
+}
+
+impl Throttle {
+    pub fn new(limits: Limits) -> Throttle {
+        Throttle {
+            state: Mutex::new(ThrottleState {
+                limits,
+                upload: TokenBucket::new(limits.upload),
+                download: TokenBucket::new(limits.download),
+                sent: RateMeter::new(),
+                received: RateMeter::new(),
+                peers: HashMap::new(),
+                waiting: Vec::new(),
+                next_ticket: 0,
+                served: 0,
+            }),
+            turn: Condvar::new(),
+        }
+    }
+
+    pub fn set_limits(&self, limits: Limits) {
+        let mut state = self.state.lock().unwrap();
+        state.limits = limits;
+        state.upload.set_rate(limits.upload);
+        state.download.set_rate(limits.download);
+        for peer in state.peers.values_mut() {
+            peer.upload.set_rate(limits.peer_upload);
+            peer.download.set_rate(limits.peer_download);
+        }
+        self.turn.notify_all();
+    }
+
+    pub fn limits(&self) -> Limits {
+        self.state.lock().unwrap().limits
+    }
+
+    pub fn set_preferred(&self, peer: IpAddr, preferred: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.peer(peer).preferred = preferred;
+    }
+
+    // Block until this slice may be written. Among writers whose peer has
+    // tokens left, preferred peers go first, then the one served least recently.
+    pub fn acquire_upload(&self, peer: IpAddr, n: usize) {
+        let mut state = self.state.lock().unwrap();
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        state.waiting.push((ticket, peer));
+        state.peer(peer);
+
+        loop {
+            let mut peer_wait = MAX_WAIT;
+            let mut next: Option<(bool, u64, u64)> = None;
+            let ThrottleState {waiting, peers, ..} = &mut *state;
+            for (other_ticket, other_peer) in waiting.iter().copied() {
+                // Peers with a writer waiting are never evicted
+                let Some(other) = peers.get_mut(&other_peer) else { continue };
+                let wait = other.upload.wait_time(n);
+                if wait > Duration::ZERO {
+                    peer_wait = peer_wait.min(wait);
+                    continue;
+                }
+                let rank = (!other.preferred, other.last_served, other_ticket);
+                if next.map(|best| rank < best).unwrap_or(true) {
+                    next = Some(rank);
+                }
+            }
+
+            let global_wait = state.upload.wait_time(n);
+            if next.map(|(_, _, next_ticket)| next_ticket == ticket).unwrap_or(false) && global_wait == Duration::ZERO {
+                state.waiting.retain(|(other, _)| *other != ticket);
+                state.served += 1;
+                let served = state.served;
+                state.upload.take(n);
+                state.sent.record(n);
+                let entry = state.peer(peer);
+                entry.upload.take(n);
+                entry.sent.record(n);
+                entry.last_served = served;
+                self.turn.notify_all();
+                return;
+            }
+
+            let wait = if global_wait > Duration::ZERO { global_wait.min(MAX_WAIT) } else { peer_wait };
+            state = self.turn.wait_timeout(state, wait).unwrap().0;
+        }
+    }
+
+    // Account for bytes already read from a peer, sleeping off any overdraft
+    pub fn account_download(&self, peer: IpAddr, n: usize) {
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+            state.download.take(n);
+            state.received.record(n);
+            let global_wait = state.download.wait_time(0);
+
+            let entry = state.peer(peer);
+            entry.download.take(n);
+            entry.received.record(n);
+            global_wait.max(entry.download.wait_time(0))
+        };
+        if wait > Duration::ZERO {
+            thread::sleep(wait);
+        }
+    }
+
+    pub fn report(&self) -> RateReport {
+        let mut state = self.state.lock().unwrap();
+        let limits = state.limits;
+        let upload = state.sent.rate();
+        let download = state.received.rate();
+        let mut peers: Vec<(IpAddr, u64, u64, u64, u64)> = state.peers.iter_mut()
+            .map(|(key, peer)| (*key, peer.sent.rate(), peer.received.rate(), peer.sent.total, peer.received.total))
+            .collect();
+        peers.sort();
+        RateReport {limits, upload, download, peers}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use std::sync::Arc;
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(10, 0, 0, last))
+    }
+
+    #[test]
+    fn bucket_refills_at_its_rate() {
+        let mut bucket = TokenBucket::new(SLICE_SIZE as u64);
+        assert_eq!(bucket.wait_time(SLICE_SIZE), Duration::ZERO);
+        bucket.take(SLICE_SIZE);
+        let wait = bucket.wait_time(SLICE_SIZE / 2);
+        assert!(wait > Duration::from_millis(400) && wait <= Duration::from_millis(500), "{:?}", wait);
+
+        thread::sleep(Duration::from_millis(200));
+        assert!(bucket.wait_time(SLICE_SIZE / 2) <= Duration::from_millis(300));
+
+        let mut unlimited = TokenBucket::new(0);
+        unlimited.take(usize::MAX / 2);
+        assert_eq!(unlimited.wait_time(SLICE_SIZE), Duration::ZERO);
+    }
+
+    #[test]
+    fn peer_limit_holds_back_one_peer_only() {
+        let throttle = Throttle::new(Limits {peer_upload: 4 * SLICE_SIZE as u64, ..Limits::default()});
+        let start = Instant::now();
+        // The first second's worth passes at once, the next has to wait for it
+        for _ in 0..6 {
+            throttle.acquire_upload(ip(1), SLICE_SIZE);
+        }
+        assert!(start.elapsed() >= Duration::from_millis(400), "{:?}", start.elapsed());
+
+        let start = Instant::now();
+        throttle.acquire_upload(ip(2), SLICE_SIZE);
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+
+    #[test]
+    fn waiting_peers_take_turns() {
+        let throttle = Arc::new(Throttle::new(Limits {upload: 10 * SLICE_SIZE as u64, ..Limits::default()}));
+        throttle.state.lock().unwrap().upload.take(10 * SLICE_SIZE);
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        let writer = |peer: u8, slices: usize| {
+            let (throttle, order) = (throttle.clone(), order.clone());
+            thread::spawn(move || {
+                for _ in 0..slices {
+                    throttle.acquire_upload(ip(peer), SLICE_SIZE);
+                    order.lock().unwrap().push(peer);
+                }
+            })
+        };
+        let first = writer(1, 6);
+        thread::sleep(Duration::from_millis(20));
+        let second = writer(2, 3);
+        first.join().unwrap();
+        second.join().unwrap();
+
+        assert_eq!(order.lock().unwrap()[..6], [1, 2, 1, 2, 1, 2]);
+    }
+
+    #[test]
+    fn idle_peers_are_forgotten() {
+        let throttle = Throttle::new(Limits::default());
+        throttle.account_download(ip(1), 10);
+        throttle.account_download(ip(2), 10);
+        if let Some(idle) = Instant::now().checked_sub(PEER_IDLE) {
+            throttle.state.lock().unwrap().peers.get_mut(&ip(1)).unwrap().last_active = idle;
+        }
+
+        throttle.account_download(ip(3), 10);
+        let peers: Vec<IpAddr> = throttle.report().peers.iter().map(|peer| peer.0).collect();
+        assert_eq!(peers, vec![ip(2), ip(3)]);
+    }
+}
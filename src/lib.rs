@@ -0,0 +1,107 @@
+//! Peer-to-peer file sharing over a Kademlia style DHT.
+//!
+//! A [`Node`] listens for peers, serves the values it stores and runs
+//! lookups for the application. Start one with [`Node::builder`]:
+//!
+//! ```
+//! use peer_stream::Node;
+//!
+//! // A node of its own network, and a second one joining through it
+//! let first = Node::builder().bootstraps(Vec::new()).port(0).start()?;
+//! let second = Node::builder().bootstraps(vec![first.address().to_string()]).port(0).start()?;
+//!
+//! let key = second.put("hello.txt", b"hello world".to_vec())?;
+//! assert_eq!(first.get(key)?, b"hello world");
+//!
+//! first.subscribe("news", |message| println!("{}: {}", message.origin, message.payload))?;
+//! // Publishing fails while no other subscriber can be reached
+//! match second.publish("news", "uploaded hello.txt") {
+//!     Ok(peers) => println!("Told {} peers", peers),
+//!     Err(e) => println!("Not published: {}", e),
+//! }
+//!
+//! for node in [second, first] {
+//!     node.shutdown()?;
+//!     node.wait();
+//! }
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+//!
+//! [`Client`] exposes the lower level operations the node is built on.
+//! Its `_async` variants return a [`Call`] future per operation, which
+//! cancels the work when dropped and fails after [`CallOptions::timeout`].
+
+#[path = "./application/client.rs"]
+pub mod client;
+
+#[path = "./application/key.rs"]
+pub mod key;
+
+#[path = "./application/data.rs"]
+pub mod data;
+
+#[path = "./application/index.rs"]
+pub mod index;
+
+#[path = "./application/tree.rs"]
+pub mod tree;
+
+#[path = "./application/manifest.rs"]
+pub mod manifest;
+
+#[path = "./application/download.rs"]
+pub mod download;
+
+#[path = "./application/ledger.rs"]
+pub mod ledger;
+
+#[path = "./application/record.rs"]
+pub mod record;
+
+#[path = "./application/pubsub.rs"]
+pub mod pubsub;
+
+#[path = "./application/chat.rs"]
+pub mod chat;
+
+#[path = "./application/latency.rs"]
+pub mod latency;
+
+#[path = "./application/config.rs"]
+pub mod config;
+
+#[path = "./application/logging.rs"]
+pub mod logging;
+
+#[path = "./application/node.rs"]
+pub mod node;
+
+#[path = "./application/call.rs"]
+pub mod call;
+
+#[path = "./application/gateway.rs"]
+pub mod gateway;
+
+#[path = "./application/rpc.rs"]
+pub mod rpc;
+
+#[path = "./application/metrics.rs"]
+pub mod metrics;
+
+#[path = "./application/crawler.rs"]
+pub mod crawler;
+
+#[path = "./connection/connection.rs"]
+pub mod connection;
+
+#[path = "./connection/client_thread.rs"]
+mod client_thread;
+
+#[path = "./connection/throttle.rs"]
+pub mod throttle;
+
+pub use crate::call::{Call, CallError, CallOptions};
+pub use crate::client::Client;
+pub use crate::config::Config;
+pub use crate::key::Key;
+pub use crate::node::{Node, NodeBuilder};
@@ -1,110 +1,422 @@
-use std::net::{SocketAddrV4, Ipv4Addr, TcpListener};
-
 use clap::Parser;
 
 use std::thread;
-use crate::data::Data;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use std::sync::atomic::Ordering;
 
-use log::{Record, Level, Metadata, LevelFilter, SetLoggerError};
+use serde_json::{json, Value};
 
 #[path = "./application/console_handle.rs"]
 mod console_handle;
 
-#[path = "./application/client.rs"]
-mod client;
+use peer_stream::{crawler, gateway, latency, logging, manifest, metrics, rpc, tree, Config, Key, Node};
+
+
+// Settings left out fall back to the config file, then to the defaults of peer_stream::Config
+#[derive(clap::Parser, Debug)]
+struct Cli {
+    /// TOML config file
+    #[clap(long, env = "PEER_STREAM_CONFIG")]
+    config: Option<PathBuf>,
+
+    #[clap(short)]
+    bootnode: bool,
 
-#[path = "./application/key.rs"]
-mod key;
+    /// Address to bind, e.g. 0.0.0.0:4000
+    #[clap(long, env = "PEER_STREAM_LISTEN")]
+    listen: Option<String>,
 
-#[path = "./application/data.rs"]
-mod data;
+    /// Address other nodes should reach this one on
+    #[clap(long, env = "PEER_STREAM_ADVERTISE")]
+    advertise: Option<String>,
 
-#[path = "./connection/connection.rs"]
-mod connection;
+    /// Node to join through, may be repeated or comma separated
+    #[clap(long = "bootstrap", env = "PEER_STREAM_BOOTSTRAPS", use_value_delimiter = true)]
+    bootstraps: Vec<String>,
 
-#[path = "./connection/client_thread.rs"]
-mod client_thread;
+    /// Bucket size and default replication
+    #[clap(long, env = "PEER_STREAM_K")]
+    k: Option<usize>,
 
-use crate::client::Client;
+    /// Parallel queries per lookup round
+    #[clap(long, env = "PEER_STREAM_ALPHA")]
+    alpha: Option<usize>,
 
+    /// Total upload limit in bytes per second, 0 for unlimited
+    #[clap(long, env = "PEER_STREAM_UPLOAD_LIMIT")]
+    upload_limit: Option<u64>,
 
-static CONSOLE_LOGGER: ConsoleLogger = ConsoleLogger;
-struct ConsoleLogger;
+    /// Total download limit in bytes per second, 0 for unlimited
+    #[clap(long, env = "PEER_STREAM_DOWNLOAD_LIMIT")]
+    download_limit: Option<u64>,
 
-impl log::Log for ConsoleLogger {
-  fn enabled(&self, metadata: &Metadata) -> bool {
-     metadata.level() <= Level::Info
-    }
+    /// Upload limit towards any single peer in bytes per second
+    #[clap(long, env = "PEER_STREAM_PEER_UPLOAD_LIMIT")]
+    peer_upload_limit: Option<u64>,
 
-    fn log(&self, record: &Record) {
-        if self.enabled(record.metadata()) {
-            println!("{}: - {}", record.level(), record.args());
-        }
+    /// Download limit from any single peer in bytes per second
+    #[clap(long, env = "PEER_STREAM_PEER_DOWNLOAD_LIMIT")]
+    peer_download_limit: Option<u64>,
+
+    /// Seconds between replica audits of the keys this node stored, 0 to disable
+    #[clap(long, env = "PEER_STREAM_AUDIT_INTERVAL")]
+    audit_interval: Option<u64>,
+
+    /// Directory keeping the node key and peer ledgers between runs
+    #[clap(long, env = "PEER_STREAM_DATA_DIR")]
+    data_dir: Option<PathBuf>,
+
+    /// Bytes of values kept for other nodes, 0 for unlimited
+    #[clap(long, env = "PEER_STREAM_MAX_STORAGE")]
+    max_storage: Option<u64>,
+
+    /// Log filter such as info or warn,peer_stream::client=debug, RUST_LOG is read too
+    #[clap(long, env = "PEER_STREAM_LOG_LEVEL")]
+    log_level: Option<String>,
+
+    /// Append logs to this file instead of stderr
+    #[clap(long, env = "PEER_STREAM_LOG_FILE")]
+    log_file: Option<PathBuf>,
+
+    /// Serve the HTTP gateway on this address, e.g. 127.0.0.1:8080
+    #[clap(long)]
+    http: Option<String>,
+
+    /// Answer JSON-RPC requests on a Unix socket at this path
+    #[clap(long)]
+    rpc: Option<PathBuf>,
+
+    /// Serve Prometheus metrics at /metrics on this address, e.g. 127.0.0.1:9100
+    #[clap(long, env = "PEER_STREAM_METRICS")]
+    metrics: Option<String>,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+// Exit statuses of the one shot commands, bad arguments or config exit with 2
+const EXIT_FAILED: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_NOT_FOUND: i32 = 3;
+const EXIT_UNREACHABLE: i32 = 4;
+
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Run a node with the interactive console, the default
+    Serve,
+
+    /// Send one JSON-RPC request to a running node and print its result
+    Call {
+        /// Socket the node was started with --rpc on
+        #[clap(long)]
+        socket: PathBuf,
+
+        /// One of insert, upload, get, list, providers, ping
+        method: String,
+
+        /// Parameters as a JSON object, e.g. '{"key": 42}'
+        params: Option<String>,
+    },
+
+    #[clap(flatten)]
+    Once(NodeCommand),
+}
+
+// Commands that join the network, run once and leave again
+#[derive(clap::Subcommand, Debug)]
+enum NodeCommand {
+    /// Upload a file or directory and print its key
+    Put {
+        file: PathBuf,
+
+        /// Nodes each chunk is stored on, k by default
+        #[clap(long)]
+        replicas: Option<usize>,
+    },
+
+    /// Download the file or directory stored under key
+    Get {
+        key: u32,
+
+        /// Where to write it
+        #[clap(short, long)]
+        output: PathBuf,
+    },
+
+    /// Print the routing table once joined
+    Peers,
+
+    /// Measure the round trip time to the node with key
+    Ping {
+        key: u32,
+
+        #[clap(long, default_value_t = 4)]
+        count: usize,
+    },
+
+    /// Map the overlay by asking every reachable node for its neighbours in each bucket
+    Crawl {
+        /// Times to go over the whole network, the graph grows with each one
+        #[clap(long, default_value_t = 1)]
+        rounds: usize,
+
+        /// Seconds to wait between rounds
+        #[clap(long, default_value_t = 10)]
+        interval: u64,
+
+        /// Write the graph as JSON to this file
+        #[clap(long)]
+        json: Option<PathBuf>,
+
+        /// Write the graph in Graphviz DOT to this file
+        #[clap(long)]
+        dot: Option<PathBuf>,
+    },
+}
+
+struct Failure {
+    code: i32,
+    message: String,
+}
+
+fn fail(code: i32) -> impl Fn(Box<dyn Error>) -> Failure {
+    move |e| Failure {code, message: e.to_string()}
+}
+
+// Talk to a running node instead of starting one
+fn call(socket: &Path, method: &str, params: Option<&str>) -> Result<Value, Box<dyn Error>> {
+    let params = match params {
+        Some(params) => serde_json::from_str(params).map_err(|e| format!("Invalid params: {}", e))?,
+        None => json!({}),
+    };
+    rpc::call(socket, method, params)
+}
+
+// The config file with the flags and environment variables applied on top
+fn load_config(cli: &Cli) -> Result<Config, Box<dyn Error>> {
+    let mut config = match &cli.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+
+    config.network.bootnode |= cli.bootnode;
+    if let Some(listen) = &cli.listen {
+        config.network.listen = listen.clone();
+    }
+    if let Some(advertise) = &cli.advertise {
+        config.network.advertise = Some(advertise.clone());
+    }
+    if !cli.bootstraps.is_empty() {
+        config.network.bootstraps = cli.bootstraps.clone();
+    }
+    config.dht.k = cli.k.unwrap_or(config.dht.k);
+    config.dht.alpha = cli.alpha.unwrap_or(config.dht.alpha);
+    config.limits.upload = cli.upload_limit.unwrap_or(config.limits.upload);
+    config.limits.download = cli.download_limit.unwrap_or(config.limits.download);
+    config.limits.peer_upload = cli.peer_upload_limit.unwrap_or(config.limits.peer_upload);
+    config.limits.peer_download = cli.peer_download_limit.unwrap_or(config.limits.peer_download);
+    config.timeouts.audit_secs = cli.audit_interval.unwrap_or(config.timeouts.audit_secs);
+    if let Some(data_dir) = &cli.data_dir {
+        config.storage.data_dir = Some(data_dir.clone());
+    }
+    config.storage.max_bytes = cli.max_storage.unwrap_or(config.storage.max_bytes);
+    if let Ok(rust_log) = std::env::var("RUST_LOG") {
+        config.logging.level = rust_log;
+    }
+    if let Some(log_level) = &cli.log_level {
+        config.logging.level = log_level.clone();
+    }
+    if let Some(log_file) = &cli.log_file {
+        config.logging.file = Some(log_file.clone());
     }
 
-    fn flush(&self) {}
+    config.validate()?;
+    Ok(config)
 }
 
+// Join the network, run a single command and leave again
+fn run_once(cli: &Cli, command: &NodeCommand) -> Result<Value, Failure> {
+    let mut config = load_config(cli).map_err(fail(EXIT_USAGE))?;
+    // Only warnings unless a level was asked for, stdout carries the result
+    if cli.config.is_none() && cli.log_level.is_none() && std::env::var_os("RUST_LOG").is_none() {
+        config.logging.level = "warn".to_string();
+    }
+    init_logging(&config).map_err(fail(EXIT_USAGE))?;
+    let node = Node::builder().config(config).audit_interval(None).start().map_err(fail(EXIT_UNREACHABLE))?;
+    let result = command_result(&node, command);
+    if let Err(e) = node.shutdown() {
+        log::warn!("{}", e);
+    }
+    node.wait();
+    result
+}
 
+fn command_result(node: &Node, command: &NodeCommand) -> Result<Value, Failure> {
+    let mut client = node.client();
+    match command {
+        NodeCommand::Put {file, replicas} => {
+            let replicas = replicas.unwrap_or(client.replication());
+            let key = tree::upload_path(&mut client, file, replicas, None).map_err(fail(EXIT_FAILED))?;
+            Ok(json!({"key": key.key, "path": file}))
+        },
+        NodeCommand::Get {key, output} => {
+            let key = Key {key: *key};
+            let data = client.get_data(key).map_err(fail(EXIT_NOT_FOUND))?;
+            tree::download_path(&mut client, key, &data, output).map_err(fail(EXIT_FAILED))?;
+            let file_meta = match manifest::is_manifest(&data) {
+                true => manifest::parse_manifest(&data).map_err(fail(EXIT_FAILED))?.file_meta,
+                false => data.file_meta,
+            };
+            Ok(json!({"key": key.key, "file_meta": file_meta, "output": output}))
+        },
+        NodeCommand::Peers => {
+            let nodes: Vec<Value> = client.routing_table().iter().map(|((key, address), stats)| gateway::node_json(&client, *key, address, stats)).collect();
+            Ok(json!({"key": node.key().key, "address": node.address(), "nodes": nodes}))
+        },
+        NodeCommand::Ping {key, count} => {
+            let (key, address) = node.find_node(Key {key: *key}).map_err(fail(EXIT_NOT_FOUND))?;
+            let rtts: Vec<Duration> = (0..*count).filter_map(|_| node.ping(key).ok()).collect();
+            if rtts.is_empty() {
+                return Err(Failure {code: EXIT_UNREACHABLE, message: format!("No reply from {}", key.key)});
+            }
+
+            let ms = |rtt: Duration| rtt.as_secs_f64() * 1000.0;
+            let summary = latency::summarize(&rtts).map(|(min, avg, max)| json!({"min_ms": ms(min), "avg_ms": ms(avg), "max_ms": ms(max)}));
+            Ok(json!({
+                "key": key.key,
+                "address": address,
+                "sent": count,
+                "lost": count - rtts.len(),
+                "rtts_ms": rtts.iter().map(|rtt| ms(*rtt)).collect::<Vec<f64>>(),
+                "summary": summary,
+            }))
+        },
+        NodeCommand::Crawl {rounds, interval, json, dot} => {
+            let mut crawl = crawler::Crawl::default();
+            for round in 0..*rounds {
+                if round > 0 {
+                    thread::sleep(Duration::from_secs(*interval));
+                }
+                crawl.round(&client);
+                log::info!(round = round + 1, nodes = crawl.nodes.len(), edges = crawl.edges(); "Crawl round done");
+            }
+
+            let k = client.replication();
+            if let Some(json) = json {
+                let graph = serde_json::to_string_pretty(&crawl.to_json(k)).unwrap();
+                std::fs::write(json, graph).map_err(|e| Failure {code: EXIT_FAILED, message: format!("Could not write {}: {}", json.display(), e)})?;
+            }
+            if let Some(dot) = dot {
+                std::fs::write(dot, crawl.to_dot()).map_err(|e| Failure {code: EXIT_FAILED, message: format!("Could not write {}: {}", dot.display(), e)})?;
+            }
+            Ok(json!({
+                "rounds": crawl.rounds,
+                "nodes": crawl.nodes.len(),
+                "edges": crawl.edges(),
+                "unreachable": crawl.unreachable().iter().map(|key| json!({"key": key, "address": crawl.nodes[key].address})).collect::<Vec<Value>>(),
+                "buckets": crawl.bucket_stats(k),
+                "json": json,
+                "dot": dot,
+            }))
+        },
+    }
+}
 
-#[derive(clap::Parser, Debug)]
-struct Cli {
-    #[clap(short)]
-    bootnode: bool,
+// Start the endpoints asked for on the command line
+fn serve_endpoints(cli: &Cli, node: &Node) -> Result<(), Box<dyn Error>> {
+    if let Some(http) = &cli.http {
+        gateway::serve(node.client(), http)?;
+    }
+    if let Some(socket) = &cli.rpc {
+        rpc::serve(node.client(), socket)?;
+    }
+    if let Some(address) = &cli.metrics {
+        metrics::serve(node.client(), address)?;
+    }
+    Ok(())
 }
 
 
 
+fn init_logging(config: &Config) -> Result<(), Box<dyn Error>> {
+    logging::init(&config.logging.level, config.logging.file.as_ref(), config.logging.format)
+}
+
 fn main() {
     
     std::env::set_var("RUST_BACKTRACE", "1");
-    // Initiate Logger
-    log::set_logger(&CONSOLE_LOGGER);
-    log::set_max_level(LevelFilter::Info);
 
     // Parse Inputs
     let  cli = Cli::parse();
 
-    let mut port = if cli.bootnode {
-        12345
-    } else {
-        0
+    // One shot commands print JSON on stdout and report failures through the exit status
+    let result = match &cli.command {
+        None | Some(Command::Serve) => None,
+        Some(Command::Call {socket, method, params}) => Some(call(socket, method, params.as_deref()).map_err(fail(EXIT_FAILED))),
+        Some(Command::Once(command)) => Some(run_once(&cli, command)),
     };
-    
-    // Listener
-    let address = Ipv4Addr::LOCALHOST;
-    let socket = SocketAddrV4::new(address, port);
-    let listener = TcpListener::bind(socket).unwrap();
-    
-    // Generate Rand Port
-    let addr = listener.local_addr().unwrap();
-    let addr_string = addr.to_string().clone();
-    let mut split = addr_string.split(":");
-    split.next();
-    let strp = split.next().unwrap();
-    port = strp.parse::<u16>().unwrap();
-
-    println!("Server started on {}", port);
-
-    // Run Console and Client Loop
-    let host = address.to_string();
-    let client = Client::new(host, port.to_string());
-
-    let mut client_thread_copy = client.clone();
-    let client_thread = thread::spawn(move || {client_thread_copy.run(listener)});
+    if let Some(result) = result {
+        match result {
+            Ok(result) => println!("{}", serde_json::to_string_pretty(&result).unwrap()),
+            Err(failure) => {
+                eprintln!("Error: {}", failure.message);
+                std::process::exit(failure.code);
+            },
+        }
+        return;
+    }
 
-    let mut client_poll_copy = client.clone();
-    let client_poll = thread::spawn(move || {client_poll_copy.poll()});
-    
-    let console_thread_copy = client.clone();
-    let console = thread::spawn(|| console_handle::console(console_thread_copy));
+    let config = match load_config(&cli) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(EXIT_USAGE);
+        },
+    };
+    if let Err(e) = init_logging(&config) {
+        eprintln!("Error: {}", e);
+        std::process::exit(EXIT_USAGE);
+    }
+    let data_dir = config.storage.data_dir.clone();
+    let node = match Node::builder().config(config).start() {
+        Ok(node) => node,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(EXIT_UNREACHABLE);
+        },
+    };
 
-    client_thread.join();
-    client_poll.join();
-    console.join();
-    
-}
+    println!("Server started on {}", node.address());
+    println!("Node key {}", node.key().key);
+    println!("Public key {}", node.public_key());
 
+    // The node already joined, so it leaves properly before exiting
+    if let Err(e) = serve_endpoints(&cli, &node) {
+        eprintln!("Error: {}", e);
+        if let Err(e) = node.shutdown() {
+            log::warn!("{}", e);
+        }
+        node.wait();
+        std::process::exit(EXIT_USAGE);
+    }
 
+    // First signal leaves the network cleanly, a second one exits at once
+    let mut client_signal_copy = node.client();
+    ctrlc::set_handler(move || {
+        if client_signal_copy.stopping.load(Ordering::SeqCst) {
+            std::process::exit(1);
+        }
+        if let Err(e) = client_signal_copy.shutdown() {
+            log::warn!("{}", e);
+        }
+    }).unwrap();
 
+    // Run Console
+    let console_thread_copy = Box::new(node.client());
+    thread::spawn(move || console_handle::console(console_thread_copy, data_dir));
 
+    // Returns once shutdown is done, the console may still be waiting on stdin
+    node.wait();
+}